@@ -0,0 +1,24 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Every API key starts with this, so leaked keys are easy to spot.
+const KEY_PREFIX: &str = "ttt_";
+
+/// Length of the start of a key kept in clear to tell keys apart.
+const SHOWN_CHARS: usize = 12;
+
+/// A new API key, the part of it that can be shown later, and the hash it
+/// is stored under.
+pub fn new_api_key() -> (String, String, String) {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let key = format!("{}{}", KEY_PREFIX, URL_SAFE_NO_PAD.encode(bytes));
+    let shown = key[..SHOWN_CHARS].to_owned();
+    let hash = hash_api_key(&key);
+    (key, shown, hash)
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
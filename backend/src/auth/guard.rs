@@ -0,0 +1,91 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpMessage};
+use actix_web::error::{ErrorForbidden, ErrorUnauthorized};
+use futures::future::{LocalBoxFuture, ready, Ready};
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use crate::auth::user::{AuthUser, Role, Scope};
+
+/// Lets through only users with at least the given role. Must sit inside
+/// `JwtAuth`, which puts the `AuthUser` on the request.
+pub struct RequireRole(pub Role);
+
+/// Lets through only credentials with at least the given scope, so API keys
+/// stay out of routes they were not issued for. Must sit inside `JwtAuth`.
+pub struct RequireScope(pub Scope);
+
+#[derive(Clone, Copy)]
+enum Requirement {
+    Role(Role),
+    Scope(Scope),
+}
+
+impl Requirement {
+    fn check(self, user: &AuthUser) -> Result<(), Error> {
+        match self {
+            Requirement::Role(role) if !user.has_role(role) => Err(ErrorForbidden(format!("{} role required", role.name()))),
+            Requirement::Scope(scope) if !user.has_scope(scope) => Err(ErrorForbidden(format!("{} scope required", scope.name()))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = GuardMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Ready<Result<Self::Transform, Self::InitError>> {
+        ready(Ok(GuardMiddleware { service: Rc::new(service), requirement: Requirement::Role(self.0) }))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = GuardMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Ready<Result<Self::Transform, Self::InitError>> {
+        ready(Ok(GuardMiddleware { service: Rc::new(service), requirement: Requirement::Scope(self.0) }))
+    }
+}
+
+pub struct GuardMiddleware<S> {
+    service: Rc<S>,
+    requirement: Requirement,
+}
+
+impl<S, B> Service<ServiceRequest> for GuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let user = req.extensions().get::<AuthUser>().copied();
+        match user.map(|user| self.requirement.check(&user)) {
+            Some(Ok(())) => Box::pin(self.service.call(req)),
+            Some(Err(e)) => Box::pin(async move { Err(e) }),
+            None => Box::pin(async move { Err(ErrorUnauthorized("Missing user")) }),
+        }
+    }
+}
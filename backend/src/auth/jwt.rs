@@ -1,30 +1,256 @@
-use anyhow::Result;
-use chrono::{Utc, Duration};
-use jsonwebtoken::{encode, decode, Header, Validation, EncodingKey, DecodingKey, TokenData};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use anyhow::{anyhow, bail, Result};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc, Duration};
+use jsonwebtoken::{encode, decode, decode_header, Algorithm, Header, Validation, EncodingKey, DecodingKey, TokenData};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters,
+    OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use rand::RngCore;
+use rsa::RsaPublicKey;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::traits::PublicKeyParts;
 use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::auth::user::Role;
+use crate::config::env_or;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
-    pub exp: usize
+    pub exp: usize,
+    /// Missing from tokens issued before roles existed.
+    #[serde(default)]
+    pub role: Role,
+    /// Token id, used to revoke this token alone. Nil on older tokens.
+    #[serde(default)]
+    pub jti: Uuid,
+    #[serde(default)]
+    pub iat: usize,
+    /// Only set on stream tickets, which are not accepted as access tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
 }
 
-fn secret() -> String {
-    std::env::var("JWT_SECRET").expect("JWT_SECRET must be set")
+/// DER prefix of an Ed25519 `SubjectPublicKeyInfo`; the raw key follows.
+const ED25519_SPKI_PREFIX: &[u8] = &[0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+/// Signing and verification keys, loaded once from the environment.
+///
+/// With `JWT_SIGNING_KEY` (a PEM private key), `JWT_SIGNING_KID` and
+/// `JWT_PUBLIC_KEYS` (comma-separated `kid=path` PEM public keys) tokens are
+/// signed with RS256 or EdDSA and carry a `kid`. Keep a retired key in
+/// `JWT_PUBLIC_KEYS` until the tokens it signed have expired. Without them
+/// tokens fall back to HS256 with `JWT_SECRET`.
+struct KeyStore {
+    signing_kid: Option<String>,
+    signing_alg: Algorithm,
+    signing_key: EncodingKey,
+    verification: HashMap<String, (Algorithm, DecodingKey)>,
+    jwks: JwkSet,
+    /// Only set in HS256 mode.
+    secret: Option<DecodingKey>,
+}
+
+static KEYS: LazyLock<KeyStore> = LazyLock::new(|| KeyStore::from_env().expect("failed to load JWT keys"));
+
+impl KeyStore {
+    fn from_env() -> Result<Self> {
+        let Ok(signing_path) = std::env::var("JWT_SIGNING_KEY") else {
+            let secret = std::env::var("JWT_SECRET").map_err(|_| anyhow!("JWT_SECRET or JWT_SIGNING_KEY must be set"))?;
+            return Ok(Self {
+                signing_kid: None,
+                signing_alg: Algorithm::HS256,
+                signing_key: EncodingKey::from_secret(secret.as_ref()),
+                verification: HashMap::new(),
+                jwks: JwkSet { keys: Vec::new() },
+                secret: Some(DecodingKey::from_secret(secret.as_ref())),
+            });
+        };
+        let signing_kid = std::env::var("JWT_SIGNING_KID").map_err(|_| anyhow!("JWT_SIGNING_KID must be set"))?;
+
+        let mut verification = HashMap::new();
+        let mut keys = Vec::new();
+        for entry in std::env::var("JWT_PUBLIC_KEYS").unwrap_or_default().split(',').filter(|e| !e.trim().is_empty()) {
+            let (kid, path) = entry.trim().split_once('=')
+                .ok_or_else(|| anyhow!("JWT_PUBLIC_KEYS entries must look like kid=path, got {}", entry))?;
+            let (alg, jwk) = public_jwk(kid, &std::fs::read_to_string(path)?)?;
+            verification.insert(kid.to_owned(), (alg, DecodingKey::from_jwk(&jwk)?));
+            keys.push(jwk);
+        }
+
+        let &(signing_alg, _) = verification.get(&signing_kid)
+            .ok_or_else(|| anyhow!("JWT_PUBLIC_KEYS has no key for signing kid {}", signing_kid))?;
+        let pem = std::fs::read(&signing_path)?;
+        let signing_key = match signing_alg {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(&pem)?,
+            _ => EncodingKey::from_rsa_pem(&pem)?,
+        };
+        println!("Signing tokens with {:?} key {}, {} verification keys", signing_alg, signing_kid, keys.len());
+
+        Ok(Self {
+            signing_kid: Some(signing_kid),
+            signing_alg,
+            signing_key,
+            verification,
+            jwks: JwkSet { keys },
+            secret: None,
+        })
+    }
+}
+
+/// Builds the JWK for a PEM public key, which must be RSA or Ed25519.
+fn public_jwk(kid: &str, pem_text: &str) -> Result<(Algorithm, Jwk)> {
+    let parsed = pem::parse(pem_text)?;
+    let (alg, key_algorithm, params) = match parsed.contents().strip_prefix(ED25519_SPKI_PREFIX) {
+        Some(raw) if raw.len() == 32 => (
+            Algorithm::EdDSA,
+            KeyAlgorithm::EdDSA,
+            AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(raw),
+            }),
+        ),
+        _ => {
+            let key = match parsed.tag() {
+                "RSA PUBLIC KEY" => RsaPublicKey::from_pkcs1_der(parsed.contents())?,
+                _ => RsaPublicKey::from_public_key_der(parsed.contents())?,
+            };
+            (
+                Algorithm::RS256,
+                KeyAlgorithm::RS256,
+                AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+                    e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+                }),
+            )
+        }
+    };
+    let jwk = Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(kid.to_owned()),
+            ..Default::default()
+        },
+        algorithm: params,
+    };
+    Ok((alg, jwk))
+}
+
+/// Loads the keys now so a bad configuration fails at startup rather than
+/// on the first sign-in.
+pub fn init_keys() {
+    LazyLock::force(&KEYS);
+}
+
+/// The public verification keys, for `/.well-known/jwks.json`.
+pub fn jwks() -> &'static JwkSet {
+    &KEYS.jwks
+}
+
+pub fn access_token_ttl() -> Duration {
+    Duration::minutes(env_or("ACCESS_TOKEN_MINUTES", 15))
+}
+
+pub fn refresh_token_ttl() -> Duration {
+    Duration::days(env_or("REFRESH_TOKEN_DAYS", 30))
+}
+
+/// How long a ticket for `stream_ticket_audience` stays valid.
+pub fn stream_ticket_ttl() -> Duration {
+    Duration::seconds(env_or("STREAM_TICKET_SECS", 60))
+}
+
+/// The audience of tickets for one room's event stream.
+fn stream_ticket_audience(room_id: Uuid) -> String {
+    format!("room-events:{}", room_id)
+}
+
+pub fn create_jwt_for_user(user_id: &str, role: Role) -> Result<String> {
+    let now = Utc::now();
+    sign(&Claims {
+        sub: user_id.to_owned(),
+        exp: (now + access_token_ttl()).timestamp() as usize,
+        role,
+        jti: Uuid::new_v4(),
+        iat: now.timestamp() as usize,
+        aud: None,
+    })
 }
 
-pub fn create_jwt_for_user(user_id: &str, hours_valid: i64) -> Result<String> {
-    let exp = (Utc::now() + Duration::hours(hours_valid)).timestamp() as usize;
-    let claims = Claims { sub: user_id.to_owned(), exp };
-    let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(secret().as_ref()))?;
-    Ok(token)  
+/// A short-lived token that opens `room_id`'s event stream and nothing else,
+/// for clients such as `EventSource` that can only pass it in the URL.
+pub fn create_stream_ticket(user_id: Uuid, role: Role, room_id: Uuid) -> Result<String> {
+    let now = Utc::now();
+    sign(&Claims {
+        sub: user_id.to_string(),
+        exp: (now + stream_ticket_ttl()).timestamp() as usize,
+        role,
+        jti: Uuid::new_v4(),
+        iat: now.timestamp() as usize,
+        aud: Some(stream_ticket_audience(room_id)),
+    })
 }
 
+fn sign(claims: &Claims) -> Result<String> {
+    let keys = &*KEYS;
+    let header = Header {
+        kid: keys.signing_kid.clone(),
+        ..Header::new(keys.signing_alg)
+    };
+    let token = encode(&header, claims, &keys.signing_key)?;
+    Ok(token)
+}
+
+/// Verifies a token against the key named by its `kid`, or the HS256 secret
+/// for tokens without one. Each key only accepts its own algorithm.
 pub fn verify_jwt(token: &str) -> Result<TokenData<Claims>> {
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret().as_ref()),
-        &Validation::default()
-    )?;
+    verify(token, None)
+}
+
+pub fn verify_stream_ticket(token: &str, room_id: Uuid) -> Result<TokenData<Claims>> {
+    verify(token, Some(&stream_ticket_audience(room_id)))
+}
+
+/// Tokens carrying an audience are only accepted when it is `audience`.
+fn verify(token: &str, audience: Option<&str>) -> Result<TokenData<Claims>> {
+    let keys = &*KEYS;
+    let header = decode_header(token)?;
+    let (alg, key) = match (&header.kid, &keys.secret) {
+        (Some(kid), _) => keys.verification.get(kid)
+            .map(|(alg, key)| (*alg, key))
+            .ok_or_else(|| anyhow!("unknown key id {}", kid))?,
+        (None, Some(secret)) => (Algorithm::HS256, secret),
+        (None, None) => bail!("token has no key id"),
+    };
+    let mut validation = Validation::new(alg);
+    if let Some(audience) = audience {
+        validation.set_audience(&[audience]);
+    }
+    let token_data = decode::<Claims>(token, key, &validation)?;
     Ok(token_data)
-}
\ No newline at end of file
+}
+
+pub fn timestamp(secs: usize) -> DateTime<Utc> {
+    DateTime::from_timestamp(secs as i64, 0).unwrap_or_default()
+}
+
+/// A new opaque refresh token and the hash it is stored under.
+pub fn new_refresh_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let hash = hash_refresh_token(&token);
+    (token, hash)
+}
+
+pub fn hash_refresh_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,186 @@
+use std::time::Duration;
+use actix_web::HttpRequest;
+use sha2::{Digest, Sha256};
+
+use crate::config::env_or;
+
+/// Brute-force protection for password sign-in. Consecutive failures are
+/// counted per username and per client IP; past the free attempts each
+/// further failure locks the key out for twice as long as the one before,
+/// up to `max_lockout`. Failures older than `failure_window` are forgotten.
+pub struct LoginConfig {
+    pub free_attempts: i32,
+    /// Higher than `free_attempts` since many users can share an address.
+    pub ip_free_attempts: i32,
+    pub base_lockout: Duration,
+    pub max_lockout: Duration,
+    pub failure_window: Duration,
+    /// How many reverse proxies in front of us append to `X-Forwarded-For`.
+    /// The client IP is the address the outermost of them saw; anything
+    /// further left was sent by the client and is ignored. 0 uses the peer
+    /// address.
+    pub trusted_proxies: usize,
+}
+
+impl LoginConfig {
+    pub fn from_env() -> Self {
+        Self {
+            free_attempts: env_or("LOGIN_FREE_ATTEMPTS", 5),
+            ip_free_attempts: env_or("LOGIN_IP_FREE_ATTEMPTS", 20),
+            base_lockout: Duration::from_secs(env_or("LOGIN_LOCKOUT_BASE_SECS", 30)),
+            max_lockout: Duration::from_secs(env_or("LOGIN_LOCKOUT_MAX_SECS", 3600)),
+            failure_window: Duration::from_secs(env_or("LOGIN_FAILURE_WINDOW_SECS", 900)),
+            trusted_proxies: env_or("TRUSTED_PROXIES", 0),
+        }
+    }
+
+    /// The lockout earned by `failures` consecutive failures, if any.
+    pub fn lockout_for(&self, failures: i32, free_attempts: i32) -> Option<Duration> {
+        let over = u32::try_from(failures - free_attempts).ok().filter(|&over| over > 0)?;
+        let factor = 2u32.saturating_pow(over - 1);
+        Some(self.base_lockout.saturating_mul(factor).min(self.max_lockout))
+    }
+
+    /// Each further failure's lockout, from the first one past the free
+    /// attempts until `max_lockout` is reached.
+    pub fn lockout_schedule(&self) -> Vec<f64> {
+        let mut schedule = Vec::new();
+        for failures in 1..=32 {
+            let lockout = self.lockout_for(failures, 0).unwrap_or_default();
+            schedule.push(lockout.as_secs_f64());
+            if lockout >= self.max_lockout {
+                break;
+            }
+        }
+        schedule
+    }
+
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<String> {
+        if self.trusted_proxies == 0 {
+            return req.peer_addr().map(|addr| addr.ip().to_string());
+        }
+        let hops: Vec<&str> = req.headers()
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|hop| !hop.is_empty())
+            .collect();
+        // Fewer hops than proxies means the request skipped some of them,
+        // so none of the hops can be trusted.
+        match hops.len().checked_sub(self.trusted_proxies) {
+            Some(index) => Some(hops[index].to_owned()),
+            None => req.peer_addr().map(|addr| addr.ip().to_string()),
+        }
+    }
+}
+
+/// Longest name kept as is in a throttling key; longer ones are hashed so
+/// the key still fits the `login_throttles` column.
+const USERNAME_KEY_MAX_LENGTH: usize = 64;
+
+/// Usernames are counted case-insensitively so variants share one budget.
+pub fn username_key(username: &str) -> String {
+    let username = username.to_lowercase();
+    if username.chars().count() > USERNAME_KEY_MAX_LENGTH {
+        return format!("user:sha256:{}", hex::encode(Sha256::digest(username.as_bytes())));
+    }
+    format!("user:{}", username)
+}
+
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use super::*;
+
+    fn config(trusted_proxies: usize) -> LoginConfig {
+        LoginConfig {
+            free_attempts: 5,
+            ip_free_attempts: 20,
+            base_lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(3600),
+            failure_window: Duration::from_secs(900),
+            trusted_proxies,
+        }
+    }
+
+    #[test]
+    fn lockout_doubles_past_the_free_attempts() {
+        let config = config(0);
+        assert_eq!(config.lockout_for(0, 5), None);
+        assert_eq!(config.lockout_for(5, 5), None);
+        assert_eq!(config.lockout_for(6, 5), Some(Duration::from_secs(30)));
+        assert_eq!(config.lockout_for(7, 5), Some(Duration::from_secs(60)));
+        assert_eq!(config.lockout_for(9, 5), Some(Duration::from_secs(240)));
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        let config = config(0);
+        assert_eq!(config.lockout_for(13, 5), Some(Duration::from_secs(3600)));
+        assert_eq!(config.lockout_for(i32::MAX, 5), Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn lockout_schedule_stops_at_the_cap() {
+        assert_eq!(config(0).lockout_schedule(), [30.0, 60.0, 120.0, 240.0, 480.0, 960.0, 1920.0, 3600.0]);
+
+        let mut config = config(0);
+        config.base_lockout = Duration::ZERO;
+        assert_eq!(config.lockout_schedule().len(), 32);
+    }
+
+    fn request(forwarded_for: &[&str]) -> actix_web::HttpRequest {
+        let mut req = TestRequest::default().peer_addr("10.0.0.1:4000".parse().unwrap());
+        for value in forwarded_for {
+            req = req.append_header(("X-Forwarded-For", *value));
+        }
+        req.to_http_request()
+    }
+
+    #[test]
+    fn client_ip_ignores_forwarded_for_without_proxies() {
+        assert_eq!(config(0).client_ip(&request(&["203.0.113.9"])).as_deref(), Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn client_ip_takes_the_hop_the_outermost_proxy_saw() {
+        // The client sent a forged hop; the proxy appended the real address.
+        let req = request(&["198.51.100.7, 203.0.113.9"]);
+        assert_eq!(config(1).client_ip(&req).as_deref(), Some("203.0.113.9"));
+
+        let req = request(&["198.51.100.7, 203.0.113.9, 192.0.2.1"]);
+        assert_eq!(config(2).client_ip(&req).as_deref(), Some("203.0.113.9"));
+
+        // Repeated headers are read as one list.
+        let req = request(&["198.51.100.7", "203.0.113.9, 192.0.2.1"]);
+        assert_eq!(config(2).client_ip(&req).as_deref(), Some("203.0.113.9"));
+    }
+
+    #[test]
+    fn username_keys_fold_case() {
+        assert_eq!(username_key("Alice"), "user:alice");
+        assert_eq!(username_key("ALICE"), username_key("alice"));
+    }
+
+    #[test]
+    fn long_username_keys_are_hashed() {
+        let long = "a".repeat(10_000);
+        let key = username_key(&long);
+        assert!(key.starts_with("user:sha256:"));
+        assert!(key.len() <= 300, "{} does not fit login_throttles.key", key.len());
+        assert_eq!(key, username_key(&long.to_uppercase()));
+        assert_ne!(key, username_key(&"a".repeat(10_001)));
+        assert_eq!(username_key(&"a".repeat(64)), format!("user:{}", "a".repeat(64)));
+    }
+
+    #[test]
+    fn client_ip_distrusts_too_few_hops() {
+        assert_eq!(config(2).client_ip(&request(&["203.0.113.9"])).as_deref(), Some("10.0.0.1"));
+        assert_eq!(config(1).client_ip(&request(&[])).as_deref(), Some("10.0.0.1"));
+    }
+}
@@ -1,14 +1,35 @@
 use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
-use actix_web::{Error, HttpMessage};
-use actix_web::error::ErrorUnauthorized;
+use actix_web::{web, Error, HttpMessage};
+use actix_web::error::{ErrorInternalServerError, ErrorUnauthorized};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
 use futures::future::{LocalBoxFuture, ready, Ready};
 use std::rc::Rc;
 use std::task::{Context, Poll};
 use uuid::Uuid;
-use crate::auth::jwt::verify_jwt;
+use crate::auth::api_key::hash_api_key;
+use crate::auth::jwt::{timestamp, verify_jwt, verify_stream_ticket};
+use crate::auth::user::{AuthUser, Role, Scope};
+use crate::state::AppState;
 
+/// Authenticates a request by its `Authorization: Bearer` access token, or
+/// by an `X-Api-Key` header. A room's event stream also accepts a stream
+/// ticket in the `ticket` query parameter, since `EventSource` cannot set
+/// headers.
 pub struct JwtAuth;
 
+#[derive(Deserialize)]
+struct TicketParams {
+    ticket: String,
+}
+
+/// The room and ticket of a `/api/rooms/{room_id}/events?ticket=` request.
+fn stream_ticket(req: &ServiceRequest) -> Option<(Uuid, String)> {
+    let room_id = req.path().strip_prefix("/api/rooms/")?.strip_suffix("/events")?.parse().ok()?;
+    let params = web::Query::<TicketParams>::from_query(req.query_string()).ok()?;
+    Some((room_id, params.into_inner().ticket))
+}
+
 impl<S, B> Transform<S, ServiceRequest> for JwtAuth
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
@@ -44,48 +65,84 @@ where
     
     fn call(&self, req: ServiceRequest) -> Self::Future {
         
-        let path = req.path().to_string();
-        if path.starts_with("/auth/") || path == "signin" || path == "signup" {
-            let fut = self.service.call(req);
-            return Box::pin(async move { fut.await });
+        if let Some(key) = req.headers().get("X-Api-Key").and_then(|h| h.to_str().ok()).map(str::to_owned) {
+            let state = req.app_data::<web::Data<AppState>>().cloned();
+            let svc = self.service.clone();
+            return Box::pin(async move {
+                let Some(state) = state else {
+                    return Err(ErrorInternalServerError("Failed to verify API key"));
+                };
+                let key = match state.db.use_api_key(&hash_api_key(&key)).await {
+                    Ok(Some(key)) => key,
+                    Ok(None) => return Err(ErrorUnauthorized("Invalid API key")),
+                    Err(e) => {
+                        println!("Failed to check API key: {:?}", e);
+                        return Err(ErrorInternalServerError("Failed to verify API key"));
+                    }
+                };
+                req.extensions_mut().insert(AuthUser {
+                    id: key.user_id,
+                    role: Role::Player,
+                    jti: key.key_id,
+                    expires_at: DateTime::<Utc>::MAX_UTC,
+                    scope: Scope::parse_key_scope(&key.scope).unwrap_or(Scope::Read),
+                    is_bot: key.is_bot,
+                });
+                svc.call(req).await
+            });
         }
-        
+
         let header = req
             .headers()
             .get(actix_web::http::header::AUTHORIZATION)
             .and_then(|h| h.to_str().ok())
             .map(|s| s.to_string());
-        
-        if header.is_none() {
-            return Box::pin(async move { Err(ErrorUnauthorized("Missing authorization header")) });
-        }
-        
-        let header = header.unwrap();
-        if !header.to_lowercase().starts_with("bearer ") {
-            return Box::pin(async move { Err(ErrorUnauthorized("Invalid authorization header format")) });
-        }
-        
-        let token = header[7..].trim();
-        
-        match verify_jwt(token) {
-            Ok(data) => {
-                match Uuid::parse_str(&data.claims.sub) {
-                    Ok(uid) => {
-                        req.extensions_mut().insert(uid);
-                        let svc = self.service.clone();
-                        Box::pin(async move {
-                            let res = svc.call(req).await?;
-                            Ok(res)
-                        })
-                    }
-                    Err(_) => {
-                        Box::pin(async move { Err(ErrorUnauthorized("Invalid JWT token")) })
-                    }
+
+        let verified = match (header, stream_ticket(&req)) {
+            (Some(header), _) => {
+                if !header.to_lowercase().starts_with("bearer ") {
+                    return Box::pin(async move { Err(ErrorUnauthorized("Invalid authorization header format")) });
                 }
+                verify_jwt(header[7..].trim())
+                    .map(|data| (data.claims, Scope::Session))
+                    .map_err(|_| "JWT verification failed")
             }
-            Err(_) => {
-                Box::pin(async move { Err(ErrorUnauthorized("JWT verification failed")) })
+            (None, Some((room_id, ticket))) => verify_stream_ticket(&ticket, room_id)
+                .map(|data| (data.claims, Scope::Play))
+                .map_err(|_| "Invalid stream ticket"),
+            (None, None) => Err("Missing authorization header"),
+        };
+        let (claims, scope) = match verified {
+            Ok(verified) => verified,
+            Err(message) => return Box::pin(async move { Err(ErrorUnauthorized(message)) }),
+        };
+        let Ok(uid) = Uuid::parse_str(&claims.sub) else {
+            return Box::pin(async move { Err(ErrorUnauthorized("Invalid JWT token")) });
+        };
+
+        req.extensions_mut().insert(AuthUser {
+            id: uid,
+            role: claims.role,
+            jti: claims.jti,
+            expires_at: timestamp(claims.exp),
+            scope,
+            is_bot: false,
+        });
+        let state = req.app_data::<web::Data<AppState>>().cloned();
+        let svc = self.service.clone();
+        Box::pin(async move {
+            if let Some(state) = state {
+                match state.db.is_access_token_revoked(claims.jti, uid, timestamp(claims.iat)).await {
+                    Ok(false) => {}
+                    Ok(true) => return Err(ErrorUnauthorized("Token has been revoked")),
+                    Err(e) => {
+                        println!("Failed to check token revocation: {:?}", e);
+                        return Err(ErrorInternalServerError("Failed to verify token"));
+                    }
+                }
             }
-        }
+            let res = svc.call(req).await?;
+            Ok(res)
+        })
     }
 }
\ No newline at end of file
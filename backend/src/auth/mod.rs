@@ -1,2 +1,7 @@
 pub mod jwt;
-pub mod middleware;
\ No newline at end of file
+pub mod middleware;
+pub mod guard;
+pub mod user;
+pub mod api_key;
+pub mod oidc;
+pub mod lockout;
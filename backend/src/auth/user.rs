@@ -0,0 +1,99 @@
+use actix_web::{dev::Payload, Error, FromRequest, HttpMessage, HttpRequest};
+use actix_web::error::ErrorUnauthorized;
+use chrono::{DateTime, Utc};
+use futures::future::{ready, Ready};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+/// What a user is allowed to do. Each role includes the powers of the
+/// ones before it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Player,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "player" => Some(Role::Player),
+            "moderator" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Player => "player",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// What a request's credentials allow. API keys carry `Read` or `Play`;
+/// access tokens from a sign-in carry `Session`, which includes both and
+/// also covers managing the account itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Play,
+    Session,
+}
+
+impl Scope {
+    /// Parses a scope an API key can be given.
+    pub fn parse_key_scope(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Scope::Read),
+            "play" => Some(Scope::Play),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Play => "play",
+            Scope::Session => "session",
+        }
+    }
+}
+
+/// The caller of an authenticated request, put on the request by `JwtAuth`.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthUser {
+    pub id: Uuid,
+    pub role: Role,
+    /// Id and expiry of the access token the request came with. For API
+    /// keys, the key id and `DateTime::MAX_UTC`.
+    pub jti: Uuid,
+    pub expires_at: DateTime<Utc>,
+    pub scope: Scope,
+    /// Set when the request came with a bot's API key.
+    pub is_bot: bool,
+}
+
+impl AuthUser {
+    pub fn has_role(&self, role: Role) -> bool {
+        self.role >= role
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scope >= scope
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<AuthUser>().copied().ok_or_else(|| ErrorUnauthorized("Not authenticated")))
+    }
+}
@@ -0,0 +1,135 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use image::imageops::FilterType;
+use uuid::Uuid;
+
+use crate::config::env_or;
+
+/// Where avatars are stored and what uploads are accepted. Uploads must be
+/// PNG, JPEG or WebP of at most `max_bytes` and `max_dimension` pixels a
+/// side. They are cropped to a square and stored re-encoded as PNG, once at
+/// `size` and once as a `thumb_size` thumbnail, which also drops metadata.
+pub struct AvatarConfig {
+    pub dir: PathBuf,
+    pub max_bytes: usize,
+    pub max_dimension: u32,
+    pub size: u32,
+    pub thumb_size: u32,
+}
+
+pub enum AvatarError {
+    UnsupportedFormat,
+    TooLarge,
+    Invalid(String),
+}
+
+/// An upload turned into the files that are stored.
+pub struct ProcessedAvatar {
+    pub full: Vec<u8>,
+    pub thumb: Vec<u8>,
+}
+
+impl AvatarConfig {
+    pub fn from_env() -> Self {
+        Self {
+            dir: PathBuf::from(env_or("AVATAR_DIR", "avatars".to_string())),
+            max_bytes: env_or("AVATAR_MAX_BYTES", 2 * 1024 * 1024),
+            max_dimension: env_or("AVATAR_MAX_DIMENSION", 4096),
+            size: env_or("AVATAR_SIZE", 256),
+            thumb_size: env_or("AVATAR_THUMB_SIZE", 64),
+        }
+    }
+
+    pub fn path(&self, avatar_id: Uuid, thumb: bool) -> PathBuf {
+        self.dir.join(file_name(avatar_id, thumb))
+    }
+
+    /// Validates and converts an upload. CPU-bound, so run it off the
+    /// async workers.
+    pub fn process(&self, bytes: &[u8]) -> Result<ProcessedAvatar, AvatarError> {
+        let format = match image::guess_format(bytes) {
+            Ok(format @ (ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP)) => format,
+            _ => return Err(AvatarError::UnsupportedFormat),
+        };
+        let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_dimension);
+        limits.max_image_height = Some(self.max_dimension);
+        reader.limits(limits);
+        let image = reader.decode().map_err(|e| match e {
+            image::ImageError::Limits(_) => AvatarError::TooLarge,
+            e => AvatarError::Invalid(e.to_string()),
+        })?;
+
+        let side = image.width().min(image.height());
+        let square = image.crop_imm((image.width() - side) / 2, (image.height() - side) / 2, side, side);
+        Ok(ProcessedAvatar {
+            full: encode_png(&square.resize_exact(self.size, self.size, FilterType::Lanczos3))?,
+            thumb: encode_png(&square.resize_exact(self.thumb_size, self.thumb_size, FilterType::Triangle))?,
+        })
+    }
+}
+
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, AvatarError> {
+    let mut out = Cursor::new(Vec::new());
+    image.write_to(&mut out, ImageFormat::Png).map_err(|e| AvatarError::Invalid(e.to_string()))?;
+    Ok(out.into_inner())
+}
+
+pub fn file_name(avatar_id: Uuid, thumb: bool) -> String {
+    if thumb {
+        format!("{}_thumb.png", avatar_id)
+    } else {
+        format!("{}.png", avatar_id)
+    }
+}
+
+/// The inverse of `file_name`, so only avatar files can be served. Other
+/// spellings of the id that `Uuid` would parse are turned away too.
+pub fn parse_file_name(name: &str) -> Option<(Uuid, bool)> {
+    let stem = name.strip_suffix(".png")?;
+    let (id, thumb) = match stem.strip_suffix("_thumb") {
+        Some(id) => (id, true),
+        None => (stem, false),
+    };
+    let id: Uuid = id.parse().ok()?;
+    (file_name(id, thumb) == name).then_some((id, thumb))
+}
+
+pub fn url(avatar_id: Uuid, thumb: bool) -> String {
+    format!("/avatars/{}", file_name(avatar_id, thumb))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_file_name_inverts_file_name() {
+        let id = Uuid::new_v4();
+        assert_eq!(parse_file_name(&file_name(id, false)), Some((id, false)));
+        assert_eq!(parse_file_name(&file_name(id, true)), Some((id, true)));
+    }
+
+    #[test]
+    fn parse_file_name_rejects_anything_else() {
+        let id = Uuid::new_v4();
+        for name in [
+            format!("{}", id),
+            format!("{}.jpg", id),
+            format!("{}_thumb", id),
+            format!("{}_small.png", id),
+            format!("../{}.png", id),
+            format!("{}.png.png", id),
+            format!("{}.png", id.simple()),
+            format!("{}.png", id.braced()),
+            format!("{}.png", id.urn()),
+            format!("{}.png", id.to_string().to_uppercase()),
+            "_thumb.png".to_owned(),
+            ".png".to_owned(),
+        ] {
+            assert_eq!(parse_file_name(&name), None, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::config::env_or;
+
+pub struct ChatConfig {
+    pub max_length: usize,
+    pub rate_limit: usize,
+    pub rate_window: Duration,
+    pub persist: bool,
+}
+
+impl ChatConfig {
+    pub fn from_env() -> Self {
+        Self {
+            max_length: env_or("CHAT_MAX_LENGTH", 280),
+            rate_limit: env_or("CHAT_RATE_LIMIT", 5),
+            rate_window: Duration::from_secs(env_or("CHAT_RATE_WINDOW_SECS", 10)),
+            persist: env_or("CHAT_PERSIST", true),
+        }
+    }
+}
+
+pub enum FilterResult {
+    /// Deliver the (possibly rewritten) message.
+    Allow(String),
+    Reject(String),
+}
+
+/// Hook for moderating chat before it is relayed. Implementations must be
+/// cheap since they run inside the room task.
+pub trait ChatFilter: Send + Sync {
+    fn filter(&self, message: &str) -> FilterResult;
+}
+
+/// Masks any word found in the masked list with asterisks, and rejects
+/// messages containing a word from the blocked list.
+pub struct WordListFilter {
+    masked: HashSet<String>,
+    blocked: HashSet<String>,
+}
+
+fn normalize(words: impl IntoIterator<Item = String>) -> HashSet<String> {
+    words.into_iter().map(|w| w.trim().to_lowercase()).filter(|w| !w.is_empty()).collect()
+}
+
+/// Reads one word per line from the file named by `key`, or nothing if unset.
+fn words_from_env(key: &str) -> Vec<String> {
+    match std::env::var(key) {
+        Ok(path) => match std::fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().map(str::to_owned).collect(),
+            Err(e) => {
+                println!("Failed to read chat word list {}: {:?}", path, e);
+                Vec::new()
+            }
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+impl WordListFilter {
+    pub fn new(masked: impl IntoIterator<Item = String>, blocked: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            masked: normalize(masked),
+            blocked: normalize(blocked),
+        }
+    }
+
+    /// Loads the masked words from `CHAT_WORD_LIST` and the blocked words
+    /// from `CHAT_BLOCKED_WORD_LIST`.
+    pub fn from_env() -> Self {
+        Self::new(words_from_env("CHAT_WORD_LIST"), words_from_env("CHAT_BLOCKED_WORD_LIST"))
+    }
+}
+
+impl ChatFilter for WordListFilter {
+    fn filter(&self, message: &str) -> FilterResult {
+        if self.masked.is_empty() && self.blocked.is_empty() {
+            return FilterResult::Allow(message.to_owned());
+        }
+        let bare = |word: &str| word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+        if message.split_whitespace().any(|word| self.blocked.contains(&bare(word))) {
+            return FilterResult::Reject("message contains a blocked word".to_owned());
+        }
+        // Walk the same whitespace-separated words as the blocked check, but
+        // copy the separators through unchanged.
+        let mut filtered = String::with_capacity(message.len());
+        let mut rest = message;
+        while !rest.is_empty() {
+            let (word, tail) = rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()));
+            if self.masked.contains(&bare(word)) {
+                filtered.push_str(&"*".repeat(word.chars().count()));
+            } else {
+                filtered.push_str(word);
+            }
+            let (separator, tail) = tail.split_at(tail.find(|c: char| !c.is_whitespace()).unwrap_or(tail.len()));
+            filtered.push_str(separator);
+            rest = tail;
+        }
+        FilterResult::Allow(filtered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> WordListFilter {
+        WordListFilter::new(["darn".to_owned()], ["badword".to_owned()])
+    }
+
+    fn allowed(message: &str) -> String {
+        match filter().filter(message) {
+            FilterResult::Allow(text) => text,
+            FilterResult::Reject(reason) => panic!("{:?} was rejected: {}", message, reason),
+        }
+    }
+
+    #[test]
+    fn masks_words_on_any_whitespace() {
+        assert_eq!(allowed("oh darn it"), "oh **** it");
+        assert_eq!(allowed("gg\ndarn"), "gg\n****");
+        assert_eq!(allowed("gg\tDarn!\r\n"), "gg\t*****\r\n");
+        assert_eq!(allowed("  darn  darn "), "  ****  **** ");
+    }
+
+    #[test]
+    fn leaves_other_words_alone() {
+        assert_eq!(allowed("darning socks"), "darning socks");
+        assert_eq!(allowed("héllo  wörld"), "héllo  wörld");
+    }
+
+    #[test]
+    fn rejects_blocked_words_on_any_whitespace() {
+        for message in ["badword", "gg\nbadword", "gg\tBADWORD.", "a\u{3000}badword"] {
+            assert!(matches!(filter().filter(message), FilterResult::Reject(_)), "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn empty_lists_pass_everything() {
+        let filter = WordListFilter::new(Vec::new(), Vec::new());
+        assert!(matches!(filter.filter("badword darn"), FilterResult::Allow(text) if text == "badword darn"));
+    }
+}
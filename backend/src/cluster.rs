@@ -0,0 +1,361 @@
+use std::{collections::HashMap, sync::{Arc, atomic::Ordering}, time::Duration};
+use dashmap::DashMap;
+use serde::{Serialize, Deserialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::config::env_or;
+use crate::state::AppState;
+use crate::protocol::{ErrorCode, GameError};
+use crate::routes::room::{restore_rooms, AdminCommand, GameCommand, GameEvent, RoomInfo};
+
+pub struct ClusterConfig {
+    pub heartbeat: Duration,
+    /// An instance that has not sent a heartbeat for this long is considered
+    /// dead and its rooms are taken over.
+    pub stale_after: Duration,
+}
+
+impl ClusterConfig {
+    pub fn from_env() -> Self {
+        Self {
+            heartbeat: Duration::from_secs(env_or("CLUSTER_HEARTBEAT_SECS", 5)),
+            stale_after: Duration::from_secs(env_or("CLUSTER_STALE_SECS", 15)),
+        }
+    }
+}
+
+/// A local client of a room owned by another instance.
+struct RemoteConnection {
+    owner: Uuid,
+    sender: mpsc::Sender<GameEvent>,
+}
+
+/// Rooms live on the instance that created them. Clients that land on a
+/// different instance talk to the owner through Postgres `NOTIFY`, one
+/// channel per instance.
+pub struct Cluster {
+    pub instance_id: Uuid,
+    pub config: ClusterConfig,
+    /// Clients connected here to rooms owned elsewhere, by connection id.
+    connections: DashMap<Uuid, RemoteConnection>,
+    /// Tasks relaying events from rooms owned here to clients elsewhere.
+    forwarders: DashMap<Uuid, JoinHandle<()>>,
+    /// Admin commands relayed to rooms owned elsewhere, by request id,
+    /// waiting for the owner's reply.
+    admin_replies: DashMap<Uuid, oneshot::Sender<Result<RoomInfo, GameError>>>,
+}
+
+impl Cluster {
+    /// Uses `INSTANCE_ID` if set so a restarted instance picks its rooms
+    /// straight back up, otherwise a fresh id.
+    pub fn from_env() -> Self {
+        let instance_id = std::env::var("INSTANCE_ID")
+            .ok()
+            .and_then(|id| id.parse().ok())
+            .unwrap_or_else(Uuid::new_v4);
+        Self {
+            instance_id,
+            config: ClusterConfig::from_env(),
+            connections: DashMap::new(),
+            forwarders: DashMap::new(),
+            admin_replies: DashMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RelayMessage {
+    Join { room_id: Uuid, user_id: Uuid, conn: Uuid, origin: Uuid },
+    Move { room_id: Uuid, user_id: Uuid, idx: usize, request_id: Option<String> },
+    Chat { room_id: Uuid, user_id: Uuid, text: String, request_id: Option<String> },
+    Leave { room_id: Uuid, user_id: Uuid, conn: Uuid },
+    Block { room_id: Uuid, user_id: Uuid, other_id: Uuid },
+    AccountDeleted { room_id: Uuid, user_id: Uuid },
+    Admin { room_id: Uuid, command: AdminCommand, request: Uuid, origin: Uuid },
+    /// The room's answer to an admin command, `None` if it is gone.
+    AdminReply { request: Uuid, result: Option<Result<RoomInfo, GameError>> },
+    /// An event for a client connected to the receiving instance.
+    Event { conn: Uuid, request_id: Option<String>, event: GameEvent },
+    /// The room dropped the client, e.g. because it closed.
+    Closed { conn: Uuid },
+}
+
+fn channel(instance_id: Uuid) -> String {
+    format!("room_relay_{}", instance_id.simple())
+}
+
+/// Postgres rejects `NOTIFY` payloads of 8000 bytes or more.
+const MAX_RELAY_PAYLOAD: usize = 7999;
+
+/// Returns `false` if the message could not be sent.
+async fn relay(state: &AppState, to: Uuid, message: &RelayMessage) -> bool {
+    let sent = match serde_json::to_string(message) {
+        Ok(payload) if payload.len() > MAX_RELAY_PAYLOAD => {
+            Err(anyhow::anyhow!("payload of {} bytes is too large", payload.len()))
+        }
+        Ok(payload) => state.db.notify(&channel(to), &payload).await,
+        Err(e) => Err(e.into()),
+    };
+    match sent {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Failed to relay message to instance {}: {:?}", to, e);
+            false
+        }
+    }
+}
+
+/// Finds the room wherever it lives: the local actor if this instance owns
+/// it, otherwise a proxy that relays commands to the owning instance.
+pub async fn room_sender(state: &Arc<AppState>, room_id: Uuid) -> Option<mpsc::Sender<GameCommand>> {
+    if let Some(tx) = state.active_rooms.get(&room_id) {
+        return Some(tx.clone());
+    }
+    match state.db.get_room_owner(room_id, state.cluster.config.stale_after).await {
+        Ok(Some(owner)) if owner != state.cluster.instance_id => Some(spawn_proxy(state.clone(), room_id, owner)),
+        Ok(_) => None,
+        Err(e) => {
+            println!("Failed to look up owner of room {}: {:?}", room_id, e);
+            None
+        }
+    }
+}
+
+/// Every room of every live instance, reached as `room_sender` would.
+pub async fn all_room_senders(state: &Arc<AppState>) -> anyhow::Result<Vec<mpsc::Sender<GameCommand>>> {
+    let rooms = state.db.get_live_rooms(state.cluster.config.stale_after).await?;
+    Ok(rooms
+        .into_iter()
+        .filter_map(|(room_id, owner)| {
+            if owner == state.cluster.instance_id {
+                state.active_rooms.get(&room_id).map(|tx| tx.clone())
+            } else {
+                Some(spawn_proxy(state.clone(), room_id, owner))
+            }
+        })
+        .collect())
+}
+
+fn spawn_proxy(state: Arc<AppState>, room_id: Uuid, owner: Uuid) -> mpsc::Sender<GameCommand> {
+    let (tx, mut rx) = mpsc::channel::<GameCommand>(32);
+    tokio::spawn(async move {
+        let mut conns: HashMap<Uuid, Uuid> = HashMap::new();
+        while let Some(cmd) = rx.recv().await {
+            let message = match cmd {
+                GameCommand::Join { user_id, player_sender } => {
+                    let conn = Uuid::new_v4();
+                    state.cluster.connections.insert(conn, RemoteConnection { owner, sender: player_sender });
+                    conns.insert(user_id, conn);
+                    RelayMessage::Join { room_id, user_id, conn, origin: state.cluster.instance_id }
+                }
+                GameCommand::Leave { user_id } => {
+                    let Some(conn) = conns.remove(&user_id) else { continue };
+                    state.cluster.connections.remove(&conn);
+                    RelayMessage::Leave { room_id, user_id, conn }
+                }
+                GameCommand::Move { user_id, idx, request_id } => RelayMessage::Move { room_id, user_id, idx, request_id },
+                GameCommand::Chat { user_id, text, request_id } => RelayMessage::Chat { room_id, user_id, text, request_id },
+                GameCommand::Block { user_id, other_id } => RelayMessage::Block { room_id, user_id, other_id },
+                GameCommand::AccountDeleted { user_id } => RelayMessage::AccountDeleted { room_id, user_id },
+                GameCommand::Admin { command, reply } => {
+                    let request = Uuid::new_v4();
+                    state.cluster.admin_replies.insert(request, reply);
+                    RelayMessage::Admin { room_id, command, request, origin: state.cluster.instance_id }
+                }
+                // Shutdown commands only go to rooms owned by this instance.
+                GameCommand::ShutdownNotice { .. } | GameCommand::Shutdown { .. } => continue,
+            };
+            if relay(&state, owner, &message).await {
+                continue;
+            }
+            match &message {
+                // Dropping the reply tells the admin the room is unavailable.
+                RelayMessage::Admin { request, .. } => {
+                    state.cluster.admin_replies.remove(request);
+                }
+                RelayMessage::Move { user_id, request_id, .. } | RelayMessage::Chat { user_id, request_id, .. } => {
+                    let sender = conns.get(user_id)
+                        .and_then(|conn| state.cluster.connections.get(conn).map(|c| c.sender.clone()));
+                    if let Some(sender) = sender {
+                        let error = GameError::new(ErrorCode::InvalidMessage, "the message could not be delivered to the room");
+                        let _ = sender.send(error.into_event(request_id.clone())).await;
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+    tx
+}
+
+/// Relays the events of a room owned here to a client on `origin`.
+async fn forward_events(state: Arc<AppState>, origin: Uuid, conn: Uuid, mut rx: mpsc::Receiver<GameEvent>) {
+    while let Some(event) = rx.recv().await {
+        let request_id = event.request_id().map(str::to_owned);
+        // A lost event would leave a gap in the client's sequence, so end
+        // the connection instead and let the client reconnect.
+        if !relay(&state, origin, &RelayMessage::Event { conn, request_id, event }).await {
+            break;
+        }
+    }
+    relay(&state, origin, &RelayMessage::Closed { conn }).await;
+    state.cluster.forwarders.remove(&conn);
+}
+
+async fn send_local(state: &AppState, room_id: Uuid, cmd: GameCommand) {
+    let room_tx = state.active_rooms.get(&room_id).map(|tx| tx.clone());
+    if let Some(tx) = room_tx {
+        let _ = tx.send(cmd).await;
+    }
+}
+
+async fn handle(state: &Arc<AppState>, message: RelayMessage) {
+    match message {
+        RelayMessage::Join { room_id, user_id, conn, origin } => {
+            let room_tx = state.active_rooms.get(&room_id).map(|tx| tx.clone());
+            let Some(room_tx) = room_tx else {
+                relay(state, origin, &RelayMessage::Closed { conn }).await;
+                return;
+            };
+            let (tx, rx) = mpsc::channel::<GameEvent>(32);
+            state.cluster.forwarders.insert(conn, tokio::spawn(forward_events(state.clone(), origin, conn, rx)));
+            let _ = room_tx.send(GameCommand::Join { user_id, player_sender: tx }).await;
+        }
+        RelayMessage::Leave { room_id, user_id, conn } => {
+            // Like a local socket, the client's channel must be closed before
+            // the room sees the leave or it is taken for a stale connection.
+            if let Some((_, forwarder)) = state.cluster.forwarders.remove(&conn) {
+                forwarder.abort();
+                let _ = forwarder.await;
+            }
+            send_local(state, room_id, GameCommand::Leave { user_id }).await;
+        }
+        RelayMessage::Move { room_id, user_id, idx, request_id } => {
+            send_local(state, room_id, GameCommand::Move { user_id, idx, request_id }).await;
+        }
+        RelayMessage::Chat { room_id, user_id, text, request_id } => {
+            send_local(state, room_id, GameCommand::Chat { user_id, text, request_id }).await;
+        }
+        RelayMessage::Block { room_id, user_id, other_id } => {
+            send_local(state, room_id, GameCommand::Block { user_id, other_id }).await;
+        }
+        RelayMessage::AccountDeleted { room_id, user_id } => {
+            send_local(state, room_id, GameCommand::AccountDeleted { user_id }).await;
+        }
+        RelayMessage::Admin { room_id, command, request, origin } => {
+            let room_tx = state.active_rooms.get(&room_id).map(|tx| tx.clone());
+            let state = state.clone();
+            // Waiting for the room here would hold up every relayed message.
+            tokio::spawn(async move {
+                let result = match room_tx {
+                    Some(tx) => {
+                        let (reply, rx) = oneshot::channel();
+                        match tx.send(GameCommand::Admin { command, reply }).await {
+                            Ok(()) => rx.await.ok(),
+                            Err(_) => None,
+                        }
+                    }
+                    None => None,
+                };
+                relay(&state, origin, &RelayMessage::AdminReply { request, result }).await;
+            });
+        }
+        RelayMessage::AdminReply { request, result } => {
+            if let Some((_, reply)) = state.cluster.admin_replies.remove(&request)
+                && let Some(result) = result {
+                let _ = reply.send(result);
+            }
+        }
+        RelayMessage::Event { conn, request_id, event } => {
+            // Waiting here would hold up every relayed message, so a client
+            // that has fallen behind is disconnected rather than skipped. It
+            // reconnects and starts again from a fresh snapshot.
+            let delivered = state.cluster.connections.get(&conn)
+                .map(|connection| connection.sender.try_send(event.with_request_id(request_id)).is_ok());
+            if delivered == Some(false) {
+                state.cluster.connections.remove(&conn);
+                println!("Disconnected relayed connection {} that fell behind", conn);
+            }
+        }
+        RelayMessage::Closed { conn } => {
+            state.cluster.connections.remove(&conn);
+        }
+    }
+}
+
+/// Subscribes to this instance's relay channel, registers the instance and
+/// takes over any rooms left behind by dead instances, then keeps relaying
+/// and heart-beating in the background.
+pub async fn start(state: Arc<AppState>) {
+    let instance_id = state.cluster.instance_id;
+    let mut listener = loop {
+        match state.db.listen(&channel(instance_id)).await {
+            Ok(listener) => break listener,
+            Err(e) => {
+                println!("Failed to listen for relayed messages: {:?}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    };
+    if let Err(e) = state.db.heartbeat(instance_id).await {
+        println!("Failed to register instance {}: {:?}", instance_id, e);
+    }
+    println!("Instance {} started", instance_id);
+    restore_rooms(state.clone(), true).await;
+
+    let relay_state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            match listener.recv().await {
+                Ok(notification) => match serde_json::from_str::<RelayMessage>(notification.payload()) {
+                    Ok(message) => handle(&relay_state, message).await,
+                    Err(e) => println!("Ignoring malformed relay message: {:?}", e),
+                },
+                Err(e) => {
+                    println!("Relay listener error: {:?}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+    tokio::spawn(run_heartbeat(state));
+}
+
+async fn run_heartbeat(state: Arc<AppState>) {
+    let cluster = &state.cluster;
+    let mut interval = tokio::time::interval(cluster.config.heartbeat);
+    loop {
+        interval.tick().await;
+        let shutting_down = state.shutting_down.load(Ordering::SeqCst);
+        // Once drained the instance deregisters; don't bring it back.
+        if shutting_down && state.active_rooms.is_empty() {
+            continue;
+        }
+        if let Err(e) = state.db.heartbeat(cluster.instance_id).await {
+            println!("Failed to send heartbeat: {:?}", e);
+            continue;
+        }
+        if !shutting_down {
+            restore_rooms(state.clone(), false).await;
+        }
+
+        // Clients of rooms whose owner died are disconnected so they
+        // reconnect to whichever instance takes the room over.
+        match state.db.get_live_instances(cluster.config.stale_after).await {
+            Ok(live) => cluster.connections.retain(|_, c| live.contains(&c.owner)),
+            Err(e) => println!("Failed to load live instances: {:?}", e),
+        }
+        // Replies the admin has stopped waiting for, e.g. from a dead owner.
+        cluster.admin_replies.retain(|_, reply| !reply.is_closed());
+    }
+}
+
+/// Removes this instance from the cluster once its rooms are closed.
+pub async fn leave(state: &AppState) {
+    if let Err(e) = state.db.remove_instance(state.cluster.instance_id).await {
+        println!("Failed to deregister instance: {:?}", e);
+    }
+}
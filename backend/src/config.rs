@@ -0,0 +1,5 @@
+/// Reads `key` from the environment, falling back to `default` when it is
+/// unset or does not parse.
+pub fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
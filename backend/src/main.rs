@@ -2,49 +2,175 @@ use db::Db;
 use actix_web::{HttpServer, App, web};
 use actix_web::middleware::Logger;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 use dashmap::DashMap;
 
-use crate::routes::room::create_room;
-use crate::routes::user::{signup, signin, me, get_all_stats, get_my_stats};
+use crate::routes::room::{create_room, get_game_chat, RoomConfig};
+use crate::routes::correspondence::{
+    create_correspondence_game, join_correspondence_game, get_correspondence_game, post_correspondence_move, run_sweeper,
+};
+use crate::config::env_or;
+use crate::routes::user::{signup, signin, me, change_password, change_username, delete_account, get_all_stats, get_my_stats};
+use crate::routes::auth::{refresh, logout, logout_all, guest, upgrade, jwks_endpoint, run_guest_sweeper};
+use crate::routes::bots::{create_bot, list_bots, create_api_key, list_api_keys, revoke_api_key, get_bot_stats};
+use crate::routes::oidc::{oidc_login, oidc_link, oidc_callback};
+use crate::routes::profile::{get_profile, get_profile_by_name, update_profile, upload_avatar, delete_avatar, get_avatar};
+use crate::routes::social::{
+    get_friends, remove_friend, get_friend_requests, send_friend_request, accept_friend_request, decline_friend_request,
+    get_following, get_followers, follow, unfollow, get_blocks, block_user, unblock_user,
+};
+use crate::routes::admin::{list_rooms, get_room, end_room, kick_player, message_room, broadcast, set_role};
 use crate::auth::middleware::JwtAuth;
+use crate::auth::guard::RequireRole;
+use crate::auth::user::Role;
+use crate::chat::{ChatConfig, WordListFilter};
+use crate::cluster::Cluster;
+use crate::auth::oidc::OidcClient;
+use crate::auth::lockout::LoginConfig;
+use crate::validation::AccountPolicy;
+use crate::avatar::AvatarConfig;
 use state::AppState;
 use ws::join_room;
+use sse::{room_events, create_stream_ticket, post_move};
 
 pub mod routes;
 pub mod auth;
 pub mod state;
 pub mod ws;
+pub mod sse;
+pub mod chat;
+pub mod protocol;
+pub mod config;
+pub mod rate_limit;
+pub mod shutdown;
+pub mod cluster;
+pub mod validation;
+pub mod avatar;
 
 #[actix_web::main]
 async fn main () {
     dotenvy::dotenv().unwrap();
+    auth::jwt::init_keys();
     let db = Db::new().await.unwrap();
     let active_rooms = Arc::new(DashMap::new());
     
     let app_state = web::Data::new(AppState {
         db: db.clone(),
-        active_rooms: active_rooms.clone()
+        active_rooms: active_rooms.clone(),
+        chat_config: ChatConfig::from_env(),
+        chat_filter: Arc::new(WordListFilter::from_env()),
+        room_config: RoomConfig::from_env(),
+        shutting_down: AtomicBool::new(false),
+        cluster: Cluster::from_env(),
+        oidc: OidcClient::from_env().unwrap(),
+        login_config: LoginConfig::from_env(),
+        account_policy: AccountPolicy::from_env(),
+        avatar_config: AvatarConfig::from_env(),
     });
     
-    let _ = HttpServer::new( move || {
+    cluster::start(app_state.clone().into_inner()).await;
+    tokio::spawn(run_sweeper(
+        app_state.clone().into_inner(),
+        Duration::from_secs(env_or("CORRESPONDENCE_SWEEP_SECS", 60)),
+    ));
+    tokio::spawn(run_guest_sweeper(
+        app_state.clone().into_inner(),
+        Duration::from_secs(env_or("GUEST_SWEEP_SECS", 3600)),
+    ));
+
+    let shutdown_state = app_state.clone();
+    let server = HttpServer::new( move || {
         App::new()
             .wrap(Logger::default())
             .app_data(app_state.clone())
             .service(signup)
             .service(signin)
+            .service(refresh)
+            .service(logout)
+            .service(logout_all)
+            .service(guest)
+            .service(upgrade)
+            .service(oidc_login)
+            .service(oidc_link)
+            .service(oidc_callback)
+            .service(jwks_endpoint)
             .service(get_all_stats)
+            .service(get_bot_stats)
+            .service(get_avatar)
+            .service(
+                web::scope("/api/users")
+                    .service(get_profile_by_name)
+                    .service(get_profile)
+            )
             .service(
                 web::scope("/api")
                     .service(me)
+                    .service(change_password)
+                    .service(change_username)
+                    .service(delete_account)
+                    .service(update_profile)
+                    .service(upload_avatar)
+                    .service(delete_avatar)
+                    .service(get_friends)
+                    .service(remove_friend)
+                    .service(get_friend_requests)
+                    .service(send_friend_request)
+                    .service(accept_friend_request)
+                    .service(decline_friend_request)
+                    .service(get_following)
+                    .service(get_followers)
+                    .service(follow)
+                    .service(unfollow)
+                    .service(get_blocks)
+                    .service(block_user)
+                    .service(unblock_user)
                     .service(get_my_stats)
                     .service(create_room)
                     .service(join_room)
+                    .service(room_events)
+                    .service(create_stream_ticket)
+                    .service(post_move)
+                    .service(get_game_chat)
+                    .service(create_correspondence_game)
+                    .service(join_correspondence_game)
+                    .service(get_correspondence_game)
+                    .service(post_correspondence_move)
+                    .service(create_bot)
+                    .service(list_bots)
+                    .service(create_api_key)
+                    .service(list_api_keys)
+                    .service(revoke_api_key)
+                    .wrap(JwtAuth)
+            )
+            .service(
+                web::scope("/admin")
+                    .service(list_rooms)
+                    .service(get_room)
+                    .service(end_room)
+                    .service(kick_player)
+                    .service(message_room)
+                    .service(broadcast)
+                    .service(set_role)
+                    .wrap(RequireRole(Role::Admin))
                     .wrap(JwtAuth)
-            )    
+            )
     })
-    .bind("0.0.0.0:3000")
+    .bind(env_or("BIND_ADDR", "0.0.0.0:3000".to_string()))
     .unwrap()
-    .run()
-    .await;
+    .disable_signals()
+    .run();
+
+    // Rooms need the server to keep running while they drain, so shutdown is
+    // driven from here rather than by actix's own signal handling.
+    let handle = server.handle();
+    actix_web::rt::spawn(async move {
+        shutdown::wait_for_signal().await;
+        shutdown::drain_rooms(&shutdown_state).await;
+        cluster::leave(&shutdown_state).await;
+        handle.stop(true).await;
+    });
+
+    let _ = server.await;
 }
 
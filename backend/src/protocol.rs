@@ -0,0 +1,151 @@
+use serde::{Serialize, Deserialize};
+
+use crate::routes::room::GameEvent;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+/// Wire format of a connection. JSON travels in text frames, the binary
+/// encodings in binary frames; all of them carry the same envelopes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::Msgpack),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Msgpack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Json => serde_json::to_vec(value)?,
+            Encoding::Msgpack => rmp_serde::to_vec_named(value)?,
+            Encoding::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf)?;
+                buf
+            }
+        })
+    }
+
+    pub fn decode<T: serde::de::DeserializeOwned>(self, bytes: &[u8]) -> anyhow::Result<T> {
+        Ok(match self {
+            Encoding::Json => serde_json::from_slice(bytes)?,
+            Encoding::Msgpack => rmp_serde::from_slice(bytes)?,
+            Encoding::Cbor => ciborium::from_reader(bytes)?,
+        })
+    }
+}
+
+/// Parses a `Sec-WebSocket-Protocol` entry of the form
+/// `tictactoe.v1.msgpack` into a version and encoding.
+pub fn parse_subprotocol(name: &str) -> Option<(u32, Encoding)> {
+    let rest = name.trim().strip_prefix("tictactoe.v")?;
+    let (version, encoding) = rest.split_once('.')?;
+    let version = version.parse().ok()?;
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return None;
+    }
+    Some((version, Encoding::parse(encoding)?))
+}
+
+pub fn subprotocol_name(version: u32, encoding: Encoding) -> String {
+    format!("tictactoe.v{}.{}", version, encoding.name())
+}
+
+/// Machine-readable reason attached to every `error` frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidMessage,
+    UnsupportedVersion,
+    RoomFull,
+    AlreadySeated,
+    GameNotActive,
+    NotSeated,
+    NotYourTurn,
+    InvalidCell,
+    CellOccupied,
+    StaleMove,
+    ChatDisabled,
+    MessageTooLong,
+    MessageRejected,
+    RateLimited,
+    BotsOnly,
+    /// One of the users has blocked the other.
+    Blocked,
+}
+
+impl ErrorCode {
+    /// The wire name of the code, e.g. `NOT_YOUR_TURN`.
+    pub fn name(self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl GameError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    pub fn into_event(self, request_id: Option<String>) -> GameEvent {
+        GameEvent::Error { request_id, code: self.code, message: self.message }
+    }
+}
+
+/// A client command: `{"v": 1, "id": "...", "type": "move", "payload": 4}`.
+#[derive(Deserialize)]
+pub struct ClientEnvelope {
+    pub v: u32,
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub payload: serde_json::Value,
+}
+
+/// A server frame. `seq` increases by one for every frame sent on a
+/// connection so clients can detect gaps; `id` echoes the request an
+/// `ack` or `error` refers to.
+#[derive(Serialize)]
+pub struct ServerEnvelope<'a> {
+    pub v: u32,
+    pub seq: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<&'a str>,
+    #[serde(flatten)]
+    pub event: &'a GameEvent,
+}
+
+impl<'a> ServerEnvelope<'a> {
+    pub fn new(seq: u64, event: &'a GameEvent) -> Self {
+        Self { v: PROTOCOL_VERSION, seq, id: event.request_id(), event }
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Sliding-window limiter keyed by user, owned by a single room task.
+#[derive(Default)]
+pub struct RateLimiter {
+    sent: HashMap<Uuid, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn check(&mut self, user_id: Uuid, limit: usize, window: Duration) -> bool {
+        let now = Instant::now();
+        let history = self.sent.entry(user_id).or_default();
+        while history.front().is_some_and(|&t| now.duration_since(t) > window) {
+            history.pop_front();
+        }
+        if history.len() >= limit {
+            return false;
+        }
+        history.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_up_to_the_limit_per_user() {
+        let mut limiter = RateLimiter::default();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let window = Duration::from_secs(60);
+        assert!(limiter.check(alice, 2, window));
+        assert!(limiter.check(alice, 2, window));
+        assert!(!limiter.check(alice, 2, window));
+        assert!(limiter.check(bob, 2, window));
+    }
+
+    #[test]
+    fn frees_slots_once_the_window_passes() {
+        let mut limiter = RateLimiter::default();
+        let user = Uuid::new_v4();
+        let window = Duration::from_millis(50);
+        assert!(limiter.check(user, 1, window));
+        assert!(!limiter.check(user, 1, window));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.check(user, 1, window));
+    }
+
+    #[test]
+    fn rejected_attempts_do_not_extend_the_window() {
+        let mut limiter = RateLimiter::default();
+        let user = Uuid::new_v4();
+        let window = Duration::from_millis(50);
+        assert!(limiter.check(user, 1, window));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!limiter.check(user, 1, window));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(limiter.check(user, 1, window));
+    }
+}
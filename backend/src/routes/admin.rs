@@ -0,0 +1,164 @@
+use std::time::Duration;
+use actix_web::{HttpResponse, Responder, get, post, put, web};
+use actix_web::http::StatusCode;
+use serde::{Serialize, Deserialize};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+use crate::cluster;
+use crate::state::AppState;
+use crate::auth::user::Role;
+use crate::protocol::{ErrorCode, GameError};
+use crate::routes::room::{AdminCommand, ForcedResult, GameCommand, RoomInfo};
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct RoomList {
+    instance_id: Uuid,
+    rooms: Vec<RoomInfo>,
+}
+
+#[derive(Deserialize)]
+pub struct EndRoomRequest {
+    pub result: ForcedResult,
+}
+
+#[derive(Deserialize)]
+pub struct KickRequest {
+    pub user_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleRequest {
+    pub role: Role,
+}
+
+#[derive(Deserialize)]
+pub struct SystemMessageRequest {
+    pub text: String,
+}
+
+enum AdminError {
+    Unavailable,
+    Rejected(GameError),
+}
+
+async fn send_admin(tx: &mpsc::Sender<GameCommand>, command: AdminCommand) -> Result<RoomInfo, AdminError> {
+    let (reply, rx) = oneshot::channel();
+    if tx.send(GameCommand::Admin { command, reply }).await.is_err() {
+        return Err(AdminError::Unavailable);
+    }
+    match tokio::time::timeout(REPLY_TIMEOUT, rx).await {
+        Ok(Ok(result)) => result.map_err(AdminError::Rejected),
+        _ => Err(AdminError::Unavailable),
+    }
+}
+
+/// Runs `command` against a room, on whichever instance owns it.
+async fn run(app_state: &web::Data<AppState>, room_id: Uuid, command: AdminCommand) -> HttpResponse {
+    let Some(room_tx) = cluster::room_sender(app_state, room_id).await else {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "room not found"
+        }));
+    };
+    match send_admin(&room_tx, command).await {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(AdminError::Rejected(e)) => {
+            let status = match e.code {
+                ErrorCode::GameNotActive => StatusCode::CONFLICT,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            };
+            HttpResponse::build(status).json(serde_json::json!({
+                "error": e.message,
+                "code": e.code
+            }))
+        }
+        Err(AdminError::Unavailable) => HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "room did not respond"
+        })),
+    }
+}
+
+/// Lists the rooms of every live instance.
+#[get("/rooms")]
+async fn list_rooms(app_state: web::Data<AppState>) -> impl Responder {
+    let senders = match cluster::all_room_senders(&app_state).await {
+        Ok(senders) => senders,
+        Err(e) => {
+            println!("Failed to list rooms: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let replies = futures::future::join_all(senders.iter().map(|tx| send_admin(tx, AdminCommand::Inspect))).await;
+    let mut rooms: Vec<RoomInfo> = replies.into_iter().filter_map(Result::ok).collect();
+    rooms.sort_by_key(|room| room.state.created_at);
+
+    HttpResponse::Ok().json(RoomList {
+        instance_id: app_state.cluster.instance_id,
+        rooms,
+    })
+}
+
+#[get("/rooms/{room_id}")]
+async fn get_room(path: web::Path<Uuid>, app_state: web::Data<AppState>) -> impl Responder {
+    run(&app_state, path.into_inner(), AdminCommand::Inspect).await
+}
+
+#[post("/rooms/{room_id}/end")]
+async fn end_room(path: web::Path<Uuid>, app_state: web::Data<AppState>, body: web::Json<EndRoomRequest>) -> impl Responder {
+    run(&app_state, path.into_inner(), AdminCommand::End(body.result)).await
+}
+
+#[post("/rooms/{room_id}/kick")]
+async fn kick_player(path: web::Path<Uuid>, app_state: web::Data<AppState>, body: web::Json<KickRequest>) -> impl Responder {
+    run(&app_state, path.into_inner(), AdminCommand::Kick(body.user_id)).await
+}
+
+#[post("/rooms/{room_id}/message")]
+async fn message_room(
+    path: web::Path<Uuid>,
+    app_state: web::Data<AppState>,
+    body: web::Json<SystemMessageRequest>,
+) -> impl Responder {
+    run(&app_state, path.into_inner(), AdminCommand::Broadcast(body.into_inner().text)).await
+}
+
+/// Sends a system message to every room of every live instance.
+#[post("/broadcast")]
+async fn broadcast(app_state: web::Data<AppState>, body: web::Json<SystemMessageRequest>) -> impl Responder {
+    let senders = match cluster::all_room_senders(&app_state).await {
+        Ok(senders) => senders,
+        Err(e) => {
+            println!("Failed to list rooms: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let replies = futures::future::join_all(
+        senders.iter().map(|tx| send_admin(tx, AdminCommand::Broadcast(body.text.clone())))
+    ).await;
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "rooms": replies.iter().filter(|r| r.is_ok()).count()
+    }))
+}
+
+/// Changes a user's role. Their current access tokens are revoked, so the
+/// new role applies from their next token refresh or sign-in.
+#[put("/users/{user_id}/role")]
+async fn set_role(path: web::Path<Uuid>, app_state: web::Data<AppState>, body: web::Json<SetRoleRequest>) -> impl Responder {
+    let user_id = path.into_inner();
+    match app_state.db.set_user_role(user_id, body.role.name()).await {
+        Ok(true) => HttpResponse::Ok().json(serde_json::json!({
+            "user_id": user_id,
+            "role": body.role
+        })),
+        Ok(false) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": "user not found"
+        })),
+        Err(e) => {
+            println!("Failed to set role for {}: {:?}", user_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::{HttpResponse, Responder, get, post, web};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::state::AppState;
+use crate::auth::jwt::{access_token_ttl, create_jwt_for_user, hash_refresh_token, jwks, new_refresh_token, refresh_token_ttl};
+use crate::auth::middleware::JwtAuth;
+use crate::auth::guard::RequireScope;
+use crate::auth::user::{AuthUser, Role, Scope};
+use crate::config::env_or;
+use crate::routes::user::{LoginRequest, SigninResponse, hash_password};
+use crate::validation::{FieldErrors, username_taken};
+use db::models::tokens::RefreshOutcome;
+use db::models::users::GuestUpgrade;
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct LogoutRequest {
+    /// Also ends the session this refresh token belongs to.
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GuestResponse {
+    pub id: Uuid,
+    pub username: String,
+    #[serde(flatten)]
+    pub session: SigninResponse,
+}
+
+fn session_tokens(user_id: Uuid, role: Role, refresh_token: String) -> Result<SigninResponse, Box<HttpResponse>> {
+    match create_jwt_for_user(&user_id.to_string(), role) {
+        Ok(token) => Ok(SigninResponse {
+            token,
+            refresh_token,
+            expires_in: access_token_ttl().num_seconds(),
+        }),
+        Err(_) => Err(Box::new(HttpResponse::InternalServerError().finish())),
+    }
+}
+
+fn signin_response(user_id: Uuid, role: Role, refresh_token: String) -> HttpResponse {
+    match session_tokens(user_id, role, refresh_token) {
+        Ok(session) => HttpResponse::Ok().json(session),
+        Err(resp) => *resp,
+    }
+}
+
+async fn create_session(app_state: &AppState, user_id: Uuid, role: Role) -> Result<SigninResponse, Box<HttpResponse>> {
+    let (refresh_token, hash) = new_refresh_token();
+    let expires_at = chrono::Utc::now() + refresh_token_ttl();
+    if let Err(e) = app_state.db.create_refresh_token(user_id, &hash, expires_at).await {
+        println!("Failed to store refresh token: {:?}", e);
+        return Err(Box::new(HttpResponse::InternalServerError().finish()));
+    }
+    session_tokens(user_id, role, refresh_token)
+}
+
+/// Starts a new session: an access token plus a refresh token.
+pub async fn issue_session(app_state: &AppState, user_id: Uuid, role: Role) -> HttpResponse {
+    match create_session(app_state, user_id, role).await {
+        Ok(session) => HttpResponse::Ok().json(session),
+        Err(resp) => *resp,
+    }
+}
+
+/// Creates a guest account under a generated name and signs it in. Guests
+/// stay off the leaderboard and expire after `GUEST_TTL_DAYS` without a
+/// sign-in or refresh.
+#[post("/auth/guest")]
+async fn guest(app_state: web::Data<AppState>) -> impl Responder {
+    for _ in 0..5 {
+        let username = format!("guest-{:08x}", rand::random::<u32>());
+        match app_state.db.create_guest_user(&username).await {
+            Ok(Some(id)) => {
+                println!("Created guest {} ({})", username, id);
+                return match create_session(&app_state, id, Role::Player).await {
+                    Ok(session) => HttpResponse::Ok().json(GuestResponse { id, username, session }),
+                    Err(resp) => *resp,
+                };
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                println!("Failed to create guest: {:?}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    HttpResponse::ServiceUnavailable().json(serde_json::json!({
+        "error": "could not find a free guest name, try again"
+    }))
+}
+
+/// Turns the calling guest into a regular account with the given username
+/// and password. Its sessions and games carry over.
+#[post("/auth/upgrade", wrap = "RequireScope(Scope::Session)", wrap = "JwtAuth")]
+async fn upgrade(user: AuthUser, app_state: web::Data<AppState>, body: web::Json<LoginRequest>) -> impl Responder {
+    let mut errors = FieldErrors::default();
+    if let Err(e) = app_state.account_policy.check_username(&app_state.db, &body.username, Some(user.id), &mut errors).await {
+        println!("Failed to check username {}: {:?}", body.username, e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    app_state.account_policy.check_password("password", &body.password, &body.username, &mut errors);
+    if let Err(resp) = errors.into_result() {
+        return *resp;
+    }
+    let password_hash = match hash_password(&body.password) {
+        Ok(phc) => phc,
+        Err(_) => return HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": "failed to hash password"
+        }))
+    };
+    match app_state.db.upgrade_guest(user.id, &body.username, &password_hash).await {
+        Ok(GuestUpgrade::Upgraded) => {
+            println!("Guest {} upgraded to {}", user.id, body.username);
+            HttpResponse::Ok().json(serde_json::json!({
+                "message": "account upgraded",
+                "id": user.id,
+                "username": body.username
+            }))
+        }
+        Ok(GuestUpgrade::NotGuest) => HttpResponse::Conflict().json(serde_json::json!({
+            "error": "not a guest account"
+        })),
+        Ok(GuestUpgrade::UsernameTaken) => username_taken(&body.username),
+        Err(e) => {
+            println!("Failed to upgrade guest {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Expires guests that have been inactive for `GUEST_TTL_DAYS`.
+pub async fn run_guest_sweeper(state: Arc<AppState>, every: Duration) {
+    let ttl = chrono::Duration::days(env_or("GUEST_TTL_DAYS", 7));
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        match state.db.expire_guests(chrono::Utc::now() - ttl).await {
+            Ok((0, 0)) => {}
+            Ok((deleted, retired)) => println!("Expired guests: {} deleted, {} with games kept", deleted, retired),
+            Err(e) => println!("Failed to expire guests: {:?}", e),
+        }
+    }
+}
+
+/// Trades a refresh token for a new access token and a new refresh token.
+/// Each refresh token works once; presenting a used one ends the session.
+#[post("/auth/refresh")]
+async fn refresh(app_state: web::Data<AppState>, body: web::Json<RefreshRequest>) -> impl Responder {
+    let (refresh_token, new_hash) = new_refresh_token();
+    let expires_at = chrono::Utc::now() + refresh_token_ttl();
+    match app_state.db.rotate_refresh_token(&hash_refresh_token(&body.refresh_token), &new_hash, expires_at).await {
+        Ok(RefreshOutcome::Rotated(session)) => {
+            let role = Role::parse(&session.role).unwrap_or_default();
+            signin_response(session.user_id, role, refresh_token)
+        }
+        Ok(RefreshOutcome::Invalid) => HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "invalid or expired refresh token"
+        })),
+        Ok(RefreshOutcome::Reused) => HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "refresh token already used, session revoked"
+        })),
+        Err(e) => {
+            println!("Failed to rotate refresh token: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Revokes the access token used for the request and, if given, the
+/// session of the refresh token.
+#[post("/auth/logout", wrap = "RequireScope(Scope::Session)", wrap = "JwtAuth")]
+async fn logout(user: AuthUser, app_state: web::Data<AppState>, body: Option<web::Json<LogoutRequest>>) -> impl Responder {
+    let mut result = app_state.db.revoke_access_token(user.id, user.jti, user.expires_at).await;
+    if let Some(refresh_token) = body.and_then(|b| b.into_inner().refresh_token) {
+        result = result.and(app_state.db.revoke_refresh_token(user.id, &hash_refresh_token(&refresh_token)).await);
+    }
+    match result {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            println!("Failed to log out {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Revokes every session of the user, on every device.
+#[post("/auth/logout-all", wrap = "RequireScope(Scope::Session)", wrap = "JwtAuth")]
+async fn logout_all(user: AuthUser, app_state: web::Data<AppState>) -> impl Responder {
+    match app_state.db.revoke_all_sessions(user.id).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            println!("Failed to revoke sessions of {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Public keys for verifying our access tokens, by `kid`.
+#[get("/.well-known/jwks.json")]
+async fn jwks_endpoint() -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "public, max-age=300"))
+        .json(jwks())
+}
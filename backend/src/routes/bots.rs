@@ -0,0 +1,201 @@
+use actix_web::{HttpResponse, Responder, delete, get, post, web};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::state::AppState;
+use crate::auth::api_key::new_api_key;
+use crate::auth::guard::RequireScope;
+use crate::auth::user::{AuthUser, Scope};
+use crate::validation::{FieldErrors, check_text, username_taken};
+use db::models::bots::ApiKey;
+
+const API_KEY_NAME_MAX_LENGTH: usize = 100;
+
+#[derive(Deserialize)]
+pub struct CreateBotRequest {
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scope: String,
+}
+
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub info: ApiKey,
+    /// The key itself. Only ever returned here.
+    pub key: String,
+}
+
+#[derive(Serialize)]
+pub struct BotStatsEntry {
+    pub user_id: Uuid,
+    pub username: String,
+    pub owner_id: Option<Uuid>,
+    pub games_played: i64,
+    pub games_won: i64,
+    pub win_rate: f32,
+}
+
+/// Fails unless `bot_id` is one of the caller's bots.
+async fn check_owner(app_state: &AppState, user: &AuthUser, bot_id: Uuid) -> Result<(), Box<HttpResponse>> {
+    match app_state.db.is_bot_owner(bot_id, user.id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Box::new(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "bot not found"
+        })))),
+        Err(e) => {
+            println!("Failed to look up bot {}: {:?}", bot_id, e);
+            Err(Box::new(HttpResponse::InternalServerError().finish()))
+        }
+    }
+}
+
+/// Creates a bot account owned by the caller. Bots have no password and
+/// play through API keys.
+#[post("/bots", wrap = "RequireScope(Scope::Session)")]
+async fn create_bot(user: AuthUser, app_state: web::Data<AppState>, body: web::Json<CreateBotRequest>) -> impl Responder {
+    match app_state.db.is_guest(user.id).await {
+        Ok(false) => {}
+        Ok(true) => return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "guests cannot own bots"
+        })),
+        Err(e) => {
+            println!("Failed to look up user {}: {:?}", user.id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    let mut errors = FieldErrors::default();
+    if let Err(e) = app_state.account_policy.check_username(&app_state.db, &body.username, None, &mut errors).await {
+        println!("Failed to check username {}: {:?}", body.username, e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    if let Err(resp) = errors.into_result() {
+        return *resp;
+    }
+    match app_state.db.create_bot(user.id, &body.username).await {
+        Ok(Some(bot)) => {
+            println!("User {} created bot {} ({})", user.id, bot.username, bot.id);
+            HttpResponse::Ok().json(bot)
+        }
+        Ok(None) => username_taken(&body.username),
+        Err(e) => {
+            println!("Failed to create bot: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/bots", wrap = "RequireScope(Scope::Session)")]
+async fn list_bots(user: AuthUser, app_state: web::Data<AppState>) -> impl Responder {
+    match app_state.db.get_bots(user.id).await {
+        Ok(bots) => HttpResponse::Ok().json(bots),
+        Err(e) => {
+            println!("Failed to list bots of {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Issues an API key for one of the caller's bots. The key is in the
+/// response and cannot be retrieved again.
+#[post("/bots/{bot_id}/keys", wrap = "RequireScope(Scope::Session)")]
+async fn create_api_key(
+    user: AuthUser,
+    path: web::Path<Uuid>,
+    app_state: web::Data<AppState>,
+    body: web::Json<CreateApiKeyRequest>,
+) -> impl Responder {
+    let bot_id = path.into_inner();
+    if let Err(resp) = check_owner(&app_state, &user, bot_id).await {
+        return *resp;
+    }
+    let Some(scope) = Scope::parse_key_scope(&body.scope) else {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "scope must be read or play"
+        }));
+    };
+    let mut errors = FieldErrors::default();
+    let name = check_text(&mut errors, "name", &body.name, API_KEY_NAME_MAX_LENGTH, false).unwrap_or_else(|| {
+        errors.add("name", "required", "must not be empty");
+        String::new()
+    });
+    if let Err(resp) = errors.into_result() {
+        return *resp;
+    }
+
+    let (key, shown, hash) = new_api_key();
+    match app_state.db.create_api_key(bot_id, &name, &shown, &hash, scope.name()).await {
+        Ok(info) => HttpResponse::Ok().json(CreatedApiKey { info, key }),
+        Err(e) => {
+            println!("Failed to create API key for {}: {:?}", bot_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/bots/{bot_id}/keys", wrap = "RequireScope(Scope::Session)")]
+async fn list_api_keys(user: AuthUser, path: web::Path<Uuid>, app_state: web::Data<AppState>) -> impl Responder {
+    let bot_id = path.into_inner();
+    if let Err(resp) = check_owner(&app_state, &user, bot_id).await {
+        return *resp;
+    }
+    match app_state.db.get_api_keys(bot_id).await {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(e) => {
+            println!("Failed to list API keys of {}: {:?}", bot_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/bots/{bot_id}/keys/{key_id}", wrap = "RequireScope(Scope::Session)")]
+async fn revoke_api_key(user: AuthUser, path: web::Path<(Uuid, Uuid)>, app_state: web::Data<AppState>) -> impl Responder {
+    let (bot_id, key_id) = path.into_inner();
+    if let Err(resp) = check_owner(&app_state, &user, bot_id).await {
+        return *resp;
+    }
+    match app_state.db.revoke_api_key(bot_id, key_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": "API key not found"
+        })),
+        Err(e) => {
+            println!("Failed to revoke API key {}: {:?}", key_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The bot leaderboard, kept apart from `/stats`.
+#[get("/stats/bots")]
+async fn get_bot_stats(app_state: web::Data<AppState>) -> impl Responder {
+    match app_state.db.get_all_bot_stats().await {
+        Ok(stats) => {
+            let entries: Vec<BotStatsEntry> = stats.into_iter()
+                .map(|s| BotStatsEntry {
+                    user_id: s.id,
+                    username: s.username,
+                    owner_id: s.owner_id,
+                    games_played: s.games_played,
+                    games_won: s.games_won,
+                    win_rate: if s.games_played > 0 {
+                        (s.games_won as f32 / s.games_played as f32 * 10000.0).round() / 100.0
+                    } else {
+                        0.0
+                    },
+                })
+                .collect();
+            HttpResponse::Ok().json(entries)
+        }
+        Err(e) => {
+            println!("Failed to get bot stats: {:?}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to retrieve bot statistics"
+            }))
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use std::{sync::Arc, time::Duration};
+use actix_web::{HttpResponse, Responder, get, post, web};
+use actix_web::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::state::AppState;
+use crate::auth::guard::RequireScope;
+use crate::auth::user::{AuthUser, Scope};
+use crate::protocol::{ErrorCode, GameError};
+use crate::routes::room::{creator_moves_first, GameState, GameStatus, RoomOptions};
+use db::models::audit::RecordRejectedMove;
+use db::models::correspondence::{CreateCorrespondenceGame, StartCorrespondenceGame};
+use db::models::games::{Game, PlayerSymbol};
+
+const MAX_DAYS_PER_MOVE: i32 = 14;
+
+#[derive(Deserialize)]
+pub struct CreateCorrespondenceRequest {
+    pub days_per_move: i32,
+    /// Restricts the challenge to one opponent; anyone can accept it otherwise.
+    pub opponent_id: Option<Uuid>,
+    #[serde(flatten)]
+    pub options: RoomOptions,
+}
+
+#[derive(Deserialize)]
+pub struct CorrespondenceMoveRequest {
+    pub cell: usize,
+    /// Number this move will have, i.e. moves played so far plus one.
+    pub move_number: i32,
+}
+
+#[derive(Serialize)]
+pub struct CorrespondenceGameView {
+    pub id: Uuid,
+    pub status: GameStatus,
+    pub board: [Option<PlayerSymbol>; 9],
+    pub current_turn: Option<PlayerSymbol>,
+    pub player_x: Option<Uuid>,
+    pub player_o: Option<Uuid>,
+    pub first_player: Option<Uuid>,
+    pub move_number: i32,
+    pub winning_line: Option<[usize; 3]>,
+    pub winner_id: Option<Uuid>,
+    pub days_per_move: Option<i32>,
+    pub move_deadline: Option<DateTime<Utc>>,
+}
+
+impl CorrespondenceGameView {
+    fn new(record: &Game) -> Self {
+        let game = GameState::from_record(record);
+        Self {
+            id: record.id,
+            status: game.status,
+            board: game.board,
+            current_turn: (game.status == GameStatus::Active).then_some(game.current_turn),
+            player_x: game.player_x,
+            player_o: game.player_o,
+            first_player: game.first_player,
+            move_number: game.move_count,
+            winning_line: game.winning_line(),
+            winner_id: record.winner_id,
+            days_per_move: record.days_per_move,
+            move_deadline: record.move_deadline,
+        }
+    }
+}
+
+fn error_response(error: GameError) -> HttpResponse {
+    let status = match error.code {
+        ErrorCode::NotSeated | ErrorCode::BotsOnly | ErrorCode::Blocked => StatusCode::FORBIDDEN,
+        ErrorCode::InvalidCell | ErrorCode::InvalidMessage => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::CONFLICT,
+    };
+    HttpResponse::build(status).json(serde_json::json!({
+        "error": error.message,
+        "code": error.code
+    }))
+}
+
+/// Rejects a challenge between users where either has blocked the other.
+async fn check_not_blocked(app_state: &AppState, user_id: Uuid, other_id: Uuid) -> Result<(), Box<HttpResponse>> {
+    match app_state.db.is_blocked_between(user_id, other_id).await {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(Box::new(error_response(GameError::new(ErrorCode::Blocked, "you cannot play this user")))),
+        Err(e) => {
+            println!("Failed to check blocks between {} and {}: {:?}", user_id, other_id, e);
+            Err(Box::new(HttpResponse::InternalServerError().finish()))
+        }
+    }
+}
+
+async fn load_correspondence_game(app_state: &AppState, game_id: Uuid) -> Result<Game, Box<HttpResponse>> {
+    match app_state.db.get_game(game_id).await {
+        Ok(Some(record)) if record.mode == "correspondence" => Ok(record),
+        Ok(_) => Err(Box::new(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "correspondence game not found"
+        })))),
+        Err(e) => {
+            println!("Failed to load game {}: {:?}", game_id, e);
+            Err(Box::new(HttpResponse::InternalServerError().finish()))
+        }
+    }
+}
+
+#[post("/games/correspondence", wrap = "RequireScope(Scope::Play)")]
+async fn create_correspondence_game(
+    user: AuthUser,
+    app_state: web::Data<AppState>,
+    body: web::Json<CreateCorrespondenceRequest>,
+) -> impl Responder {
+    let user_id = user.id;
+    if !(1..=MAX_DAYS_PER_MOVE).contains(&body.days_per_move) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("days_per_move must be between 1 and {}", MAX_DAYS_PER_MOVE)
+        }));
+    }
+    if body.opponent_id == Some(user_id) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "you cannot challenge yourself"
+        }));
+    }
+    if body.options.bots_only && !user.is_bot {
+        return error_response(GameError::new(ErrorCode::BotsOnly, "only bots can create bot-only challenges"));
+    }
+    if let Some(opponent_id) = body.opponent_id
+        && let Err(resp) = check_not_blocked(&app_state, user_id, opponent_id).await {
+        return *resp;
+    }
+
+    let options = match serde_json::to_value(body.options) {
+        Ok(o) => o,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    match app_state.db.create_correspondence_game(CreateCorrespondenceGame {
+        creator_id: user_id,
+        invited_id: body.opponent_id,
+        days_per_move: body.days_per_move,
+        options,
+    }).await {
+        Ok(game_id) => HttpResponse::Ok().json(serde_json::json!({ "game_id": game_id })),
+        Err(e) => {
+            println!("Failed to create correspondence game: {:?}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "failed to create game"
+            }))
+        }
+    }
+}
+
+#[post("/games/{game_id}/join", wrap = "RequireScope(Scope::Play)")]
+async fn join_correspondence_game(
+    user: AuthUser,
+    path: web::Path<Uuid>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let user_id = user.id;
+    let record = match load_correspondence_game(&app_state, path.into_inner()).await {
+        Ok(r) => r,
+        Err(resp) => return *resp,
+    };
+    if record.invited_id.is_some_and(|invited| invited != user_id) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "this challenge is for another player"
+        }));
+    }
+
+    let mut game = GameState::from_record(&record);
+    if game.options.bots_only && !user.is_bot {
+        return error_response(GameError::new(ErrorCode::BotsOnly, "only bots can join this game"));
+    }
+    if let Some(creator_id) = record.creator_id
+        && let Err(resp) = check_not_blocked(&app_state, user_id, creator_id).await {
+        return *resp;
+    }
+    match game.add_player(user_id) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::InternalServerError().finish(),
+        Err(e) => return error_response(e),
+    }
+    let Some((creator_seat, joiner_seat)) = game.seat_order(user_id) else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    let creator_first = creator_moves_first(&app_state, game.options.first_player, creator_seat, joiner_seat).await;
+    game.assign_seats(creator_seat, joiner_seat, creator_first);
+
+    let (Some(player_x_id), Some(player_o_id), Some(first_player_id)) = (game.player_x, game.player_o, game.first_player) else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    match app_state.db.start_correspondence_game(StartCorrespondenceGame {
+        game_id: record.id,
+        player_x_id,
+        player_o_id,
+        first_player_id,
+    }).await {
+        Ok(true) => {}
+        Ok(false) => return error_response(GameError::new(ErrorCode::RoomFull, "game has already started")),
+        Err(e) => {
+            println!("Failed to start correspondence game: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match load_correspondence_game(&app_state, record.id).await {
+        Ok(record) => HttpResponse::Ok().json(CorrespondenceGameView::new(&record)),
+        Err(resp) => *resp,
+    }
+}
+
+#[get("/games/{game_id}")]
+async fn get_correspondence_game(path: web::Path<Uuid>, app_state: web::Data<AppState>) -> impl Responder {
+    match load_correspondence_game(&app_state, path.into_inner()).await {
+        Ok(record) => HttpResponse::Ok().json(CorrespondenceGameView::new(&record)),
+        Err(resp) => *resp,
+    }
+}
+
+#[post("/games/{game_id}/moves", wrap = "RequireScope(Scope::Play)")]
+async fn post_correspondence_move(
+    user: AuthUser,
+    path: web::Path<Uuid>,
+    app_state: web::Data<AppState>,
+    body: web::Json<CorrespondenceMoveRequest>,
+) -> impl Responder {
+    let user_id = user.id;
+    let record = match load_correspondence_game(&app_state, path.into_inner()).await {
+        Ok(r) => r,
+        Err(resp) => return *resp,
+    };
+
+    let mut game = GameState::from_record(&record);
+    let checked = if body.move_number != game.move_count + 1 {
+        Err(GameError::new(
+            ErrorCode::StaleMove,
+            format!("expected move number {}", game.move_count + 1),
+        ))
+    } else if record.move_deadline.is_some_and(|deadline| deadline <= Utc::now()) {
+        Err(GameError::new(ErrorCode::GameNotActive, "time to move has run out"))
+    } else {
+        game.validate_move(user_id, body.cell)
+    };
+    if let Err(e) = checked {
+        let code = e.code.name();
+        if let Err(db_err) = app_state.db.record_rejected_move(RecordRejectedMove {
+            room_id: record.room_id,
+            game_id: Some(record.id),
+            user_id,
+            cell: i32::try_from(body.cell).unwrap_or(i32::MAX),
+            code: &code,
+            message: &e.message,
+        }).await {
+            println!("Failed to record rejected move: {:?}", db_err);
+        }
+        return error_response(e);
+    }
+
+    if let Err(e) = game.make_move(body.cell) {
+        return error_response(e);
+    }
+    let result = if let Some(winner_symbol) = game.check_winner() {
+        Some(match winner_symbol {
+            PlayerSymbol::X => game.player_x,
+            PlayerSymbol::O => game.player_o,
+        })
+    } else if game.is_draw() {
+        Some(None)
+    } else {
+        None
+    };
+    match app_state.db.apply_correspondence_move(record.id, record.moves_count, &game.board, result).await {
+        Ok(true) => {}
+        Ok(false) => return error_response(GameError::new(ErrorCode::StaleMove, "the game changed, reload and try again")),
+        Err(e) => {
+            println!("Failed to store correspondence move: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match load_correspondence_game(&app_state, record.id).await {
+        Ok(record) => HttpResponse::Ok().json(CorrespondenceGameView::new(&record)),
+        Err(resp) => *resp,
+    }
+}
+
+/// Adjudicates correspondence games whose player to move ran out of time:
+/// the other player wins.
+pub async fn run_sweeper(state: Arc<AppState>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let expired = match state.db.get_expired_correspondence_games().await {
+            Ok(games) => games,
+            Err(e) => {
+                println!("Failed to load expired correspondence games: {:?}", e);
+                continue;
+            }
+        };
+        for record in expired {
+            let game = GameState::from_record(&record);
+            let winner_id = match game.current_turn {
+                PlayerSymbol::X => game.player_o,
+                PlayerSymbol::O => game.player_x,
+            };
+            match state.db.finish_expired_game(record.id, winner_id).await {
+                Ok(true) => println!("Correspondence game {} timed out, winner: {:?}", record.id, winner_id),
+                Ok(false) => {}
+                Err(e) => println!("Failed to adjudicate game {}: {:?}", record.id, e),
+            }
+        }
+    }
+}
@@ -1,2 +1,9 @@
 pub mod user;
-pub mod room;
\ No newline at end of file
+pub mod room;
+pub mod correspondence;
+pub mod auth;
+pub mod admin;
+pub mod bots;
+pub mod oidc;
+pub mod profile;
+pub mod social;
@@ -0,0 +1,245 @@
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
+use actix_web::cookie::{Cookie, SameSite, time::Duration};
+use actix_web::http::header::LOCATION;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::state::AppState;
+use crate::auth::guard::RequireScope;
+use crate::auth::middleware::JwtAuth;
+use crate::auth::oidc::{IdentityClaims, OidcClient};
+use crate::auth::user::{AuthUser, Role, Scope};
+use crate::routes::auth::issue_session;
+use crate::validation::{AccountPolicy, FieldErrors};
+use db::models::identities::{LinkOutcome, LOGIN_TIMEOUT_MINUTES};
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+    pub code: Option<String>,
+    pub state: String,
+    /// Set by the provider instead of `code` when sign-in failed.
+    pub error: Option<String>,
+}
+
+fn oidc_client(app_state: &AppState) -> Result<&OidcClient, Box<HttpResponse>> {
+    app_state.oidc.as_ref().ok_or_else(|| Box::new(HttpResponse::NotFound().json(serde_json::json!({
+        "error": "single sign-on is not configured"
+    }))))
+}
+
+/// Holds a sign-in's `state` in the browser that started it, so the
+/// callback only completes sign-ins begun there. `Lax` so it is sent on
+/// the provider's redirect back.
+const STATE_COOKIE: &str = "oidc_state";
+
+fn state_cookie(oidc: &OidcClient, state: String) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, state)
+        .path("/auth/oidc")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(oidc.redirect_uri.starts_with("https://"))
+        .max_age(Duration::minutes(LOGIN_TIMEOUT_MINUTES.into()))
+        .finish()
+}
+
+/// Starts a sign-in at the provider and remembers it until the callback.
+/// Returns the URL to send the browser to and the cookie binding the
+/// sign-in to it.
+async fn start_login(app_state: &AppState, link_user_id: Option<Uuid>) -> Result<(String, Cookie<'static>), Box<HttpResponse>> {
+    let oidc = oidc_client(app_state)?;
+    let request = oidc.authorization_request().await.map_err(|e| {
+        println!("Failed to reach identity provider: {:?}", e);
+        Box::new(HttpResponse::BadGateway().json(serde_json::json!({
+            "error": "identity provider unavailable"
+        })))
+    })?;
+    if let Err(e) = app_state.db.create_oidc_login(&request.state, &request.code_verifier, &request.nonce, link_user_id).await {
+        println!("Failed to store sign-in state: {:?}", e);
+        return Err(Box::new(HttpResponse::InternalServerError().finish()));
+    }
+    Ok((request.url, state_cookie(oidc, request.state)))
+}
+
+/// How many usernames to try for a new account before giving up.
+const USERNAME_ATTEMPTS: usize = 5;
+
+/// A username for a new account, from the identity's preferred username or
+/// email with anything `AccountPolicy` does not allow left out. Later
+/// attempts get a random suffix, and the last one is generated outright in
+/// case the provider's name can never pass.
+fn username_candidate(policy: &AccountPolicy, claims: &IdentityClaims, attempt: usize) -> String {
+    let name = claims.preferred_username.as_deref()
+        .or_else(|| claims.email.as_deref().and_then(|e| e.split('@').next()))
+        .unwrap_or_default();
+    let mut base: String = name.chars()
+        .filter(|&c| policy.username_char(c))
+        .skip_while(|c| matches!(c, '_' | '-' | '.'))
+        .take(policy.username_max_length.saturating_sub(5))
+        .collect();
+    if base.is_empty() {
+        base = "user".to_owned();
+    }
+    match attempt {
+        0 => base,
+        n if n + 1 == USERNAME_ATTEMPTS => format!("player-{:08x}", rand::random::<u32>()),
+        _ => format!("{}-{:04x}", base, rand::random::<u16>()),
+    }
+}
+
+/// Signs in the account linked to the identity, creating one on first use.
+async fn sign_in(app_state: &AppState, issuer: &str, claims: &IdentityClaims) -> HttpResponse {
+    match app_state.db.get_identity_user(issuer, &claims.sub).await {
+        Ok(Some(user)) => {
+            let role = Role::parse(&user.role).unwrap_or_default();
+            return issue_session(app_state, user.user_id, role).await;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            println!("Failed to look up identity {}: {:?}", claims.sub, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    for attempt in 0..USERNAME_ATTEMPTS {
+        let username = username_candidate(&app_state.account_policy, claims, attempt);
+        let mut errors = FieldErrors::default();
+        if let Err(e) = app_state.account_policy.check_username(&app_state.db, &username, None, &mut errors).await {
+            println!("Failed to check username {}: {:?}", username, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+        if !errors.is_empty() {
+            continue;
+        }
+        match app_state.db.create_identity_user(&username, issuer, &claims.sub, claims.email.as_deref()).await {
+            Ok(Some(user_id)) => {
+                println!("Created user {} ({}) for identity {}", username, user_id, claims.sub);
+                return issue_session(app_state, user_id, Role::Player).await;
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                println!("Failed to create user for identity {}: {:?}", claims.sub, e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    HttpResponse::ServiceUnavailable().json(serde_json::json!({
+        "error": "could not find a free username, try again"
+    }))
+}
+
+/// Sends the browser to the identity provider to sign in.
+#[get("/auth/oidc/login")]
+async fn oidc_login(app_state: web::Data<AppState>) -> impl Responder {
+    match start_login(&app_state, None).await {
+        Ok((url, cookie)) => HttpResponse::Found().insert_header((LOCATION, url)).cookie(cookie).finish(),
+        Err(resp) => *resp,
+    }
+}
+
+/// Starts linking an identity at the provider to the caller's account.
+/// Returns the URL to send the browser to. The browser has to keep the
+/// cookie set here, so cross-origin callers need to send credentials.
+#[post("/auth/oidc/link", wrap = "RequireScope(Scope::Session)", wrap = "JwtAuth")]
+async fn oidc_link(user: AuthUser, app_state: web::Data<AppState>) -> impl Responder {
+    match start_login(&app_state, Some(user.id)).await {
+        Ok((url, cookie)) => HttpResponse::Ok().cookie(cookie).json(serde_json::json!({ "authorization_url": url })),
+        Err(resp) => *resp,
+    }
+}
+
+/// Where the provider sends the browser back to. Finishes a sign-in with
+/// a session of our own, or a link, if this browser started it.
+#[get("/auth/oidc/callback")]
+async fn oidc_callback(req: HttpRequest, app_state: web::Data<AppState>, params: web::Query<CallbackParams>) -> impl Responder {
+    let oidc = match oidc_client(&app_state) {
+        Ok(oidc) => oidc,
+        Err(resp) => return *resp,
+    };
+    let mut resp = match req.cookie(STATE_COOKIE) {
+        Some(cookie) if cookie.value() == params.state => finish_login(&app_state, oidc, &params).await,
+        _ => HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "sign-in was not started in this browser, start again"
+        })),
+    };
+    let _ = resp.add_removal_cookie(&state_cookie(oidc, String::new()));
+    resp
+}
+
+async fn finish_login(app_state: &AppState, oidc: &OidcClient, params: &CallbackParams) -> HttpResponse {
+    let login = match app_state.db.take_oidc_login(&params.state).await {
+        Ok(Some(login)) => login,
+        Ok(None) => return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "unknown or expired sign-in, start again"
+        })),
+        Err(e) => {
+            println!("Failed to load sign-in state: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let Some(code) = params.code.as_deref() else {
+        return HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": format!("sign-in failed: {}", params.error.as_deref().unwrap_or("no code"))
+        }));
+    };
+    let claims = match oidc.exchange_code(code, &login.code_verifier, &login.nonce).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            println!("Failed to complete sign-in: {:?}", e);
+            return HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "sign-in could not be verified"
+            }));
+        }
+    };
+
+    let Some(user_id) = login.link_user_id else {
+        return sign_in(app_state, &oidc.issuer, &claims).await;
+    };
+    match app_state.db.link_identity(user_id, &oidc.issuer, &claims.sub, claims.email.as_deref()).await {
+        Ok(LinkOutcome::Linked) => HttpResponse::Ok().json(serde_json::json!({
+            "message": "identity linked",
+            "user_id": user_id
+        })),
+        Ok(LinkOutcome::LinkedElsewhere) => HttpResponse::Conflict().json(serde_json::json!({
+            "error": "this identity is already linked to another account"
+        })),
+        Err(e) => {
+            println!("Failed to link identity to {}: {:?}", user_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(preferred_username: Option<&str>, email: Option<&str>) -> IdentityClaims {
+        IdentityClaims {
+            sub: "1".to_owned(),
+            nonce: None,
+            email: email.map(str::to_owned),
+            preferred_username: preferred_username.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn username_candidates_keep_only_allowed_characters() {
+        let policy = AccountPolicy::from_env();
+        assert_eq!(username_candidate(&policy, &claims(Some("ada.lovelace"), None), 0), "ada.lovelace");
+        assert_eq!(username_candidate(&policy, &claims(Some("_Ada Lovelace!"), None), 0), "AdaLovelace");
+        assert_eq!(username_candidate(&policy, &claims(None, Some("ada+sso@example.com")), 0), "adasso");
+        assert_eq!(username_candidate(&policy, &claims(Some("..."), None), 0), "user");
+        let long = "a".repeat(100);
+        assert_eq!(username_candidate(&policy, &claims(Some(&long), None), 0).len(), policy.username_max_length - 5);
+    }
+
+    #[test]
+    fn later_username_candidates_are_suffixed_then_generated() {
+        let policy = AccountPolicy::from_env();
+        let claims = claims(Some("ada"), None);
+        let suffixed = username_candidate(&policy, &claims, 1);
+        assert!(suffixed.starts_with("ada-") && suffixed.len() == 8, "{}", suffixed);
+        let generated = username_candidate(&policy, &claims, USERNAME_ATTEMPTS - 1);
+        assert!(generated.starts_with("player-") && generated.len() == 15, "{}", generated);
+    }
+}
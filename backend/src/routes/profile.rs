@@ -0,0 +1,279 @@
+use actix_web::{HttpResponse, Responder, delete, get, patch, put, web};
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::state::AppState;
+use crate::auth::guard::RequireScope;
+use crate::auth::user::{AuthUser, Scope};
+use crate::avatar::{self, AvatarError};
+use crate::routes::user::{UserStats, load_user_stats};
+use crate::validation::{FieldErrors, check_text};
+use db::models::profiles::{Profile, RecentGame, UpdateProfile};
+
+/// How many finished games a profile lists.
+const RECENT_GAMES: i64 = 10;
+
+const DISPLAY_NAME_MAX_LENGTH: usize = 64;
+const BIO_MAX_LENGTH: usize = 500;
+
+/// ISO 3166-1 alpha-2 country codes.
+const COUNTRY_CODES: &[&str] = &[
+    "AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX", "AZ",
+    "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ", "BR", "BS",
+    "BT", "BV", "BW", "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK", "CL", "CM", "CN",
+    "CO", "CR", "CU", "CV", "CW", "CX", "CY", "CZ", "DE", "DJ", "DK", "DM", "DO", "DZ", "EC", "EE",
+    "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM", "FO", "FR", "GA", "GB", "GD", "GE", "GF",
+    "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS", "GT", "GU", "GW", "GY", "HK", "HM",
+    "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN", "IO", "IQ", "IR", "IS", "IT", "JE", "JM",
+    "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN", "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC",
+    "LI", "LK", "LR", "LS", "LT", "LU", "LV", "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK",
+    "ML", "MM", "MN", "MO", "MP", "MQ", "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA",
+    "NC", "NE", "NF", "NG", "NI", "NL", "NO", "NP", "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG",
+    "PH", "PK", "PL", "PM", "PN", "PR", "PS", "PT", "PW", "PY", "QA", "RE", "RO", "RS", "RU", "RW",
+    "SA", "SB", "SC", "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO", "SR", "SS",
+    "ST", "SV", "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO",
+    "TR", "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI",
+    "VN", "VU", "WF", "WS", "YE", "YT", "ZA", "ZM", "ZW",
+];
+
+/// Fields left out keep their value; an empty string clears one.
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub country: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ProfileResponse {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub country: Option<String>,
+    pub avatar_url: Option<String>,
+    pub avatar_thumb_url: Option<String>,
+    pub is_guest: bool,
+    pub is_bot: bool,
+    pub created_at: Option<DateTime<Utc>>,
+    pub stats: UserStats,
+    pub recent_games: Vec<RecentGame>,
+}
+
+async fn profile_response(app_state: &AppState, profile: anyhow::Result<Option<Profile>>) -> HttpResponse {
+    let profile = match profile {
+        Ok(Some(profile)) => profile,
+        Ok(None) => return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "user not found"
+        })),
+        Err(e) => {
+            println!("Failed to get profile: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let stats = load_user_stats(app_state, profile.id).await;
+    let recent_games = app_state.db.get_recent_games(profile.id, RECENT_GAMES).await;
+    match stats.and_then(|stats| recent_games.map(|games| (stats, games))) {
+        Ok((stats, recent_games)) => HttpResponse::Ok().json(ProfileResponse {
+            id: profile.id,
+            username: profile.username,
+            display_name: profile.display_name,
+            bio: profile.bio,
+            country: profile.country,
+            avatar_url: profile.avatar_id.map(|id| avatar::url(id, false)),
+            avatar_thumb_url: profile.avatar_id.map(|id| avatar::url(id, true)),
+            is_guest: profile.is_guest,
+            is_bot: profile.is_bot,
+            created_at: profile.created_at,
+            stats,
+            recent_games,
+        }),
+        Err(e) => {
+            println!("Failed to get profile of {}: {:?}", profile.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Public profile, with statistics and the last few games.
+#[get("/{user_id}")]
+async fn get_profile(app_state: web::Data<AppState>, path: web::Path<Uuid>) -> impl Responder {
+    let profile = app_state.db.get_profile(path.into_inner()).await;
+    profile_response(&app_state, profile).await
+}
+
+#[get("/by-name/{username}")]
+async fn get_profile_by_name(app_state: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+    let profile = app_state.db.get_profile_by_username(&path).await;
+    profile_response(&app_state, profile).await
+}
+
+#[patch("/me/profile", wrap = "RequireScope(Scope::Session)")]
+async fn update_profile(user: AuthUser, app_state: web::Data<AppState>, body: web::Json<UpdateProfileRequest>) -> impl Responder {
+    let current = match app_state.db.get_profile(user.id).await {
+        Ok(Some(profile)) => profile,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Failed to get profile of {}: {:?}", user.id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let mut errors = FieldErrors::default();
+    let display_name = match &body.display_name {
+        Some(name) => check_text(&mut errors, "display_name", name, DISPLAY_NAME_MAX_LENGTH, false),
+        None => current.display_name,
+    };
+    let bio = match &body.bio {
+        Some(bio) => check_text(&mut errors, "bio", bio, BIO_MAX_LENGTH, true),
+        None => current.bio,
+    };
+    let country = match &body.country {
+        Some(code) if code.is_empty() => None,
+        Some(code) => {
+            let code = code.to_ascii_uppercase();
+            if !COUNTRY_CODES.contains(&code.as_str()) {
+                errors.add("country", "unknown_country", "must be an ISO 3166-1 alpha-2 code");
+            }
+            Some(code)
+        }
+        None => current.country,
+    };
+    if let Err(resp) = errors.into_result() {
+        return *resp;
+    }
+
+    let update = UpdateProfile {
+        display_name: display_name.as_deref(),
+        bio: bio.as_deref(),
+        country: country.as_deref(),
+    };
+    match app_state.db.update_profile(user.id, update).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "display_name": display_name,
+            "bio": bio,
+            "country": country
+        })),
+        Err(e) => {
+            println!("Failed to update profile of {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Removes an avatar's files. Failures are only logged; the avatar is no
+/// longer referenced either way.
+pub async fn remove_avatar_files(app_state: &AppState, avatar_id: Uuid) {
+    for thumb in [false, true] {
+        let path = app_state.avatar_config.path(avatar_id, thumb);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            println!("Failed to remove avatar file {}: {:?}", path.display(), e);
+        }
+    }
+}
+
+/// Sets the avatar from the raw image in the request body.
+#[put("/me/avatar", wrap = "RequireScope(Scope::Session)")]
+async fn upload_avatar(user: AuthUser, app_state: web::Data<AppState>, mut payload: web::Payload) -> impl Responder {
+    let max_bytes = app_state.avatar_config.max_bytes;
+    let mut bytes = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let Ok(chunk) = chunk else {
+            return HttpResponse::BadRequest().finish();
+        };
+        if bytes.len() + chunk.len() > max_bytes {
+            return HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                "error": format!("avatar must be at most {} bytes", max_bytes)
+            }));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let state = app_state.clone();
+    let processed = match web::block(move || state.avatar_config.process(&bytes)).await {
+        Ok(Ok(processed)) => processed,
+        Ok(Err(AvatarError::UnsupportedFormat)) => return HttpResponse::UnsupportedMediaType().json(serde_json::json!({
+            "error": "avatar must be a PNG, JPEG or WebP image"
+        })),
+        Ok(Err(AvatarError::TooLarge)) => return HttpResponse::PayloadTooLarge().json(serde_json::json!({
+            "error": format!(
+                "avatar must be at most {0}x{0} pixels",
+                app_state.avatar_config.max_dimension
+            )
+        })),
+        Ok(Err(AvatarError::Invalid(reason))) => return HttpResponse::UnprocessableEntity().json(serde_json::json!({
+            "error": format!("could not read image: {}", reason)
+        })),
+        Err(e) => {
+            println!("Failed to process avatar of {}: {:?}", user.id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let avatar_id = Uuid::new_v4();
+    let config = &app_state.avatar_config;
+    let written = async {
+        tokio::fs::create_dir_all(&config.dir).await?;
+        tokio::fs::write(config.path(avatar_id, false), &processed.full).await?;
+        tokio::fs::write(config.path(avatar_id, true), &processed.thumb).await
+    };
+    if let Err(e) = written.await {
+        println!("Failed to store avatar of {}: {:?}", user.id, e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    match app_state.db.set_avatar(user.id, Some(avatar_id)).await {
+        Ok(previous) => {
+            if let Some(previous) = previous {
+                remove_avatar_files(&app_state, previous).await;
+            }
+            HttpResponse::Ok().json(serde_json::json!({
+                "avatar_url": avatar::url(avatar_id, false),
+                "avatar_thumb_url": avatar::url(avatar_id, true)
+            }))
+        }
+        Err(e) => {
+            println!("Failed to set avatar of {}: {:?}", user.id, e);
+            remove_avatar_files(&app_state, avatar_id).await;
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/me/avatar", wrap = "RequireScope(Scope::Session)")]
+async fn delete_avatar(user: AuthUser, app_state: web::Data<AppState>) -> impl Responder {
+    match app_state.db.set_avatar(user.id, None).await {
+        Ok(previous) => {
+            if let Some(previous) = previous {
+                remove_avatar_files(&app_state, previous).await;
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => {
+            println!("Failed to remove avatar of {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Serves stored avatars. A new upload gets a new file name, so they can be
+/// cached indefinitely.
+#[get("/avatars/{file}")]
+async fn get_avatar(app_state: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+    let Some((avatar_id, thumb)) = avatar::parse_file_name(&path) else {
+        return HttpResponse::NotFound().finish();
+    };
+    match tokio::fs::read(app_state.avatar_config.path(avatar_id, thumb)).await {
+        Ok(bytes) => HttpResponse::Ok()
+            .content_type("image/png")
+            .insert_header(("Cache-Control", "public, max-age=31536000, immutable"))
+            .body(bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Failed to read avatar {}: {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
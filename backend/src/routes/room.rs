@@ -1,35 +1,126 @@
-use std::{collections::HashMap, sync::Arc};
-use actix_web::{HttpRequest, HttpResponse, Responder, post, web, HttpMessage};
+use std::{collections::{HashMap, hash_map::Entry}, sync::{Arc, atomic::Ordering}, time::Duration};
+use actix_web::{HttpResponse, Responder, get, post, web};
 use uuid::Uuid;
-use tokio::sync::{mpsc};
-use serde::{Serialize};
+use chrono::{DateTime, Utc};
+use tokio::sync::{mpsc, oneshot};
+use serde::{Serialize, Deserialize};
 
-use crate::{state::AppState};
-use db::models::games::{CreateGameRequest, PlayerSymbol};
+use crate::{state::AppState, auth::{guard::RequireScope, user::{AuthUser, Role, Scope}}, chat::FilterResult, config::env_or, protocol::{ErrorCode, GameError}, rate_limit::RateLimiter};
+use db::models::games::{CreateGameRequest, Game, PlayerSymbol};
+use db::models::audit::RecordRejectedMove;
+
+pub struct RoomConfig {
+    pub move_rate_limit: usize,
+    pub move_rate_window: Duration,
+    /// How long games in progress get to finish once shutdown starts.
+    pub shutdown_grace: Duration,
+    /// Keep unfinished rooms checkpointed for the next start instead of
+    /// aborting them.
+    pub suspend_on_shutdown: bool,
+    /// How long a room waits for an opponent before it expires.
+    pub max_wait: Option<Duration>,
+    /// How long a game in progress may go without a move.
+    pub max_idle: Option<Duration>,
+    /// Hard cap on a room's age, whatever it is doing.
+    pub max_lifetime: Option<Duration>,
+}
+
+/// Reads a limit in seconds, where `0` disables it.
+fn limit_from_env(key: &str, default: u64) -> Option<Duration> {
+    match env_or(key, default) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+impl RoomConfig {
+    pub fn from_env() -> Self {
+        Self {
+            move_rate_limit: env_or("MOVE_RATE_LIMIT", 3),
+            move_rate_window: Duration::from_millis(env_or("MOVE_RATE_WINDOW_MS", 1000)),
+            shutdown_grace: Duration::from_secs(env_or("SHUTDOWN_GRACE_SECS", 60)),
+            suspend_on_shutdown: env_or("SHUTDOWN_SUSPEND_ROOMS", true),
+            max_wait: limit_from_env("ROOM_MAX_WAIT_SECS", 600),
+            max_idle: limit_from_env("ROOM_MAX_IDLE_SECS", 300),
+            max_lifetime: limit_from_env("ROOM_MAX_LIFETIME_SECS", 3600),
+        }
+    }
+}
 
 #[derive(Serialize)]
 struct CreateRoomResponse {
     room_id: String
 }
 
-#[post("/room")]
-async fn create_room(req: HttpRequest, app_state: web::Data<AppState>) -> impl Responder {
-    let _user_id = match req.extensions().get::<Uuid>() {
-        Some(&uid) => uid,
-        None => return HttpResponse::Unauthorized().finish(),
-    };
+/// Who gets the first move once both seats are filled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirstPlayer {
+    #[default]
+    Creator,
+    Joiner,
+    Random,
+    /// Swap relative to the last game these two players played against each other.
+    Alternate,
+}
+
+/// Which symbol the creator's seat plays.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolPreference {
+    #[default]
+    X,
+    O,
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomOptions {
+    pub first_player: FirstPlayer,
+    pub creator_symbol: SymbolPreference,
+    pub chat_enabled: bool,
+    /// Only bot accounts may take a seat.
+    pub bots_only: bool,
+    /// Once both seats are taken, anyone else joining watches the game.
+    /// Friends of the players see the room in their friends list.
+    pub spectators: bool,
+}
+
+impl Default for RoomOptions {
+    fn default() -> Self {
+        Self {
+            first_player: FirstPlayer::default(),
+            creator_symbol: SymbolPreference::default(),
+            chat_enabled: true,
+            bots_only: false,
+            spectators: false,
+        }
+    }
+}
+
+#[post("/room", wrap = "RequireScope(Scope::Play)")]
+async fn create_room(
+    user: AuthUser,
+    app_state: web::Data<AppState>,
+    body: Option<web::Json<RoomOptions>>,
+) -> impl Responder {
+    let user_id = user.id;
+    if app_state.shutting_down.load(Ordering::SeqCst) {
+        return HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "server is shutting down"
+        }));
+    }
+    let options = body.map(|b| b.into_inner()).unwrap_or_default();
+    if options.bots_only && !user.is_bot {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "only bots can create bot-only rooms",
+            "code": ErrorCode::BotsOnly
+        }));
+    }
     
     let room_id = Uuid::new_v4();
-    let (tx, rx) = mpsc::channel::<GameCommand>(32);
-    
-    let state_clone = app_state.clone().into_inner();
-    let task_room_id = room_id.clone();
-    
-    tokio::spawn(async move {
-        room_task(task_room_id, rx, state_clone).await;
-    });
-    
-    app_state.active_rooms.insert(room_id, tx);
+    spawn_room(GameState::new(room_id, user_id, options), app_state.clone().into_inner()).await;
     
     HttpResponse::Ok().json(CreateRoomResponse {
         room_id: room_id.to_string()
@@ -37,20 +128,71 @@ async fn create_room(req: HttpRequest, app_state: web::Data<AppState>) -> impl R
 }
 
 
-#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+#[get("/games/{game_id}/chat")]
+async fn get_game_chat(user: AuthUser, path: web::Path<Uuid>, app_state: web::Data<AppState>) -> impl Responder {
+    let user_id = user.id;
+    let game_id = path.into_inner();
+
+    match app_state.db.get_game_players(game_id).await {
+        Ok(Some((player_x, player_o))) if player_x == Some(user_id) || player_o == Some(user_id) => {}
+        Ok(Some(_)) if user.has_role(Role::Moderator) => {}
+        Ok(Some(_)) => return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "only players of this game can read its chat"
+        })),
+        Ok(None) => return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "game not found"
+        })),
+        Err(e) => {
+            println!("Failed to look up game {}: {:?}", game_id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match app_state.db.get_game_chat(game_id).await {
+        Ok(messages) => HttpResponse::Ok().json(messages),
+        Err(e) => {
+            println!("Failed to get game chat: {:?}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to retrieve chat"
+            }))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum GameStatus {
     WaitingForPlayers,
     Active,
     Finished
 }
 
+/// Room state owned by `room_task`. It is checkpointed to Postgres as JSON
+/// after every change so rooms can be restored after a restart.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct GameState {
     pub room_id: Uuid,
+    pub creator_id: Uuid,
+    pub options: RoomOptions,
     pub board: [Option<PlayerSymbol>; 9],
     pub current_turn: PlayerSymbol,
     pub status: GameStatus,
     pub player_x: Option<Uuid>,
-    pub player_o: Option<Uuid>
+    pub player_o: Option<Uuid>,
+    /// First player to join, held here until the second seat is filled.
+    pub waiting_player: Option<Uuid>,
+    pub first_player: Option<Uuid>,
+    pub move_count: i32,
+    pub usernames: HashMap<Uuid, String>,
+    /// Id of the `games` row, created once both seats are filled.
+    pub game_id: Option<Uuid>,
+    /// Cells played so far, in order.
+    pub moves: Vec<usize>,
+    pub created_at: DateTime<Utc>,
+    /// When both seats were filled.
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+    pub last_move_at: Option<DateTime<Utc>>,
 }
 
 pub enum GameCommand {
@@ -61,47 +203,294 @@ pub enum GameCommand {
     Move {
         user_id: Uuid,
         idx: usize,
+        request_id: Option<String>,
     },
     Leave {
         user_id: Uuid,
-    }
+    },
+    Chat {
+        user_id: Uuid,
+        text: String,
+        request_id: Option<String>,
+    },
+    /// The server is going down at `deadline`. Rooms without a game in
+    /// progress stop running straight away: with `suspend_on_shutdown` their
+    /// checkpoint is kept and they are restored on the next start, otherwise
+    /// they are closed for good. Games in progress play on until `Shutdown`.
+    ShutdownNotice {
+        deadline: DateTime<Utc>,
+    },
+    /// The grace period is over: close now, suspending or aborting the game.
+    Shutdown {
+        done: oneshot::Sender<()>,
+    },
+    /// `user_id` blocked `other_id`. Whichever of the two is only watching
+    /// is removed if the other is seated in the room.
+    Block {
+        user_id: Uuid,
+        other_id: Uuid,
+    },
+    /// `user_id` deleted their account. They are removed from the room,
+    /// forfeiting a game in progress.
+    AccountDeleted {
+        user_id: Uuid,
+    },
+    /// Replies with the room as it stands after the command.
+    Admin {
+        command: AdminCommand,
+        reply: oneshot::Sender<Result<RoomInfo, GameError>>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminCommand {
+    Inspect,
+    End(ForcedResult),
+    Kick(Uuid),
+    Broadcast(String),
+}
+
+/// Outcome an admin can impose on a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForcedResult {
+    X,
+    O,
+    Draw,
+    /// End without a result, like a shutdown abort.
+    Abort,
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub room_id: Uuid,
+    /// The instance running the room.
+    pub instance_id: Uuid,
+    pub age_secs: i64,
+    pub clients: Vec<Uuid>,
+    pub state: GameState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Checkpointed; the room comes back when the server restarts.
+    Suspended,
+    /// Ended by the server without a result.
+    Aborted,
+    /// Removed by an administrator.
+    Kicked,
+    /// A player in the room and this spectator blocked one another.
+    Blocked,
+    /// Another instance runs the room now; reconnect to continue.
+    Relocated,
+    /// The user deleted their account.
+    AccountDeleted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpiryReason {
+    /// Nobody took the second seat in time.
+    NoOpponent,
+    /// The game went too long without a move.
+    Idle,
+    /// The room reached its maximum lifetime.
+    Lifetime,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
 pub enum GameEvent {
     GameJoined,
     OpponentJoined(Uuid),
     BoardUpdate([Option<PlayerSymbol>; 9]),
     GameOver { winner: Option<Uuid> },
-    Error(String),
+    StateSnapshot(StateSnapshot),
+    ChatMessage { user_id: Uuid, text: String },
+    ServerShuttingDown { deadline: DateTime<Utc> },
+    RoomClosed { reason: CloseReason },
+    RoomExpired { reason: ExpiryReason },
+    SystemMessage { text: String },
+    /// The command with this request id was accepted.
+    Ack {
+        #[serde(skip)]
+        request_id: Option<String>,
+    },
+    Error {
+        #[serde(skip)]
+        request_id: Option<String>,
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SeatInfo {
+    pub user_id: Uuid,
+    pub username: Option<String>,
+}
+
+/// Everything a client needs to render the room, from the receiver's
+/// point of view.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub board: [Option<PlayerSymbol>; 9],
+    pub current_turn: PlayerSymbol,
+    pub status: GameStatus,
+    pub player_x: Option<SeatInfo>,
+    pub player_o: Option<SeatInfo>,
+    /// Whoever sat down first, until the second seat is filled and symbols
+    /// are assigned.
+    pub waiting_player: Option<SeatInfo>,
+    pub your_symbol: Option<PlayerSymbol>,
+    pub move_number: i32,
+    pub winning_line: Option<[usize; 3]>,
+}
+
+impl GameEvent {
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            GameEvent::Ack { request_id } | GameEvent::Error { request_id, .. } => request_id.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Reattaches a request id, which is carried outside the payload.
+    pub fn with_request_id(self, request_id: Option<String>) -> Self {
+        match self {
+            GameEvent::Ack { .. } => GameEvent::Ack { request_id },
+            GameEvent::Error { code, message, .. } => GameEvent::Error { request_id, code, message },
+            event => event,
+        }
+    }
 }
 
 impl GameState {
-    pub fn new(room_id: Uuid) -> Self {
+    pub fn new(room_id: Uuid, creator_id: Uuid, options: RoomOptions) -> Self {
         Self {
             room_id,
+            creator_id,
+            options,
             board: [None; 9],
             current_turn: PlayerSymbol::X,
             status: GameStatus::WaitingForPlayers,
             player_x: None,
-            player_o: None
+            player_o: None,
+            waiting_player: None,
+            first_player: None,
+            move_count: 0,
+            usernames: HashMap::new(),
+            game_id: None,
+            moves: Vec::new(),
+            created_at: Utc::now(),
+            started_at: None,
+            last_move_at: None,
         }
     }
     
-    pub fn add_player(&mut self, player_id: Uuid) -> Result<PlayerSymbol, String> {
+    /// Rebuilds the state of a game stored in Postgres, such as a
+    /// correspondence game, so it can be validated like a live one.
+    pub fn from_record(record: &Game) -> Self {
+        let options = record.options.clone()
+            .and_then(|o| serde_json::from_value(o).ok())
+            .unwrap_or_default();
+        let mut game = Self::new(record.room_id, record.creator_id.unwrap_or_default(), options);
+        for (cell, symbol) in game.board.iter_mut().zip(&record.board_state) {
+            *cell = *symbol;
+        }
+        game.player_x = record.player_x_id;
+        game.player_o = record.player_o_id;
+        game.first_player = record.first_player_id;
+        game.move_count = record.moves_count;
+        game.game_id = Some(record.id);
+        game.started_at = record.started_at;
+        game.status = match record.status.as_str() {
+            "waiting" => GameStatus::WaitingForPlayers,
+            "active" => GameStatus::Active,
+            _ => GameStatus::Finished,
+        };
+        if game.status == GameStatus::WaitingForPlayers {
+            game.waiting_player = record.creator_id;
+        }
+        let first_symbol = record.first_player_id
+            .and_then(|p| game.symbol_of(p))
+            .unwrap_or(PlayerSymbol::X);
+        game.current_turn = if game.move_count % 2 == 0 { first_symbol } else { opposite(first_symbol) };
+        game
+    }
+
+    /// Seats a player. Returns `true` once both seats are taken and
+    /// `assign_seats` should be called.
+    pub fn add_player(&mut self, player_id: Uuid) -> Result<bool, GameError> {
         if self.status != GameStatus::WaitingForPlayers {
-            return Err("Game is either finished or full".to_string());
-        }
-        if self.player_x.is_none() {
-            self.player_x = Some(player_id);
-            Ok(PlayerSymbol::X)
-        } else if self.player_o.is_none() {
-            self.player_o = Some(player_id);
-            self.status = GameStatus::Active;
-            Ok(PlayerSymbol::O)
+            return Err(GameError::new(ErrorCode::RoomFull, "Game is either finished or full"));
+        }
+        match self.waiting_player {
+            None => {
+                self.waiting_player = Some(player_id);
+                Ok(false)
+            }
+            Some(first) if first == player_id => Err(GameError::new(ErrorCode::AlreadySeated, "Already seated in this room")),
+            Some(_) => Ok(true),
+        }
+    }
+
+    /// Returns `(creator_seat, joiner_seat)` for the two seated players. The
+    /// creator seat is the room creator if they sat down, otherwise whoever
+    /// joined first.
+    pub fn seat_order(&self, second: Uuid) -> Option<(Uuid, Uuid)> {
+        let first = self.waiting_player?;
+        if second == self.creator_id {
+            Some((second, first))
+        } else {
+            Some((first, second))
+        }
+    }
+
+    pub fn assign_seats(&mut self, creator_seat: Uuid, joiner_seat: Uuid, creator_moves_first: bool) {
+        let creator_symbol = match self.options.creator_symbol {
+            SymbolPreference::X => PlayerSymbol::X,
+            SymbolPreference::O => PlayerSymbol::O,
+            SymbolPreference::Random => if rand::random::<bool>() { PlayerSymbol::X } else { PlayerSymbol::O },
+        };
+        let (first_player, first_symbol) = if creator_moves_first {
+            (creator_seat, creator_symbol)
         } else {
-            Err("Room is full".to_string())
+            (joiner_seat, opposite(creator_symbol))
+        };
+        match creator_symbol {
+            PlayerSymbol::X => {
+                self.player_x = Some(creator_seat);
+                self.player_o = Some(joiner_seat);
+            }
+            PlayerSymbol::O => {
+                self.player_x = Some(joiner_seat);
+                self.player_o = Some(creator_seat);
+            }
         }
+        self.waiting_player = None;
+        self.first_player = Some(first_player);
+        self.current_turn = first_symbol;
+        self.status = GameStatus::Active;
+        self.started_at = Some(Utc::now());
+    }
+
+    /// The next limit from `config` this room will run into, if any.
+    pub fn expiry(&self, config: &RoomConfig) -> Option<(DateTime<Utc>, ExpiryReason)> {
+        let after = |since: DateTime<Utc>, limit: Option<Duration>| {
+            limit.and_then(|l| chrono::Duration::from_std(l).ok()).map(|l| since + l)
+        };
+        let activity = match self.status {
+            GameStatus::WaitingForPlayers => after(self.created_at, config.max_wait)
+                .map(|at| (at, ExpiryReason::NoOpponent)),
+            GameStatus::Active => after(self.last_move_at.or(self.started_at).unwrap_or(self.created_at), config.max_idle)
+                .map(|at| (at, ExpiryReason::Idle)),
+            GameStatus::Finished => None,
+        };
+        let lifetime = after(self.created_at, config.max_lifetime).map(|at| (at, ExpiryReason::Lifetime));
+        [activity, lifetime].into_iter().flatten().min_by_key(|(at, _)| *at)
     }
     
     pub fn is_turn(&self, player_id: Uuid) -> bool {
@@ -111,40 +500,95 @@ impl GameState {
         }
     }
 
-    pub fn make_move(&mut self, idx: usize) -> Result<bool, String> {
+    /// Checks everything the server is authoritative for before a move is
+    /// applied: the game is running, the sender holds a seat, it is their
+    /// turn and the cell is playable.
+    pub fn validate_move(&self, player_id: Uuid, idx: usize) -> Result<(), GameError> {
+        if self.status != GameStatus::Active {
+            return Err(GameError::new(ErrorCode::GameNotActive, "waiting for opponent"));
+        }
+        if self.symbol_of(player_id).is_none() {
+            return Err(GameError::new(ErrorCode::NotSeated, "you are not seated in this game"));
+        }
+        if !self.is_turn(player_id) {
+            return Err(GameError::new(ErrorCode::NotYourTurn, "it is not your turn"));
+        }
+        if idx > 8 {
+            return Err(GameError::new(ErrorCode::InvalidCell, "Index out of bounds"));
+        }
+        if self.board[idx].is_some() {
+            return Err(GameError::new(ErrorCode::CellOccupied, "Cell already taken"));
+        }
+        Ok(())
+    }
+
+    pub fn make_move(&mut self, idx: usize) -> Result<bool, GameError> {
         if idx > 8 {
-            return Err("Index out of bounds".to_string());
+            return Err(GameError::new(ErrorCode::InvalidCell, "Index out of bounds"));
         }
         if self.board[idx].is_some() {
-            return Err("Cell already taken".to_string());
+            return Err(GameError::new(ErrorCode::CellOccupied, "Cell already taken"));
         }
         self.board[idx] = Some(self.current_turn);
         Ok(true)
     }
     
     pub fn switch_turn(&mut self) {
-        self.current_turn = match self.current_turn {
-            PlayerSymbol::X => PlayerSymbol::O,
-            PlayerSymbol::O => PlayerSymbol::X,
-        };
+        self.current_turn = opposite(self.current_turn);
     }
 
     pub fn check_winner(&self) -> Option<PlayerSymbol> {
+        self.winning_line().and_then(|[x, _, _]| self.board[x])
+    }
+
+    pub fn winning_line(&self) -> Option<[usize; 3]> {
         let b = self.board;
         let wins = [
-            (0, 1, 2), (3, 4, 5), (6, 7, 8),
-            (0, 3, 6), (1, 4, 7), (2, 5, 8),
-            (0, 4, 8), (2, 4, 6)
+            [0, 1, 2], [3, 4, 5], [6, 7, 8],
+            [0, 3, 6], [1, 4, 7], [2, 5, 8],
+            [0, 4, 8], [2, 4, 6]
         ];
 
-        for (x, y, z) in wins.iter() {
-            if let (Some(p1), Some(p2), Some(p3)) = (b[*x], b[*y], b[*z]) {
-                if p1 == p2 && p2 == p3 {
-                    return Some(p1);
-                }
-            }
+        wins.into_iter().find(|&[x, y, z]| {
+            matches!((b[x], b[y], b[z]), (Some(p1), Some(p2), Some(p3)) if p1 == p2 && p2 == p3)
+        })
+    }
+
+    pub fn symbol_of(&self, player_id: Uuid) -> Option<PlayerSymbol> {
+        if self.player_x == Some(player_id) {
+            Some(PlayerSymbol::X)
+        } else if self.player_o == Some(player_id) {
+            Some(PlayerSymbol::O)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `user_id` created the room or has a seat in it, as opposed to
+    /// watching it.
+    pub fn is_seated(&self, user_id: Uuid) -> bool {
+        self.creator_id == user_id || self.waiting_player == Some(user_id) || self.symbol_of(user_id).is_some()
+    }
+
+    fn seat_info(&self, player_id: Option<Uuid>) -> Option<SeatInfo> {
+        player_id.map(|user_id| SeatInfo {
+            user_id,
+            username: self.usernames.get(&user_id).cloned(),
+        })
+    }
+
+    pub fn snapshot_for(&self, receiver: Uuid) -> StateSnapshot {
+        StateSnapshot {
+            board: self.board,
+            current_turn: self.current_turn,
+            status: self.status,
+            player_x: self.seat_info(self.player_x),
+            player_o: self.seat_info(self.player_o),
+            waiting_player: self.seat_info(self.waiting_player),
+            your_symbol: self.symbol_of(receiver),
+            move_number: self.move_count,
+            winning_line: self.winning_line(),
         }
-        None
     }
 
     pub fn is_draw(&self) -> bool {
@@ -152,159 +596,630 @@ impl GameState {
     }
 }
 
-pub async fn room_task(room_id: Uuid, mut rx: mpsc::Receiver<GameCommand>, state: Arc<AppState>) {
-    let mut game = GameState::new(room_id);
+fn opposite(symbol: PlayerSymbol) -> PlayerSymbol {
+    match symbol {
+        PlayerSymbol::X => PlayerSymbol::O,
+        PlayerSymbol::O => PlayerSymbol::X,
+    }
+}
+
+pub async fn creator_moves_first(state: &AppState, policy: FirstPlayer, creator_seat: Uuid, joiner_seat: Uuid) -> bool {
+    match policy {
+        FirstPlayer::Creator => true,
+        FirstPlayer::Joiner => false,
+        FirstPlayer::Random => rand::random::<bool>(),
+        FirstPlayer::Alternate => match state.db.get_last_first_player(creator_seat, joiner_seat).await {
+            Ok(Some(last)) => last != creator_seat,
+            Ok(None) => true,
+            Err(e) => {
+                println!("Failed to look up previous first player: {:?}", e);
+                true
+            }
+        },
+    }
+}
+
+/// Records this instance as the room's owner, then starts the actor for
+/// `game` and registers it in `active_rooms`.
+pub async fn spawn_room(game: GameState, state: Arc<AppState>) {
+    if !checkpoint(&state, &game).await {
+        return;
+    }
+    let room_id = game.room_id;
+    let (tx, rx) = mpsc::channel::<GameCommand>(32);
+    state.active_rooms.insert(room_id, tx);
+    tokio::spawn(room_task(game, rx, state));
+}
+
+/// Re-spawns every checkpointed room whose owner is gone. At startup
+/// (`include_own`) this also covers rooms this instance owned before a
+/// restart, and active games that cannot be resumed are abandoned.
+pub async fn restore_rooms(state: Arc<AppState>, include_own: bool) {
+    let cluster = &state.cluster;
+    let checkpoints = match state.db.claim_orphaned_rooms(cluster.instance_id, cluster.config.stale_after, include_own).await {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Failed to load room checkpoints: {:?}", e);
+            return;
+        }
+    };
+    for checkpoint in checkpoints {
+        match serde_json::from_value::<GameState>(checkpoint.state) {
+            Ok(game) => {
+                println!("Restoring room {}", checkpoint.room_id);
+                if let Some(game_id) = game.game_id
+                    && let Err(e) = state.db.resume_game(game_id).await {
+                    println!("Failed to resume game {}: {:?}", game_id, e);
+                }
+                spawn_room(game, state.clone()).await;
+            }
+            Err(e) => {
+                println!("Dropping unreadable checkpoint for room {}: {:?}", checkpoint.room_id, e);
+                let _ = state.db.delete_room_checkpoint(checkpoint.room_id, cluster.instance_id).await;
+            }
+        }
+    }
+    if !include_own {
+        return;
+    }
+    match state.db.abandon_orphaned_live_games().await {
+        Ok(0) => {}
+        Ok(n) => println!("Marked {} unrecoverable games as abandoned", n),
+        Err(e) => println!("Failed to abandon orphaned games: {:?}", e),
+    }
+}
+
+/// Saves the room's state. Returns `false` if another instance has taken
+/// the room over, in which case this instance must stop running it. A
+/// failed write is logged and does not count as losing the room.
+async fn checkpoint(state: &AppState, game: &GameState) -> bool {
+    let saved = match serde_json::to_value(game) {
+        Ok(value) => state.db.save_room_checkpoint(game.room_id, state.cluster.instance_id, &value).await,
+        Err(e) => Err(e.into()),
+    };
+    match saved {
+        Ok(true) => true,
+        Ok(false) => {
+            println!("Room {} has been taken over by another instance", game.room_id);
+            false
+        }
+        Err(e) => {
+            println!("Failed to checkpoint room {}: {:?}", game.room_id, e);
+            true
+        }
+    }
+}
+
+pub async fn room_task(
+    mut game: GameState,
+    mut rx: mpsc::Receiver<GameCommand>,
+    state: Arc<AppState>,
+) {
+    let room_id = game.room_id;
     let mut clients: HashMap<Uuid, mpsc::Sender<GameEvent>> = HashMap::new();
-    let mut current_game_id: Option<Uuid> = None;
-    let mut move_count = 0;
+    let mut chat_limiter = RateLimiter::default();
+    let mut move_limiter = RateLimiter::default();
+    let mut closing: Option<CloseReason> = None;
+    let mut shutdown_done: Option<oneshot::Sender<()>> = None;
+    let mut expired: Option<ExpiryReason> = None;
+    // Cleared once another instance has taken the room over.
+    let mut owned = true;
+    let close_reason = if state.room_config.suspend_on_shutdown {
+        CloseReason::Suspended
+    } else {
+        CloseReason::Aborted
+    };
 
+    // Rows left by a previous owner of a restored room.
+    if let Err(e) = state.db.clear_room_presence(room_id).await {
+        println!("Failed to clear presence in room {}: {:?}", room_id, e);
+    }
     println!("Room {} spawned", room_id);
 
-    while let Some(cmd) = rx.recv().await {
+    loop {
+        let expiry = game.expiry(&state.room_config);
+        let cmd = tokio::select! {
+            cmd = rx.recv() => match cmd {
+                Some(cmd) => cmd,
+                None => break,
+            },
+            _ = sleep_until(expiry.map(|(at, _)| at)) => {
+                expired = expiry.map(|(_, reason)| reason);
+                break;
+            }
+        };
         match cmd {
+            GameCommand::Join { user_id, player_sender } if game.symbol_of(user_id).is_some() || game.waiting_player == Some(user_id) => {
+                println!("user {} reconnected", user_id);
+                clients.insert(user_id, player_sender.clone());
+                set_presence(&state, &game, user_id).await;
+                let _ = player_sender.send(GameEvent::GameJoined).await;
+                let _ = player_sender.send(GameEvent::BoardUpdate(game.board)).await;
+                let _ = player_sender.send(GameEvent::StateSnapshot(game.snapshot_for(user_id))).await;
+            }
             GameCommand::Join { user_id, player_sender } => {
                 println!("user {} trying to join", user_id);
+                if game.options.bots_only && !state.db.is_bot(user_id).await.unwrap_or(false) {
+                    let error = GameError::new(ErrorCode::BotsOnly, "only bots can join this room");
+                    let _ = player_sender.send(error.into_event(None)).await;
+                    continue;
+                }
+                if blocked_from_room(&state, &game, user_id).await {
+                    let error = GameError::new(ErrorCode::Blocked, "you cannot join this room");
+                    let _ = player_sender.send(error.into_event(None)).await;
+                    continue;
+                }
+                if let Entry::Vacant(entry) = game.usernames.entry(user_id) {
+                    match state.db.get_username(user_id).await {
+                        Ok(username) => {
+                            entry.insert(username);
+                        }
+                        Err(e) => println!("Failed to look up username for {}: {:?}", user_id, e),
+                    }
+                }
                 match game.add_player(user_id) {
-                    Ok(player_symbol) => {
+                    Ok(seats_filled) => {
+                        let waiting_player = game.waiting_player;
+                        if seats_filled && let Some((creator_seat, joiner_seat)) = game.seat_order(user_id) {
+                            let creator_first = creator_moves_first(&state, game.options.first_player, creator_seat, joiner_seat).await;
+                            game.assign_seats(creator_seat, joiner_seat, creator_first);
+                        }
+
                         clients.insert(user_id, player_sender.clone());
+                        set_presence(&state, &game, user_id).await;
                         let _ = player_sender.send(GameEvent::GameJoined).await;
                         broadcast_game_state(&mut clients, &game).await;
 
-                        if player_symbol == PlayerSymbol::O && current_game_id.is_none() {
+                        if seats_filled && game.game_id.is_none() {
                             match state.db.create_game(CreateGameRequest {
                                 room_id,
                                 player_x_id: game.player_x,
                                 player_o_id: game.player_o,
+                                first_player_id: game.first_player,
                             }).await {
                                 Ok(game_record) => {
-                                    current_game_id = Some(game_record.id);
+                                    game.game_id = Some(game_record.id);
                                     println!("Created game record: {}", game_record.id);
                                 }
                                 Err(e) => println!("Failed to create game record: {:?}", e),
                             }
                         }
 
-                        if player_symbol == PlayerSymbol::O {
-                            if let Some(first_player_id) = game.player_x {
-                                if let Some(tx) = clients.get(&first_player_id) {
-                                    let _ = tx.send(GameEvent::OpponentJoined(user_id)).await;
-                                }
-                            }
+                        if seats_filled
+                            && let Some(first_player_id) = waiting_player
+                            && let Some(tx) = clients.get(&first_player_id) {
+                            let _ = tx.send(GameEvent::OpponentJoined(user_id)).await;
                         }
 
-                        println!("Player {} joined as {:?}, game status: {:?}", user_id, player_symbol, game.status);
+                        owned &= checkpoint(&state, &game).await;
+                        println!("Player {} joined, game status: {:?}, first player: {:?}", user_id, game.status, game.first_player);
+                    }
+                    Err(e) if e.code == ErrorCode::RoomFull && game.options.spectators && game.status == GameStatus::Active => {
+                        println!("user {} is watching room {}", user_id, room_id);
+                        clients.insert(user_id, player_sender.clone());
+                        set_presence(&state, &game, user_id).await;
+                        let _ = player_sender.send(GameEvent::GameJoined).await;
+                        let _ = player_sender.send(GameEvent::BoardUpdate(game.board)).await;
+                        let _ = player_sender.send(GameEvent::StateSnapshot(game.snapshot_for(user_id))).await;
                     }
                     Err(e) => {
-                        let _ = player_sender.send(GameEvent::Error(e)).await;
+                        let _ = player_sender.send(e.into_event(None)).await;
                     }
                 }
             }
-            GameCommand::Move { user_id, idx } => {
+            GameCommand::Move { user_id, idx, request_id } => {
                 println!("move attempt: user {}, position {}", user_id, idx);
-                if game.status != GameStatus::Active {
-                    println!("game not active");
-                    if let Some(tx) = clients.get(&user_id) {
-                        let _ = tx.send(GameEvent::Error("waiting for opponent".to_string())).await;
-                    }
+                let config = &state.room_config;
+                let checked = if move_limiter.check(user_id, config.move_rate_limit, config.move_rate_window) {
+                    game.validate_move(user_id, idx)
+                } else {
+                    Err(GameError::new(ErrorCode::RateLimited, "you are moving too quickly"))
+                };
+                if let Err(e) = checked {
+                    reject_move(&state, &clients, &game, user_id, idx, e, request_id).await;
                     continue;
                 }
                 match game.make_move(idx) {
                     Ok(_) => {
-                        move_count += 1;
+                        game.move_count += 1;
+                        game.moves.push(idx);
+                        game.last_move_at = Some(Utc::now());
 
+                        // The move is only acknowledged once the checkpoint
+                        // shows this instance still owns the room.
                         if let Some(winner_symbol) = game.check_winner() {
-                            game.status = GameStatus::Finished;
                             let winner_id = match winner_symbol {
                                 PlayerSymbol::X => game.player_x,
                                 PlayerSymbol::O => game.player_o
                             };
-                            broadcast_game_state(&mut clients, &game).await;
-                            let event = GameEvent::GameOver { winner: winner_id };
-                            for client in clients.values() {
-                                let _ = client.send(event.clone()).await;
-                            }
-
-                            if let Some(game_id) = current_game_id {
-                                let board_state: Vec<Option<PlayerSymbol>> = game.board.iter().map(|&cell| cell).collect();
-                                let _ = state.db.finish_game(
-                                    game_id,
-                                    winner_id,
-                                    &board_state,
-                                    move_count,
-                                ).await;
-                                println!("Game {} finished, winner: {:?}", game_id, winner_id);
-                            }
+                            owned &= end_game(&state, &mut clients, &mut game, winner_id).await;
                         } else if game.is_draw() {
-                            game.status = GameStatus::Finished;
-                            broadcast_game_state(&mut clients, &game).await;
-                            let event = GameEvent::GameOver { winner: None };
-                            for client in clients.values() {
-                                let _ = client.send(event.clone()).await;
-                            }
-
-                            if let Some(game_id) = current_game_id {
-                                let board_state: Vec<Option<PlayerSymbol>> = game.board.iter().map(|&cell| cell).collect();
-                                let _ = state.db.finish_game(
-                                    game_id,
-                                    None,
-                                    &board_state,
-                                    move_count,
-                                ).await;
-                                println!("Game {} ended in draw", game_id);
-                            }
+                            owned &= end_game(&state, &mut clients, &mut game, None).await;
                         } else {
                             game.switch_turn();
-                            broadcast_game_state(&mut clients, &game).await;
+                            owned &= checkpoint(&state, &game).await;
+                            if owned {
+                                broadcast_game_state(&mut clients, &game).await;
+                            }
+                        }
+                        if owned {
+                            send_to(&clients, user_id, GameEvent::Ack { request_id }).await;
                         }
                     }
                     Err(e) => {
-                        if let Some(tx) = clients.get(&user_id) {
-                            let _ = tx.send(GameEvent::Error(e)).await;
-                        }
+                        reject_move(&state, &clients, &game, user_id, idx, e, request_id).await;
                     }
                 }
             }
             GameCommand::Leave { user_id } => {
+                // A reconnect replaces the sender, so a leave whose channel is
+                // still open comes from a connection that was superseded.
+                if clients.get(&user_id).is_some_and(|tx| !tx.is_closed()) {
+                    continue;
+                }
                 clients.remove(&user_id);
-                if game.status == GameStatus::Active {
-                    game.status = GameStatus::Finished;
+                if let Err(e) = state.db.clear_presence(user_id, room_id).await {
+                    println!("Failed to clear presence of {}: {:?}", user_id, e);
+                }
+                if game.waiting_player == Some(user_id) {
+                    game.waiting_player = None;
+                }
+                if game.status == GameStatus::Active && game.symbol_of(user_id).is_some() {
                     let winner_id = if game.player_x == Some(user_id) {
                         game.player_o
                     } else {
                         game.player_x
                     };
-                    if let Some(wid) = winner_id {
-                        let event = GameEvent::GameOver { winner: Some(wid) };
+                    println!("Player {} abandoned room {}", user_id, room_id);
+                    owned &= end_game(&state, &mut clients, &mut game, winner_id).await;
+                }
+            }
+            GameCommand::Chat { user_id, text, request_id } => {
+                if !clients.contains_key(&user_id) {
+                    continue;
+                }
+                if game.symbol_of(user_id).is_none() && game.waiting_player != Some(user_id) {
+                    let error = GameError::new(ErrorCode::NotSeated, "spectators cannot chat");
+                    send_to(&clients, user_id, error.into_event(request_id)).await;
+                    continue;
+                }
+                let text = match validate_chat(&state, &game, &mut chat_limiter, user_id, &text) {
+                    Ok(text) => {
+                        send_to(&clients, user_id, GameEvent::Ack { request_id }).await;
+                        text
+                    }
+                    Err(e) => {
+                        send_to(&clients, user_id, e.into_event(request_id)).await;
+                        continue;
+                    }
+                };
+
+                // Messages are not delivered between users who blocked each
+                // other, including to spectators.
+                let others: Vec<Uuid> = clients.keys().copied().filter(|&id| id != user_id).collect();
+                let blocked = match state.db.get_blocked_among(user_id, &others).await {
+                    Ok(blocked) => blocked,
+                    Err(e) => {
+                        println!("Failed to check blocks for {}: {:?}", user_id, e);
+                        others
+                    }
+                };
+                let event = GameEvent::ChatMessage { user_id, text: text.clone() };
+                for (id, client) in clients.iter() {
+                    if !blocked.contains(id) {
+                        let _ = client.send(event.clone()).await;
+                    }
+                }
+
+                if state.chat_config.persist
+                    && let Err(e) = state.db.save_chat_message(room_id, game.game_id, user_id, &text).await {
+                    println!("Failed to save chat message: {:?}", e);
+                }
+            }
+            GameCommand::ShutdownNotice { deadline } => {
+                let event = GameEvent::ServerShuttingDown { deadline };
+                for client in clients.values() {
+                    let _ = client.send(event.clone()).await;
+                }
+                if game.status != GameStatus::Active {
+                    closing = Some(close_reason);
+                }
+            }
+            GameCommand::Shutdown { done } => {
+                closing = Some(close_reason);
+                shutdown_done = Some(done);
+            }
+            GameCommand::Block { user_id, other_id } => {
+                for (watcher, other) in [(user_id, other_id), (other_id, user_id)] {
+                    if game.is_seated(watcher) || !game.is_seated(other) {
+                        continue;
+                    }
+                    let Some(tx) = clients.remove(&watcher) else { continue };
+                    println!("Removed {} from room {} after a block", watcher, room_id);
+                    let _ = tx.send(GameEvent::RoomClosed { reason: CloseReason::Blocked }).await;
+                    if let Err(e) = state.db.clear_presence(watcher, room_id).await {
+                        println!("Failed to clear presence of {}: {:?}", watcher, e);
+                    }
+                }
+            }
+            GameCommand::AccountDeleted { user_id } => {
+                println!("Removed deleted account {} from room {}", user_id, room_id);
+                owned &= remove_user(&state, &mut clients, &mut game, user_id, CloseReason::AccountDeleted).await;
+            }
+            GameCommand::Admin { command, reply } => {
+                let result = match command {
+                    AdminCommand::Inspect => Ok(()),
+                    AdminCommand::Broadcast(text) => {
+                        let event = GameEvent::SystemMessage { text };
                         for client in clients.values() {
                             let _ = client.send(event.clone()).await;
                         }
+                        Ok(())
                     }
-
-                    if let Some(game_id) = current_game_id {
-                        let board_state: Vec<Option<PlayerSymbol>> = game.board.iter().map(|&cell| cell).collect();
-                        let _ = state.db.finish_game(
-                            game_id,
-                            winner_id,
-                            &board_state,
-                            move_count,
-                        ).await;
-                        println!("Game {} abandoned, winner: {:?}", game_id, winner_id);
+                    AdminCommand::Kick(user_id) => {
+                        println!("Admin kicked {} from room {}", user_id, room_id);
+                        owned &= remove_user(&state, &mut clients, &mut game, user_id, CloseReason::Kicked).await;
+                        Ok(())
                     }
-                }
+                    AdminCommand::End(ForcedResult::Abort) => {
+                        closing = Some(CloseReason::Aborted);
+                        Ok(())
+                    }
+                    AdminCommand::End(_) if game.status != GameStatus::Active => {
+                        Err(GameError::new(ErrorCode::GameNotActive, "only a game in progress can be given a result"))
+                    }
+                    AdminCommand::End(result) => {
+                        let winner_id = match result {
+                            ForcedResult::X => game.player_x,
+                            ForcedResult::O => game.player_o,
+                            _ => None,
+                        };
+                        println!("Admin ended room {} with {:?}", room_id, result);
+                        owned &= end_game(&state, &mut clients, &mut game, winner_id).await;
+                        Ok(())
+                    }
+                };
+                let _ = reply.send(result.map(|_| RoomInfo {
+                    room_id,
+                    instance_id: state.cluster.instance_id,
+                    age_secs: (Utc::now() - game.created_at).num_seconds(),
+                    clients: clients.keys().copied().collect(),
+                    state: game.clone(),
+                }));
             }
         }
-        if game.status == GameStatus::Finished {
+        if game.status == GameStatus::Finished || closing.is_some() || !owned {
             break;
         }
     }
     state.active_rooms.remove(&room_id);
+    if !owned {
+        // The new owner has the room and its players' presence; leave both
+        // alone and send our clients to it.
+        let event = GameEvent::RoomClosed { reason: CloseReason::Relocated };
+        for client in clients.values() {
+            let _ = client.send(event.clone()).await;
+        }
+        if let Some(done) = shutdown_done {
+            let _ = done.send(());
+        }
+        println!("room {} handed over", room_id);
+        return;
+    }
+    if let Err(e) = state.db.clear_room_presence(room_id).await {
+        println!("Failed to clear presence in room {}: {:?}", room_id, e);
+    }
+
+    let in_progress = game.status == GameStatus::Active;
+    let result = if closing == Some(CloseReason::Suspended) {
+        match game.game_id {
+            Some(game_id) if in_progress && checkpoint(&state, &game).await => state.db.suspend_game(game_id).await,
+            _ => Ok(()),
+        }
+    } else {
+        // Only the owner's checkpoint is deleted, so this also tells us
+        // whether the room is still ours to close.
+        match state.db.delete_room_checkpoint(room_id, state.cluster.instance_id).await {
+            Ok(true) => {
+                let board_state: Vec<Option<PlayerSymbol>> = game.board.to_vec();
+                match (closing, game.game_id) {
+                    (Some(CloseReason::Aborted), Some(game_id)) if in_progress => {
+                        state.db.abort_game(game_id, &board_state, game.move_count).await
+                    }
+                    (None, Some(game_id)) if in_progress && expired.is_some() => {
+                        state.db.abandon_game(game_id, &board_state, game.move_count).await
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        }
+    };
+    if let Err(e) = result {
+        println!("Failed to clean up room {}: {:?}", room_id, e);
+    }
+    if let Some(reason) = closing {
+        let event = GameEvent::RoomClosed { reason };
+        for client in clients.values() {
+            let _ = client.send(event.clone()).await;
+        }
+    }
+    if let Some(reason) = expired {
+        println!("room {} expired: {:?}", room_id, reason);
+        let event = GameEvent::RoomExpired { reason };
+        for client in clients.values() {
+            let _ = client.send(event.clone()).await;
+        }
+    }
+    if let Some(done) = shutdown_done {
+        let _ = done.send(());
+    }
     println!("room {} closed", room_id);
 }
 
+/// Sends `user_id` away with `reason`, giving up their seat and forfeiting
+/// a game in progress to the opponent. Returns `false` if another instance
+/// owns the room.
+async fn remove_user(
+    state: &AppState,
+    clients: &mut HashMap<Uuid, mpsc::Sender<GameEvent>>,
+    game: &mut GameState,
+    user_id: Uuid,
+    reason: CloseReason,
+) -> bool {
+    if let Some(tx) = clients.remove(&user_id) {
+        let _ = tx.send(GameEvent::RoomClosed { reason }).await;
+    }
+    if let Err(e) = state.db.clear_presence(user_id, game.room_id).await {
+        println!("Failed to clear presence of {}: {:?}", user_id, e);
+    }
+    if game.waiting_player == Some(user_id) {
+        game.waiting_player = None;
+        if !checkpoint(state, game).await {
+            return false;
+        }
+    }
+    if game.status == GameStatus::Active && game.symbol_of(user_id).is_some() {
+        let winner_id = if game.player_x == Some(user_id) { game.player_o } else { game.player_x };
+        return end_game(state, clients, game, winner_id).await;
+    }
+    true
+}
+
+/// Finishes the game with `winner_id`, or a draw, and records the result.
+/// Returns `false`, recording nothing, if another instance owns the room.
+async fn end_game(
+    state: &AppState,
+    clients: &mut HashMap<Uuid, mpsc::Sender<GameEvent>>,
+    game: &mut GameState,
+    winner_id: Option<Uuid>,
+) -> bool {
+    game.status = GameStatus::Finished;
+    if !checkpoint(state, game).await {
+        return false;
+    }
+    broadcast_game_state(clients, game).await;
+    let event = GameEvent::GameOver { winner: winner_id };
+    for client in clients.values() {
+        let _ = client.send(event.clone()).await;
+    }
+    if let Some(game_id) = game.game_id {
+        let board_state: Vec<Option<PlayerSymbol>> = game.board.to_vec();
+        match state.db.finish_game(game_id, winner_id, &board_state, game.move_count).await {
+            Ok(true) => println!("Game {} finished, winner: {:?}", game_id, winner_id),
+            Ok(false) => println!("Game {} had already finished", game_id),
+            Err(e) => println!("Failed to finish game {}: {:?}", game_id, e),
+        }
+    }
+    true
+}
+
+/// Whether `user_id` has blocked, or is blocked by, the room's creator or a
+/// player. Fails closed if the blocks cannot be read.
+async fn blocked_from_room(state: &AppState, game: &GameState, user_id: Uuid) -> bool {
+    let mut others: Vec<Uuid> = [Some(game.creator_id), game.waiting_player, game.player_x, game.player_o]
+        .into_iter()
+        .flatten()
+        .filter(|&id| id != user_id)
+        .collect();
+    others.dedup();
+    match state.db.get_blocked_among(user_id, &others).await {
+        Ok(blocked) => !blocked.is_empty(),
+        Err(e) => {
+            println!("Failed to check blocks for {}: {:?}", user_id, e);
+            true
+        }
+    }
+}
+
+/// Records that `user_id` is connected to the room, for friends lists.
+async fn set_presence(state: &AppState, game: &GameState, user_id: Uuid) {
+    if let Err(e) = state.db.set_presence(user_id, game.room_id, state.cluster.instance_id, game.options.spectators).await {
+        println!("Failed to record presence of {}: {:?}", user_id, e);
+    }
+}
+
+/// Sleeps until `deadline`, or forever if there is none.
+async fn sleep_until(deadline: Option<DateTime<Utc>>) {
+    match deadline {
+        Some(at) => tokio::time::sleep((at - Utc::now()).to_std().unwrap_or_default()).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn validate_chat(
+    state: &AppState,
+    game: &GameState,
+    limiter: &mut RateLimiter,
+    user_id: Uuid,
+    text: &str,
+) -> Result<String, GameError> {
+    let config = &state.chat_config;
+    if !game.options.chat_enabled {
+        return Err(GameError::new(ErrorCode::ChatDisabled, "chat is disabled in this room"));
+    }
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(GameError::new(ErrorCode::InvalidMessage, "chat message is empty"));
+    }
+    if text.chars().count() > config.max_length {
+        return Err(GameError::new(
+            ErrorCode::MessageTooLong,
+            format!("chat messages are limited to {} characters", config.max_length),
+        ));
+    }
+    if !limiter.check(user_id, config.rate_limit, config.rate_window) {
+        return Err(GameError::new(ErrorCode::RateLimited, "you are sending messages too quickly"));
+    }
+    match state.chat_filter.filter(text) {
+        FilterResult::Allow(text) => Ok(text),
+        FilterResult::Reject(reason) => Err(GameError::new(ErrorCode::MessageRejected, reason)),
+    }
+}
+
+async fn reject_move(
+    state: &AppState,
+    clients: &HashMap<Uuid, mpsc::Sender<GameEvent>>,
+    game: &GameState,
+    user_id: Uuid,
+    idx: usize,
+    error: GameError,
+    request_id: Option<String>,
+) {
+    println!("rejected move from user {} in room {}: {}", user_id, game.room_id, error.message);
+    // Rate-limited moves are not audited, so a client flooding moves cannot
+    // turn each message into a write. The rest are written off the actor.
+    if error.code != ErrorCode::RateLimited {
+        let db = state.db.clone();
+        let (room_id, game_id, code, message) = (game.room_id, game.game_id, error.code.name(), error.message.clone());
+        tokio::spawn(async move {
+            if let Err(e) = db.record_rejected_move(RecordRejectedMove {
+                room_id,
+                game_id,
+                user_id,
+                cell: i32::try_from(idx).unwrap_or(i32::MAX),
+                code: &code,
+                message: &message,
+            }).await {
+                println!("Failed to record rejected move: {:?}", e);
+            }
+        });
+    }
+    send_to(clients, user_id, error.into_event(request_id)).await;
+}
+
+async fn send_to(clients: &HashMap<Uuid, mpsc::Sender<GameEvent>>, user_id: Uuid, event: GameEvent) {
+    if let Some(tx) = clients.get(&user_id) {
+        let _ = tx.send(event).await;
+    }
+}
+
 async fn broadcast_game_state(clients: &mut HashMap<Uuid, mpsc::Sender<GameEvent>>, game: &GameState) {
     let event = GameEvent::BoardUpdate(game.board);
-    for client in clients.values() {
+    for (&user_id, client) in clients.iter() {
         let _ = client.send(event.clone()).await;
+        let _ = client.send(GameEvent::StateSnapshot(game.snapshot_for(user_id))).await;
     }
 }
 
@@ -0,0 +1,234 @@
+use actix_web::{HttpResponse, Responder, delete, get, post, put, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::state::AppState;
+use crate::auth::guard::RequireScope;
+use crate::auth::user::{AuthUser, Scope};
+use crate::avatar;
+use crate::cluster;
+use crate::routes::room::GameCommand;
+use db::models::relationships::{FriendRequestOutcome, RelationshipOutcome};
+
+#[derive(Deserialize)]
+pub struct FriendRequestBody {
+    pub user_id: Uuid,
+}
+
+#[derive(Serialize)]
+pub struct FriendResponse {
+    pub user_id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub avatar_thumb_url: Option<String>,
+    pub since: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    /// Connected to a room on a live instance.
+    pub online: bool,
+    /// The room they are in, if it allows spectators.
+    pub room_id: Option<Uuid>,
+}
+
+fn cannot_target_self() -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "error": "you cannot do that to yourself"
+    }))
+}
+
+fn user_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({
+        "error": "user not found"
+    }))
+}
+
+fn blocked() -> HttpResponse {
+    HttpResponse::Forbidden().json(serde_json::json!({
+        "error": "one of you has blocked the other"
+    }))
+}
+
+fn no_content_or_404(result: anyhow::Result<bool>, what: &str) -> HttpResponse {
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("no such {}", what)
+        })),
+        Err(e) => {
+            println!("Failed to update {}: {:?}", what, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn relationship_response(result: anyhow::Result<RelationshipOutcome>) -> HttpResponse {
+    match result {
+        Ok(RelationshipOutcome::Done) => HttpResponse::NoContent().finish(),
+        Ok(RelationshipOutcome::Blocked) => blocked(),
+        Ok(RelationshipOutcome::NotFound) => user_not_found(),
+        Err(e) => {
+            println!("Failed to update relationship: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Friends with whether they are online and, if they are in a room that
+/// allows spectators, which one.
+#[get("/me/friends")]
+async fn get_friends(user: AuthUser, app_state: web::Data<AppState>) -> impl Responder {
+    match app_state.db.get_friends(user.id, app_state.cluster.config.stale_after).await {
+        Ok(friends) => HttpResponse::Ok().json(friends.into_iter().map(|f| FriendResponse {
+            user_id: f.id,
+            username: f.username,
+            display_name: f.display_name,
+            avatar_thumb_url: f.avatar_id.map(|id| avatar::url(id, true)),
+            since: f.since,
+            last_seen_at: f.last_seen_at,
+            online: f.online,
+            room_id: f.room_id,
+        }).collect::<Vec<_>>()),
+        Err(e) => {
+            println!("Failed to get friends of {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Ends a friendship, or withdraws a pending request.
+#[delete("/me/friends/{user_id}", wrap = "RequireScope(Scope::Session)")]
+async fn remove_friend(user: AuthUser, app_state: web::Data<AppState>, path: web::Path<Uuid>) -> impl Responder {
+    no_content_or_404(app_state.db.remove_friend(user.id, path.into_inner()).await, "friend")
+}
+
+/// Pending requests, both incoming and outgoing.
+#[get("/me/friend-requests")]
+async fn get_friend_requests(user: AuthUser, app_state: web::Data<AppState>) -> impl Responder {
+    match app_state.db.get_friend_requests(user.id).await {
+        Ok(requests) => HttpResponse::Ok().json(requests),
+        Err(e) => {
+            println!("Failed to get friend requests of {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Asks someone to be friends. If they already asked, this accepts.
+#[post("/me/friend-requests", wrap = "RequireScope(Scope::Session)")]
+async fn send_friend_request(user: AuthUser, app_state: web::Data<AppState>, body: web::Json<FriendRequestBody>) -> impl Responder {
+    if body.user_id == user.id {
+        return cannot_target_self();
+    }
+    match app_state.db.send_friend_request(user.id, body.user_id).await {
+        Ok(FriendRequestOutcome::Sent) => HttpResponse::Created().json(serde_json::json!({ "status": "sent" })),
+        Ok(FriendRequestOutcome::Accepted) => HttpResponse::Ok().json(serde_json::json!({ "status": "accepted" })),
+        Ok(FriendRequestOutcome::AlreadyFriends) => HttpResponse::Conflict().json(serde_json::json!({
+            "error": "you are already friends"
+        })),
+        Ok(FriendRequestOutcome::Blocked) => blocked(),
+        Ok(FriendRequestOutcome::NotFound) => user_not_found(),
+        Err(e) => {
+            println!("Failed to send friend request from {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("/me/friend-requests/{user_id}/accept", wrap = "RequireScope(Scope::Session)")]
+async fn accept_friend_request(user: AuthUser, app_state: web::Data<AppState>, path: web::Path<Uuid>) -> impl Responder {
+    no_content_or_404(app_state.db.accept_friend_request(user.id, path.into_inner()).await, "friend request")
+}
+
+#[post("/me/friend-requests/{user_id}/decline", wrap = "RequireScope(Scope::Session)")]
+async fn decline_friend_request(user: AuthUser, app_state: web::Data<AppState>, path: web::Path<Uuid>) -> impl Responder {
+    no_content_or_404(app_state.db.decline_friend_request(user.id, path.into_inner()).await, "friend request")
+}
+
+async fn related_users(user: &AuthUser, app_state: &AppState, kind: &str, incoming: bool) -> HttpResponse {
+    match app_state.db.get_related_users(user.id, kind, incoming).await {
+        Ok(users) => HttpResponse::Ok().json(users),
+        Err(e) => {
+            println!("Failed to get {} list of {}: {:?}", kind, user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/me/following")]
+async fn get_following(user: AuthUser, app_state: web::Data<AppState>) -> impl Responder {
+    related_users(&user, &app_state, "follow", false).await
+}
+
+#[get("/me/followers")]
+async fn get_followers(user: AuthUser, app_state: web::Data<AppState>) -> impl Responder {
+    related_users(&user, &app_state, "follow", true).await
+}
+
+#[put("/me/following/{user_id}", wrap = "RequireScope(Scope::Session)")]
+async fn follow(user: AuthUser, app_state: web::Data<AppState>, path: web::Path<Uuid>) -> impl Responder {
+    let target_id = path.into_inner();
+    if target_id == user.id {
+        return cannot_target_self();
+    }
+    relationship_response(app_state.db.follow(user.id, target_id).await)
+}
+
+#[delete("/me/following/{user_id}", wrap = "RequireScope(Scope::Session)")]
+async fn unfollow(user: AuthUser, app_state: web::Data<AppState>, path: web::Path<Uuid>) -> impl Responder {
+    match app_state.db.unfollow(user.id, path.into_inner()).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            println!("Failed to unfollow for {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/me/blocks")]
+async fn get_blocks(user: AuthUser, app_state: web::Data<AppState>) -> impl Responder {
+    related_users(&user, &app_state, "block", false).await
+}
+
+/// Blocks a user: ends any friendship, request or follow between the two,
+/// and keeps them out of each other's rooms, challenges and chat.
+#[put("/me/blocks/{user_id}", wrap = "RequireScope(Scope::Session)")]
+async fn block_user(user: AuthUser, app_state: web::Data<AppState>, path: web::Path<Uuid>) -> impl Responder {
+    let target_id = path.into_inner();
+    if target_id == user.id {
+        return cannot_target_self();
+    }
+    let result = app_state.db.block(user.id, target_id).await;
+    if let Ok(RelationshipOutcome::Done) = result {
+        leave_shared_rooms(&app_state, user.id, target_id).await;
+    }
+    relationship_response(result)
+}
+
+/// Tells every room either user is in about a new block, so that one of
+/// them watching the other play is removed rather than only being kept out
+/// of rooms joined from now on.
+async fn leave_shared_rooms(app_state: &web::Data<AppState>, user_id: Uuid, other_id: Uuid) {
+    let rooms = match app_state.db.get_presence_rooms(&[user_id, other_id]).await {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            println!("Failed to look up rooms of {} and {}: {:?}", user_id, other_id, e);
+            return;
+        }
+    };
+    for room_id in rooms {
+        if let Some(tx) = cluster::room_sender(app_state, room_id).await {
+            let _ = tx.send(GameCommand::Block { user_id, other_id }).await;
+        }
+    }
+}
+
+#[delete("/me/blocks/{user_id}", wrap = "RequireScope(Scope::Session)")]
+async fn unblock_user(user: AuthUser, app_state: web::Data<AppState>, path: web::Path<Uuid>) -> impl Responder {
+    match app_state.db.unblock(user.id, path.into_inner()).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            println!("Failed to unblock for {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
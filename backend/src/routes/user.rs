@@ -1,21 +1,44 @@
-use actix_web::{HttpResponse,HttpRequest, HttpMessage, Responder, post, web, get};
+use std::sync::LazyLock;
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, patch, post, web};
+use actix_web::http::header::RETRY_AFTER;
+use chrono::Utc;
 use serde::{Serialize, Deserialize};
 use argon2::{Argon2, PasswordHasher, PasswordVerifier, password_hash::{PasswordHash, SaltString, rand_core::OsRng}};
 use uuid::Uuid;
-use crate::{auth::jwt::create_jwt_for_user, state::AppState};
+use crate::{auth::user::{AuthUser, Role, Scope}, routes::auth::issue_session, state::AppState};
+use crate::auth::guard::RequireScope;
+use crate::auth::lockout::{ip_key, username_key};
+use db::models::logins::RecordFailedLogin;
+use crate::routes::profile::remove_avatar_files;
+use crate::routes::room::GameCommand;
+use crate::cluster;
+use crate::validation::{FieldErrors, username_taken};
+use db::models::users::UsernameChange;
 #[derive(Serialize, Deserialize)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
 }
 
+pub fn hash_password(password: &str) -> argon2::password_hash::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
 #[post("/signup")]
 async fn signup(app_state: web::Data<AppState>, body: web::Json<LoginRequest>) -> impl Responder {
-    
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = match argon2.hash_password(body.password.as_bytes(), &salt) {
-        Ok(phc) => phc.to_string(),
+    let mut errors = FieldErrors::default();
+    if let Err(e) = app_state.account_policy.check_username(&app_state.db, &body.username, None, &mut errors).await {
+        println!("Failed to check username {}: {:?}", body.username, e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    app_state.account_policy.check_password("password", &body.password, &body.username, &mut errors);
+    if let Err(resp) = errors.into_result() {
+        return *resp;
+    }
+
+    let password_hash = match hash_password(&body.password) {
+        Ok(phc) => phc,
         Err(_) => return HttpResponse::InternalServerError().json(serde_json::json!({
             "error": "failed to hash password"
         }))
@@ -25,12 +48,13 @@ async fn signup(app_state: web::Data<AppState>, body: web::Json<LoginRequest>) -
     let result = app_state.db.create_user(&body.username, &password_hash).await;
     
     match result {
-        Ok(user) => {
+        Ok(Some(user)) => {
             HttpResponse::Ok().json(serde_json::json!({
                 "message": "user created successfully",
                 "id": user.id
             }))
         }
+        Ok(None) => username_taken(&body.username),
         Err(_e) => {
             HttpResponse::InternalServerError().json(serde_json::json!({
                 "error": "failed to create user"
@@ -39,42 +63,163 @@ async fn signup(app_state: web::Data<AppState>, body: web::Json<LoginRequest>) -
     }
 }
 
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeUsernameRequest {
+    pub username: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SigninResponse {
-    pub token: String
+    pub token: String,
+    pub refresh_token: String,
+    /// Lifetime of `token` in seconds.
+    pub expires_in: i64,
+}
+
+/// Verified in place of a real hash when the user does not exist or has no
+/// password, so those sign-ins take as long as a wrong password.
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| hash_password("dummy password").unwrap_or_default());
+
+fn invalid_credentials() -> HttpResponse {
+    HttpResponse::Unauthorized().json(serde_json::json!({
+        "error": "invalid credentials"
+    }))
+}
+
+/// The throttling keys of a sign-in and the free attempts of each.
+fn signin_keys(app_state: &AppState, username: &str, ip: Option<&str>) -> Vec<(String, i32)> {
+    let config = &app_state.login_config;
+    let mut keys = vec![(username_key(username), config.free_attempts)];
+    keys.extend(ip.map(|ip| (ip_key(ip), config.ip_free_attempts)));
+    keys
+}
+
+/// Counts a password attempt for `username` from `ip` before the password
+/// is checked, or turns it away if either is locked out.
+async fn check_attempt(app_state: &AppState, username: &str, ip: Option<&str>) -> Result<(), Box<HttpResponse>> {
+    let keys = signin_keys(app_state, username, ip);
+    // Checked up front as well, so that one locked key does not get an
+    // attempt counted against the others.
+    let key_names: Vec<String> = keys.iter().map(|(key, _)| key.clone()).collect();
+    let reserved = match app_state.db.get_login_lockout(&key_names).await {
+        Ok(None) => reserve_attempts(app_state, &keys).await,
+        Ok(Some(_)) => Ok(false),
+        Err(e) => Err(e),
+    };
+    match reserved {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Box::new(locked_out(app_state, &key_names, username, ip).await)),
+        Err(e) => {
+            println!("Failed to count sign-in attempt: {:?}", e);
+            Err(Box::new(HttpResponse::InternalServerError().finish()))
+        }
+    }
+}
+
+/// Counts an attempt against every key before the password is checked,
+/// locking out those that have run out of attempts. `false` if one of them
+/// is already locked out.
+async fn reserve_attempts(app_state: &AppState, keys: &[(String, i32)]) -> anyhow::Result<bool> {
+    let config = &app_state.login_config;
+    let schedule = config.lockout_schedule();
+    for (key, free_attempts) in keys {
+        let Some(attempts) = app_state.db.reserve_login_attempt(key, config.failure_window.as_secs_f64(), *free_attempts, &schedule).await? else {
+            return Ok(false);
+        };
+        if let Some(lockout) = config.lockout_for(attempts, *free_attempts) {
+            println!("Locking out {} for {:?} after {} sign-in attempts", key, lockout, attempts);
+        }
+    }
+    Ok(true)
+}
+
+/// Forgets the username's failures once its password was right, and takes
+/// back the attempt counted against the client IP.
+async fn clear_attempts(app_state: &AppState, username: &str, ip: Option<&str>) {
+    if let Err(e) = app_state.db.clear_login_failures(&username_key(username)).await {
+        println!("Failed to clear failed sign-ins of {}: {:?}", username, e);
+    }
+    if let Some(ip) = ip
+        && let Err(e) = app_state.db.release_login_attempt(&ip_key(ip)).await {
+        println!("Failed to release sign-in attempt of {}: {:?}", ip, e);
+    }
+}
+
+/// Audits a sign-in made while locked out and turns it away. Such attempts
+/// are not counted, so retrying does not extend the lockout.
+async fn locked_out(app_state: &AppState, keys: &[String], username: &str, ip: Option<&str>) -> HttpResponse {
+    let attempt = RecordFailedLogin { username, user_id: None, ip, reason: "locked" };
+    if let Err(e) = app_state.db.record_failed_login(attempt).await {
+        println!("Failed to record failed sign-in: {:?}", e);
+    }
+    let until = match app_state.db.get_login_lockout(keys).await {
+        Ok(until) => until,
+        Err(e) => {
+            println!("Failed to check sign-in lockout: {:?}", e);
+            None
+        }
+    };
+    let retry_after = until.map_or(1, |until| (until - Utc::now()).num_seconds().max(1));
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, retry_after.to_string()))
+        .json(serde_json::json!({
+            "error": "too many failed sign-ins, try again later",
+            "retry_after": retry_after
+        }))
+}
+
+/// Writes the audit record of a failed sign-in. The attempt itself was
+/// already counted by `reserve_attempts`.
+async fn record_failed_signin(app_state: &AppState, username: &str, user_id: Option<Uuid>, ip: Option<&str>, reason: &str) {
+    if let Err(e) = app_state.db.record_failed_login(RecordFailedLogin { username, user_id, ip, reason }).await {
+        println!("Failed to record failed sign-in: {:?}", e);
+    }
 }
 
 #[post("/signin")]
-async fn signin(app_state: web::Data<AppState>, body: web::Json<LoginRequest>) -> impl Responder {
-    
-    let result = app_state.db.get_user_by_username(&body.username).await;
-    
-    match result {
-        Ok(user) => {
-            let argon2 = Argon2::default();
-            let parsed_hash = PasswordHash::new(&user.password);
-            if let Ok(hash) = parsed_hash {
-                if argon2.verify_password(body.password.as_bytes(), &hash).is_ok() {
-                    let token = match create_jwt_for_user(&user.id.to_string(), 24) {
-                        Ok(t) => t,
-                        Err(_) => return HttpResponse::InternalServerError().finish(),
-                    };
-                    HttpResponse::Ok().json(SigninResponse { token })
-                } else {
-                    HttpResponse::Unauthorized().json(serde_json::json!({
-                        "error": "invalid credentials"
-                    }))
-                }
-            } else {
-                HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "failed to parse password hash"
-                }))
+async fn signin(req: HttpRequest, app_state: web::Data<AppState>, body: web::Json<LoginRequest>) -> impl Responder {
+    let ip = app_state.login_config.client_ip(&req);
+    if let Err(resp) = check_attempt(&app_state, &body.username, ip.as_deref()).await {
+        return *resp;
+    }
+
+    let user = if app_state.account_policy.username_checkable(&body.username) {
+        match app_state.db.get_user_by_username(&body.username).await {
+            Ok(user) => user,
+            Err(e) => {
+                println!("Failed to look up user {}: {:?}", body.username, e);
+                return HttpResponse::InternalServerError().finish();
             }
         }
-        Err(_) => {
-            HttpResponse::InternalServerError().json(serde_json::json!({
-                "message": format!("username {} not found", body.username)
-            }))
+    } else {
+        None
+    };
+    let stored_hash = user.as_ref().and_then(|u| u.password.as_deref());
+    let has_password = stored_hash.is_some();
+    let verified = app_state.account_policy.password_checkable(&body.password)
+        && PasswordHash::new(stored_hash.unwrap_or(&DUMMY_HASH))
+            .is_ok_and(|hash| Argon2::default().verify_password(body.password.as_bytes(), &hash).is_ok());
+
+    match user {
+        Some(user) if verified && has_password => {
+            clear_attempts(&app_state, &body.username, ip.as_deref()).await;
+            let role = Role::parse(&user.role).unwrap_or_default();
+            issue_session(&app_state, user.id, role).await
+        }
+        Some(user) => {
+            let reason = if has_password { "wrong_password" } else { "no_password" };
+            record_failed_signin(&app_state, &body.username, Some(user.id), ip.as_deref(), reason).await;
+            invalid_credentials()
+        }
+        None => {
+            record_failed_signin(&app_state, &body.username, None, ip.as_deref(), "unknown_user").await;
+            invalid_credentials()
         }
     }
 }
@@ -85,46 +230,199 @@ pub struct UserStats {
     pub games_played: i32,
     pub games_won: i32,
     pub win_rate: f32,
+    pub moving_first: SideStats,
+    pub moving_second: SideStats,
+    /// Games with a bot in them, which the other numbers leave out.
+    pub bot_games: SideStats,
+}
+
+#[derive(Serialize)]
+pub struct SideStats {
+    pub games_played: i64,
+    pub games_won: i64,
 }
 
 #[get("/me")]
-async fn me(req: HttpRequest) -> impl Responder {
-    if let Some(uid) = req.extensions().get::<Uuid>() {
-        HttpResponse::Ok().json(serde_json::json!({
-            "user_id": uid.to_string()
+async fn me(user: AuthUser) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "user_id": user.id.to_string(),
+        "role": user.role
+    }))
+}
+
+/// Sets a new password. Every session, this one included, is revoked, so
+/// the user has to sign in again. Wrong current passwords count towards the
+/// sign-in lockout, so a stolen session cannot be used to guess it.
+#[patch("/me/password", wrap = "RequireScope(Scope::Session)")]
+async fn change_password(req: HttpRequest, user: AuthUser, app_state: web::Data<AppState>, body: web::Json<ChangePasswordRequest>) -> impl Responder {
+    let username = match app_state.db.get_username(user.id).await {
+        Ok(username) => username,
+        Err(e) => {
+            println!("Failed to look up user {}: {:?}", user.id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let ip = app_state.login_config.client_ip(&req);
+    let stored_hash = match app_state.db.get_password_hash(user.id).await {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return HttpResponse::Conflict().json(serde_json::json!({
+            "error": "account has no password"
+        })),
+        Err(e) => {
+            println!("Failed to look up password of {}: {:?}", user.id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let Err(resp) = check_attempt(&app_state, &username, ip.as_deref()).await {
+        return *resp;
+    }
+    let verified = app_state.account_policy.password_checkable(&body.current_password)
+        && PasswordHash::new(&stored_hash)
+            .is_ok_and(|hash| Argon2::default().verify_password(body.current_password.as_bytes(), &hash).is_ok());
+    if !verified {
+        record_failed_signin(&app_state, &username, Some(user.id), ip.as_deref(), "wrong_password").await;
+        return HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "current password is incorrect"
+        }));
+    }
+    clear_attempts(&app_state, &username, ip.as_deref()).await;
+    let mut errors = FieldErrors::default();
+    app_state.account_policy.check_password("new_password", &body.new_password, &username, &mut errors);
+    if let Err(resp) = errors.into_result() {
+        return *resp;
+    }
+
+    let password_hash = match hash_password(&body.new_password) {
+        Ok(phc) => phc,
+        Err(_) => return HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": "failed to hash password"
         }))
-    } else {
-        HttpResponse::Unauthorized().finish()
+    };
+    match app_state.db.change_password(user.id, &password_hash).await {
+        Ok(()) => {
+            println!("User {} changed their password", user.id);
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => {
+            println!("Failed to change password of {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
     }
 }
 
-#[get("/me/stats")]
-async fn get_my_stats(app_state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
-    if let Some(uid) = req.extensions().get::<Uuid>() {
-        match app_state.db.get_user_stats(*uid).await {
-            Ok((games_played, games_won, win_rate)) => {
-                HttpResponse::Ok().json(UserStats {
-                    user_id: *uid,
-                    games_played,
-                    games_won,
-                    win_rate,
-                })
+/// Renames the user. The old name is kept in the username history.
+#[patch("/me/username", wrap = "RequireScope(Scope::Session)")]
+async fn change_username(user: AuthUser, app_state: web::Data<AppState>, body: web::Json<ChangeUsernameRequest>) -> impl Responder {
+    match app_state.db.is_guest(user.id).await {
+        Ok(false) => {}
+        Ok(true) => return HttpResponse::Conflict().json(serde_json::json!({
+            "error": "guests choose a username with /auth/upgrade"
+        })),
+        Err(e) => {
+            println!("Failed to look up user {}: {:?}", user.id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    let mut errors = FieldErrors::default();
+    if let Err(e) = app_state.account_policy.check_username(&app_state.db, &body.username, Some(user.id), &mut errors).await {
+        println!("Failed to check username {}: {:?}", body.username, e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    if let Err(resp) = errors.into_result() {
+        return *resp;
+    }
+    match app_state.db.change_username(user.id, &body.username).await {
+        Ok(UsernameChange::Changed) => {
+            println!("User {} is now {}", user.id, body.username);
+            HttpResponse::Ok().json(serde_json::json!({
+                "id": user.id,
+                "username": body.username
+            }))
+        }
+        Ok(UsernameChange::UsernameTaken) => username_taken(&body.username),
+        Ok(UsernameChange::NotFound) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Failed to rename {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Deletes the account. Its games are kept under an anonymous name; see
+/// `Db::delete_account`. Live games the user is playing are forfeited and
+/// they are sent away from every room they are in.
+#[delete("/me", wrap = "RequireScope(Scope::Session)")]
+async fn delete_account(user: AuthUser, app_state: web::Data<AppState>) -> impl Responder {
+    let avatar_id = match app_state.db.get_profile(user.id).await {
+        Ok(profile) => profile.and_then(|p| p.avatar_id),
+        Err(e) => {
+            println!("Failed to get profile of {}: {:?}", user.id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // Looked up first, as deleting the account forgets the user's presence.
+    let rooms = match app_state.db.get_presence_rooms(&[user.id]).await {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            println!("Failed to look up rooms of {}: {:?}", user.id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match app_state.db.delete_account(user.id).await {
+        Ok(true) => {
+            for room_id in rooms {
+                if let Some(tx) = cluster::room_sender(&app_state, room_id).await {
+                    let _ = tx.send(GameCommand::AccountDeleted { user_id: user.id }).await;
+                }
             }
-            Err(e) => {
-                println!("Failed to get user stats: {:?}", e);
-                HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Failed to retrieve user statistics"
-                }))
+            if let Some(avatar_id) = avatar_id {
+                remove_avatar_files(&app_state, avatar_id).await;
             }
+            println!("Deleted account {}", user.id);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Failed to delete account {}: {:?}", user.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Results against humans, split by who moved first, plus games with bots.
+pub async fn load_user_stats(app_state: &AppState, uid: Uuid) -> anyhow::Result<UserStats> {
+    let (games_played, games_won, win_rate) = app_state.db.get_user_stats(uid).await?;
+    let (played_first, won_first, played_second, won_second) = app_state.db.get_user_first_move_stats(uid).await?;
+    let (played_bot, won_bot) = app_state.db.get_user_bot_stats(uid).await?;
+    Ok(UserStats {
+        user_id: uid,
+        games_played,
+        games_won,
+        win_rate,
+        moving_first: SideStats { games_played: played_first, games_won: won_first },
+        moving_second: SideStats { games_played: played_second, games_won: won_second },
+        bot_games: SideStats { games_played: played_bot, games_won: won_bot },
+    })
+}
+
+#[get("/me/stats")]
+async fn get_my_stats(app_state: web::Data<AppState>, user: AuthUser) -> impl Responder {
+    match load_user_stats(&app_state, user.id).await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(e) => {
+            println!("Failed to get user stats: {:?}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to retrieve user statistics"
+            }))
         }
-    } else {
-        HttpResponse::Unauthorized().finish()
     }
 }
 
 #[derive(Serialize)]
 pub struct AllUserStats {
     pub user_id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
     pub games_played: i32,
     pub games_won: i32,
     pub win_rate: f32,
@@ -135,8 +433,10 @@ async fn get_all_stats(app_state: web::Data<AppState>) -> impl Responder {
     match app_state.db.get_all_user_stats().await {
         Ok(stats) => {
             let user_stats: Vec<AllUserStats> = stats.into_iter()
-                .map(|(user_id, games_played, games_won, win_rate)| AllUserStats {
+                .map(|(user_id, username, display_name, games_played, games_won, win_rate)| AllUserStats {
                     user_id,
+                    username,
+                    display_name,
                     games_played,
                     games_won,
                     win_rate,
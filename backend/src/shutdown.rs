@@ -0,0 +1,52 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::state::AppState;
+use crate::routes::room::GameCommand;
+
+/// Resolves on SIGTERM or Ctrl-C.
+pub async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+fn room_senders(state: &AppState) -> Vec<mpsc::Sender<GameCommand>> {
+    state.active_rooms.iter().map(|entry| entry.value().clone()).collect()
+}
+
+/// Stops new rooms from being created, warns every room, lets games in
+/// progress run until the grace period ends and then closes whatever is
+/// left, waiting for each room to persist its final state.
+pub async fn drain_rooms(state: &AppState) {
+    state.shutting_down.store(true, Ordering::SeqCst);
+    let grace = state.room_config.shutdown_grace;
+    let deadline = Utc::now() + chrono::Duration::from_std(grace).unwrap_or_default();
+    println!("Shutting down, {} rooms open, deadline {}", state.active_rooms.len(), deadline);
+
+    for tx in room_senders(state) {
+        let _ = tx.send(GameCommand::ShutdownNotice { deadline }).await;
+    }
+
+    let started = Instant::now();
+    while !state.active_rooms.is_empty() && started.elapsed() < grace {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+
+    let mut pending = Vec::new();
+    for tx in room_senders(state) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if tx.send(GameCommand::Shutdown { done: done_tx }).await.is_ok() {
+            pending.push(done_rx);
+        }
+    }
+    for done in pending {
+        let _ = done.await;
+    }
+    println!("All rooms closed");
+}
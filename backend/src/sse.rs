@@ -0,0 +1,164 @@
+use actix_web::{get, post, web, HttpResponse, Responder};
+use actix_web::web::Bytes;
+use futures_util::stream;
+use serde::{Serialize, Deserialize};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{interval, Interval};
+use uuid::Uuid;
+
+use crate::state::AppState;
+use crate::auth::guard::RequireScope;
+use crate::auth::jwt;
+use crate::auth::user::{AuthUser, Scope};
+use crate::cluster;
+use crate::routes::room::{GameCommand, GameEvent};
+use crate::protocol::ServerEnvelope;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Sends `Leave` to the room when the event stream is dropped, which is how
+/// actix tells us the client went away.
+struct LeaveOnDrop {
+    room_tx: mpsc::Sender<GameCommand>,
+    user_id: Uuid,
+}
+
+impl Drop for LeaveOnDrop {
+    fn drop(&mut self) {
+        // Drop cannot wait, and a full room channel must not lose the leave.
+        let (room_tx, user_id) = (self.room_tx.clone(), self.user_id);
+        actix_web::rt::spawn(async move {
+            if room_tx.send(GameCommand::Leave { user_id }).await.is_err() {
+                println!("Failed to notify room that SSE client {} left", user_id);
+            }
+        });
+    }
+}
+
+struct EventStream {
+    game_rx: mpsc::Receiver<GameEvent>,
+    keep_alive: Interval,
+    seq: u64,
+    _guard: LeaveOnDrop,
+}
+
+#[derive(Serialize)]
+struct StreamTicket {
+    ticket: String,
+    expires_in: i64,
+}
+
+/// Issues a ticket for opening the room's event stream with
+/// `?ticket=`, for browsers whose `EventSource` cannot send headers.
+#[post("/rooms/{room_id}/events/ticket", wrap = "RequireScope(Scope::Play)")]
+pub async fn create_stream_ticket(user: AuthUser, path: web::Path<Uuid>) -> impl Responder {
+    match jwt::create_stream_ticket(user.id, user.role, path.into_inner()) {
+        Ok(ticket) => HttpResponse::Ok().json(StreamTicket {
+            ticket,
+            expires_in: jwt::stream_ticket_ttl().num_seconds(),
+        }),
+        Err(e) => {
+            println!("Failed to create stream ticket: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Joins the room like `join_room` does and streams the same envelopes as
+/// Server-Sent Events, for clients whose proxies break WebSockets.
+#[get("/rooms/{room_id}/events", wrap = "RequireScope(Scope::Play)")]
+pub async fn room_events(
+    user: AuthUser,
+    path: web::Path<Uuid>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let room_id = path.into_inner();
+
+    let user_id = user.id;
+
+    let room_tx = match cluster::room_sender(&app_state, room_id).await {
+        Some(tx) => tx,
+        None => return HttpResponse::NotFound().body("Room not found"),
+    };
+
+    let (user_tx, user_rx) = mpsc::channel::<GameEvent>(32);
+
+    if room_tx.send(GameCommand::Join {
+        user_id,
+        player_sender: user_tx
+    }).await.is_err() {
+        return HttpResponse::InternalServerError().body("Room is dead or closed");
+    }
+
+    let state = EventStream {
+        game_rx: user_rx,
+        keep_alive: interval(KEEP_ALIVE),
+        seq: 0,
+        _guard: LeaveOnDrop { room_tx, user_id },
+    };
+
+    let events = stream::unfold(state, |mut state| async move {
+        let frame = tokio::select! {
+            event = state.game_rx.recv() => {
+                let event = event?;
+                state.seq += 1;
+                let data = serde_json::to_value(ServerEnvelope::new(state.seq, &event)).ok()?;
+                let kind = data.get("type").and_then(|t| t.as_str()).unwrap_or("message");
+                format!("id: {}\nevent: {}\ndata: {}\n\n", state.seq, kind, data)
+            }
+            _ = state.keep_alive.tick() => ": keep-alive\n\n".to_string(),
+        };
+        Some((Ok::<_, actix_web::Error>(Bytes::from(frame)), state))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events)
+}
+
+#[derive(Deserialize)]
+pub struct MoveRequest {
+    pub cell: usize,
+    /// Optional client-chosen request id, echoed on the event stream.
+    pub id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MoveAccepted {
+    request_id: String,
+}
+
+/// Submits a move over plain HTTP. The outcome arrives as an `ack` or
+/// `error` with the returned request id on the player's event stream.
+#[post("/rooms/{room_id}/moves", wrap = "RequireScope(Scope::Play)")]
+pub async fn post_move(
+    user: AuthUser,
+    path: web::Path<Uuid>,
+    body: web::Json<MoveRequest>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let room_id = path.into_inner();
+
+    let user_id = user.id;
+
+    let room_tx = match cluster::room_sender(&app_state, room_id).await {
+        Some(tx) => tx,
+        None => return HttpResponse::NotFound().body("Room not found"),
+    };
+
+    let MoveRequest { cell, id } = body.into_inner();
+    let request_id = id.unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    if room_tx.send(GameCommand::Move {
+        user_id,
+        idx: cell,
+        request_id: Some(request_id.clone()),
+    }).await.is_err() {
+        return HttpResponse::InternalServerError().body("Room is dead or closed");
+    }
+
+    HttpResponse::Accepted().json(MoveAccepted { request_id })
+}
@@ -2,11 +2,29 @@ use dashmap::DashMap;
 use uuid::Uuid;
 use tokio::sync::mpsc;
 use db::Db;
-use crate::routes::room::GameCommand;
+use crate::routes::room::{GameCommand, RoomConfig};
+use crate::chat::{ChatConfig, ChatFilter};
+use crate::cluster::Cluster;
+use crate::auth::oidc::OidcClient;
+use crate::auth::lockout::LoginConfig;
+use crate::validation::AccountPolicy;
+use crate::avatar::AvatarConfig;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 
 pub struct AppState {
     pub db: Db,
-    pub active_rooms: Arc<DashMap<Uuid, mpsc::Sender<GameCommand>>>
+    pub active_rooms: Arc<DashMap<Uuid, mpsc::Sender<GameCommand>>>,
+    pub chat_config: ChatConfig,
+    pub chat_filter: Arc<dyn ChatFilter>,
+    pub room_config: RoomConfig,
+    /// Set once shutdown has started; no new rooms are created after that.
+    pub shutting_down: AtomicBool,
+    pub cluster: Cluster,
+    /// `None` when single sign-on is not configured.
+    pub oidc: Option<OidcClient>,
+    pub login_config: LoginConfig,
+    pub account_policy: AccountPolicy,
+    pub avatar_config: AvatarConfig,
 }
 
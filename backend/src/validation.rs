@@ -0,0 +1,325 @@
+use std::collections::{BTreeMap, HashSet};
+use actix_web::HttpResponse;
+use anyhow::Result;
+use serde::Serialize;
+use uuid::Uuid;
+use db::Db;
+use db::models::users::UsernameClash;
+
+use crate::config::env_or;
+
+/// Names nobody may register, or anything that looks like them. Extended
+/// with `RESERVED_USERNAMES`.
+const RESERVED_USERNAMES: &[&str] = &["admin", "administrator", "root", "system", "moderator", "support", "deleted", "guest", "bot"];
+
+/// Prefixes of generated names: guests and deleted accounts.
+const RESERVED_PREFIXES: &[&str] = &["guest-", "deleted-"];
+
+/// Rules for usernames and passwords chosen by users. Usernames are letters,
+/// digits, `_`, `-` and `.`, starting with a letter or digit; letters are
+/// ASCII only unless `USERNAME_ALLOW_UNICODE` is set, and then may not mix
+/// Latin, Cyrillic and Greek. Passwords need `PASSWORD_MIN_CLASSES` of
+/// lowercase, uppercase, digits and symbols, must not contain the username,
+/// and must not appear in `BREACHED_PASSWORDS_FILE` (one password per line).
+pub struct AccountPolicy {
+    pub username_min_length: usize,
+    pub username_max_length: usize,
+    pub allow_unicode: bool,
+    pub reserved: Vec<String>,
+    pub password_min_length: usize,
+    /// Longer passwords are turned away before they are hashed, at sign-up
+    /// and also at sign-in, so that huge ones cannot be sent to make argon2
+    /// do extra work.
+    pub password_max_length: usize,
+    pub password_min_classes: usize,
+    breached: HashSet<String>,
+}
+
+#[derive(Serialize)]
+pub struct FieldError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Validation failures by request field, sent as a 422.
+#[derive(Default)]
+pub struct FieldErrors(BTreeMap<&'static str, Vec<FieldError>>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &'static str, code: &'static str, message: impl Into<String>) {
+        self.0.entry(field).or_default().push(FieldError { code, message: message.into() });
+    }
+
+    pub fn has(&self, field: &str) -> bool {
+        self.0.contains_key(field)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn into_result(self) -> Result<(), Box<HttpResponse>> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        Err(Box::new(HttpResponse::UnprocessableEntity().json(serde_json::json!({
+            "error": "validation failed",
+            "fields": self.0
+        }))))
+    }
+}
+
+/// Trims a text field and checks its length; `Some("")` becomes `None`.
+pub fn check_text(errors: &mut FieldErrors, field: &'static str, value: &str, max_length: usize, multiline: bool) -> Option<String> {
+    let value = value.trim();
+    if value.chars().count() > max_length {
+        errors.add(field, "too_long", format!("must be at most {} characters", max_length));
+    }
+    if value.chars().any(|c| c.is_control() && !(multiline && c == '\n')) {
+        errors.add(field, "invalid_characters", "must not contain control characters");
+    }
+    (!value.is_empty()).then(|| value.to_owned())
+}
+
+/// The 409 for a username someone already has.
+pub fn username_taken(username: &str) -> HttpResponse {
+    HttpResponse::Conflict().json(serde_json::json!({
+        "error": format!("username {} is taken", username),
+        "fields": { "username": [{ "code": "taken", "message": "is taken" }] }
+    }))
+}
+
+impl AccountPolicy {
+    pub fn from_env() -> Self {
+        let mut reserved: Vec<String> = RESERVED_USERNAMES.iter().map(|&name| name.to_owned()).collect();
+        if let Ok(names) = std::env::var("RESERVED_USERNAMES") {
+            reserved.extend(names.split(',').map(str::trim).filter(|name| !name.is_empty()).map(str::to_owned));
+        }
+        let breached = match std::env::var("BREACHED_PASSWORDS_FILE") {
+            Ok(path) => match std::fs::read_to_string(&path) {
+                Ok(contents) => contents.lines().filter(|line| !line.is_empty()).map(str::to_owned).collect(),
+                Err(e) => {
+                    println!("Failed to read breached password list {}: {:?}", path, e);
+                    HashSet::new()
+                }
+            },
+            Err(_) => HashSet::new(),
+        };
+        Self {
+            username_min_length: env_or("USERNAME_MIN_LENGTH", 3),
+            username_max_length: env_or("USERNAME_MAX_LENGTH", 32),
+            allow_unicode: env_or("USERNAME_ALLOW_UNICODE", false),
+            reserved,
+            password_min_length: env_or("PASSWORD_MIN_LENGTH", 8),
+            password_max_length: env_or("PASSWORD_MAX_LENGTH", 128),
+            password_min_classes: env_or("PASSWORD_MIN_CLASSES", 2),
+            breached,
+        }
+    }
+
+    /// Checks a username about to be taken, by a new account or by
+    /// `user_id` renaming itself. Fails only if the database does.
+    pub async fn check_username(&self, db: &Db, username: &str, user_id: Option<Uuid>, errors: &mut FieldErrors) -> Result<()> {
+        self.check_username_format(username, errors);
+        if errors.has("username") {
+            return Ok(());
+        }
+
+        match db.find_username_clash(username, &self.reserved, user_id).await? {
+            Some(UsernameClash::Reserved) => errors.add("username", "reserved", "is reserved"),
+            Some(UsernameClash::Existing) => errors.add("username", "confusable", "is too similar to an existing username"),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// The checks of `check_username` that need no database: length,
+    /// characters, scripts and reserved prefixes.
+    pub fn check_username_format(&self, username: &str, errors: &mut FieldErrors) {
+        let length = username.chars().count();
+        if length < self.username_min_length {
+            errors.add("username", "too_short", format!("must be at least {} characters", self.username_min_length));
+        }
+        if length > self.username_max_length {
+            errors.add("username", "too_long", format!("must be at most {} characters", self.username_max_length));
+        }
+        if !username.chars().all(|c| self.username_char(c)) {
+            errors.add("username", "invalid_characters", "may only contain letters, digits, '_', '-' and '.'");
+        } else if username.starts_with(['_', '-', '.']) {
+            errors.add("username", "invalid_start", "must start with a letter or digit");
+        }
+        if self.allow_unicode && mixes_scripts(username) {
+            errors.add("username", "mixed_script", "must not mix Latin, Cyrillic and Greek letters");
+        }
+        let lowercase = username.to_lowercase();
+        if RESERVED_PREFIXES.iter().any(|prefix| lowercase.starts_with(prefix)) {
+            errors.add("username", "reserved", "is reserved");
+        }
+    }
+
+    /// Whether `c` may appear in a username.
+    pub fn username_char(&self, c: char) -> bool {
+        matches!(c, '_' | '-' | '.') || if self.allow_unicode { c.is_alphanumeric() } else { c.is_ascii_alphanumeric() }
+    }
+
+    /// Whether `username` is short enough to belong to an account, so that
+    /// sign-in can skip looking it up.
+    pub fn username_checkable(&self, username: &str) -> bool {
+        username.chars().count() <= self.username_max_length
+    }
+
+    /// Whether `password` is short enough to be worth hashing to compare it
+    /// with a stored one.
+    pub fn password_checkable(&self, password: &str) -> bool {
+        password.chars().count() <= self.password_max_length
+    }
+
+    /// Checks a new password, sent as `field`, for the account called
+    /// `username`.
+    pub fn check_password(&self, field: &'static str, password: &str, username: &str, errors: &mut FieldErrors) {
+        let length = password.chars().count();
+        if length < self.password_min_length {
+            errors.add(field, "too_short", format!("must be at least {} characters", self.password_min_length));
+        }
+        if length > self.password_max_length {
+            errors.add(field, "too_long", format!("must be at most {} characters", self.password_max_length));
+        }
+        let classes = [
+            password.chars().any(char::is_lowercase),
+            password.chars().any(char::is_uppercase),
+            password.chars().any(|c| c.is_numeric()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|&&present| present).count() < self.password_min_classes {
+            errors.add(field, "too_weak", format!(
+                "must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                self.password_min_classes
+            ));
+        }
+        if username.chars().count() >= 3 && password.to_lowercase().contains(&username.to_lowercase()) {
+            errors.add(field, "contains_username", "must not contain the username");
+        }
+        if self.breached.contains(password) {
+            errors.add(field, "breached", "appears in a list of breached passwords");
+        }
+    }
+}
+
+/// Whether letters from more than one of Latin, Cyrillic and Greek appear,
+/// the usual way of spoofing a name with lookalikes.
+fn mixes_scripts(name: &str) -> bool {
+    let script = |c: char| match c {
+        'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => Some(0),
+        '\u{0370}'..='\u{03FF}' => Some(1),
+        '\u{0400}'..='\u{052F}' => Some(2),
+        _ => None,
+    };
+    let mut scripts = name.chars().filter_map(script);
+    scripts.next().is_some_and(|first| scripts.any(|s| s != first))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> AccountPolicy {
+        AccountPolicy {
+            username_min_length: 3,
+            username_max_length: 16,
+            allow_unicode: false,
+            reserved: ["admin", "root", "wizard", "coop"].map(str::to_owned).to_vec(),
+            password_min_length: 8,
+            password_max_length: 32,
+            password_min_classes: 2,
+            breached: HashSet::from(["Password1".to_owned()]),
+        }
+    }
+
+    fn codes(errors: &FieldErrors, field: &str) -> Vec<&'static str> {
+        errors.0.get(field).map_or(Vec::new(), |errors| errors.iter().map(|e| e.code).collect())
+    }
+
+    fn password_codes(password: &str) -> Vec<&'static str> {
+        let mut errors = FieldErrors::default();
+        policy().check_password("password", password, "alice", &mut errors);
+        codes(&errors, "password")
+    }
+
+    fn username_codes(policy: &AccountPolicy, username: &str) -> Vec<&'static str> {
+        let mut errors = FieldErrors::default();
+        policy.check_username_format(username, &mut errors);
+        codes(&errors, "username")
+    }
+
+    #[test]
+    fn password_error_codes() {
+        assert!(password_codes("correct-Horse").is_empty());
+        assert_eq!(password_codes("aB1"), ["too_short"]);
+        assert_eq!(password_codes(&"aB1".repeat(11)), ["too_long"]);
+        assert_eq!(password_codes("alllowercase"), ["too_weak"]);
+        assert_eq!(password_codes("my-Alice-pass"), ["contains_username"]);
+        assert_eq!(password_codes("Password1"), ["breached"]);
+    }
+
+    #[test]
+    fn passwords_past_the_maximum_are_not_checked() {
+        assert!(policy().password_checkable(&"x".repeat(32)));
+        assert!(!policy().password_checkable(&"x".repeat(33)));
+    }
+
+    #[test]
+    fn usernames_past_the_maximum_are_not_looked_up() {
+        assert!(policy().username_checkable(&"x".repeat(16)));
+        assert!(!policy().username_checkable(&"x".repeat(17)));
+        assert!(!policy().username_checkable(&"x".repeat(1_000)));
+    }
+
+    #[test]
+    fn username_error_codes() {
+        let policy = policy();
+        assert!(username_codes(&policy, "alice_42").is_empty());
+        assert_eq!(username_codes(&policy, "ab"), ["too_short"]);
+        assert_eq!(username_codes(&policy, "a-very-long-username"), ["too_long"]);
+        assert_eq!(username_codes(&policy, "bad name"), ["invalid_characters"]);
+        assert_eq!(username_codes(&policy, "ünïcode"), ["invalid_characters"]);
+        assert_eq!(username_codes(&policy, "_leading"), ["invalid_start"]);
+        assert_eq!(username_codes(&policy, "guest-1234"), ["reserved"]);
+        assert_eq!(username_codes(&policy, "Deleted-me"), ["reserved"]);
+    }
+
+    #[test]
+    fn unicode_usernames_must_not_mix_scripts() {
+        let policy = AccountPolicy { allow_unicode: true, ..policy() };
+        assert!(username_codes(&policy, "ünïcode").is_empty());
+        assert!(username_codes(&policy, "соор").is_empty());
+        assert_eq!(username_codes(&policy, "pаypal"), ["mixed_script"]);
+    }
+
+    #[test]
+    fn text_fields() {
+        let mut errors = FieldErrors::default();
+        assert_eq!(check_text(&mut errors, "name", "  ci runner ", 10, false).as_deref(), Some("ci runner"));
+        assert_eq!(check_text(&mut errors, "name", "   ", 10, false), None);
+        assert!(errors.is_empty());
+
+        check_text(&mut errors, "name", &"x".repeat(11), 10, false);
+        assert_eq!(codes(&errors, "name"), ["too_long"]);
+
+        let mut errors = FieldErrors::default();
+        assert!(check_text(&mut errors, "bio", "two\nlines", 20, true).is_some());
+        assert!(errors.is_empty());
+        check_text(&mut errors, "name", "two\nlines", 20, false);
+        assert_eq!(codes(&errors, "name"), ["invalid_characters"]);
+    }
+
+    #[test]
+    fn mixed_scripts() {
+        assert!(!mixes_scripts("alice"));
+        assert!(!mixes_scripts("алиса"));
+        assert!(!mixes_scripts("αλίκη_42"));
+        assert!(!mixes_scripts("Zoë"));
+        assert!(mixes_scripts("аlice"));
+        assert!(mixes_scripts("alicε"));
+        assert!(mixes_scripts("алисa"));
+    }
+}
@@ -1,4 +1,5 @@
-use actix_web::{get, web, HttpRequest, HttpResponse, HttpMessage, Error, rt};
+use actix_web::{get, web, HttpRequest, HttpResponse, Error, rt};
+use actix_web::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
 use actix_ws::Message;
 use futures_util::StreamExt as _; // Needed for stream.next()
 use tokio::sync::mpsc;
@@ -6,92 +7,180 @@ use uuid::Uuid;
 use serde::Deserialize;
 
 use crate::state::AppState;
+use crate::auth::guard::RequireScope;
+use crate::auth::user::{AuthUser, Scope};
+use crate::cluster;
 use crate::routes::room::{GameCommand, GameEvent};
+use crate::protocol::{
+    parse_subprotocol, subprotocol_name, ClientEnvelope, Encoding, ErrorCode, GameError, ServerEnvelope,
+    PROTOCOL_VERSION, SUPPORTED_VERSIONS,
+};
 
 #[derive(Deserialize)]
-#[serde(tag = "action", content = "payload")]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
 enum ClientMessage {
-    #[serde(rename = "move")]
     Move(usize),
+    Chat(String),
 }
 
-#[get("/ws/{room_id}")]
+#[derive(Deserialize)]
+pub struct ConnectParams {
+    /// Protocol version requested by the client, defaults to the latest.
+    v: Option<u32>,
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+/// Negotiated wire settings for one connection. A `Sec-WebSocket-Protocol`
+/// offer such as `tictactoe.v1.msgpack` takes precedence over the query
+/// parameters, which browsers can't always set headers for anyway.
+struct Negotiated {
+    version: u32,
+    encoding: Encoding,
+    subprotocol: Option<String>,
+}
+
+fn negotiate(req: &HttpRequest, params: &ConnectParams) -> Option<Negotiated> {
+    let offered = req
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.split(',').filter_map(parse_subprotocol).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if let Some(&(version, encoding)) = offered.first() {
+        return Some(Negotiated { version, encoding, subprotocol: Some(subprotocol_name(version, encoding)) });
+    }
+
+    let version = params.v.unwrap_or(PROTOCOL_VERSION);
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return None;
+    }
+    Some(Negotiated { version, encoding: params.encoding, subprotocol: None })
+}
+
+#[get("/ws/{room_id}", wrap = "RequireScope(Scope::Play)")]
 pub async fn join_room(
     req: HttpRequest,
+    user: AuthUser,
     stream: web::Payload,
     path: web::Path<Uuid>,
+    params: web::Query<ConnectParams>,
     app_state: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
     let room_id = path.into_inner();
-    
-    let user_id = match req.extensions().get::<Uuid>() {
-        Some(&uid) => uid,
-        None => return Ok(HttpResponse::Unauthorized().finish()), 
+
+    let Some(negotiated) = negotiate(&req, &params) else {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "unsupported protocol version",
+            "supported": SUPPORTED_VERSIONS
+        })));
     };
 
-    let room_tx = match app_state.active_rooms.get(&room_id) {
-        Some(tx) => tx.clone(),
+    let user_id = user.id;
+
+    let room_tx = match cluster::room_sender(&app_state, room_id).await {
+        Some(tx) => tx,
         None => return Ok(HttpResponse::NotFound().body("Room not found")),
     };
 
     let (user_tx, user_rx) = mpsc::channel::<GameEvent>(32);
 
-    if let Err(_) = room_tx.send(GameCommand::Join { 
+    if room_tx.send(GameCommand::Join { 
         user_id, 
         player_sender: user_tx 
-    }).await {
+    }).await.is_err() {
          return Ok(HttpResponse::InternalServerError().body("Room is dead or closed"));
     }
 
-    let (response, session, msg_stream) = actix_ws::handle(&req, stream)?;
+    let (mut response, session, msg_stream) = actix_ws::handle(&req, stream)?;
+    if let Some(name) = &negotiated.subprotocol
+        && let Ok(value) = HeaderValue::from_str(name) {
+        response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+    }
 
+    let outbound = Outbound { session, seq: 0, encoding: negotiated.encoding };
     rt::spawn(async move {
-        ws_loop(session, msg_stream, user_rx, room_tx, user_id).await;
+        ws_loop(outbound, msg_stream, user_rx, room_tx, user_id, negotiated.version).await;
     });
 
     Ok(response)
 }
 
+/// Writes envelopes to the socket in the negotiated encoding, numbering
+/// them as it goes.
+struct Outbound {
+    session: actix_ws::Session,
+    seq: u64,
+    encoding: Encoding,
+}
+
+impl Outbound {
+    async fn send(&mut self, event: &GameEvent) -> Result<(), actix_ws::Closed> {
+        let bytes = match self.encoding.encode(&ServerEnvelope::new(self.seq + 1, event)) {
+            Ok(b) => b,
+            Err(e) => {
+                println!("Failed to encode event: {:?}", e);
+                return Ok(());
+            }
+        };
+        self.seq += 1;
+        match self.encoding {
+            Encoding::Json => match String::from_utf8(bytes) {
+                Ok(text) => self.session.text(text).await,
+                Err(_) => Ok(()),
+            },
+            Encoding::Msgpack | Encoding::Cbor => self.session.binary(bytes).await,
+        }
+    }
+}
+
 async fn ws_loop(
-    mut session: actix_ws::Session,
+    mut out: Outbound,
     mut msg_stream: actix_ws::MessageStream,
     mut game_rx: mpsc::Receiver<GameEvent>,
     room_tx: mpsc::Sender<GameCommand>,
     user_id: Uuid,
+    version: u32,
 ) {
     loop {
         tokio::select! {
             Some(msg) = msg_stream.next() => {
-                match msg {
-                    Ok(Message::Text(text)) => {
-                        if let Ok(action) = serde_json::from_str::<ClientMessage>(&text) {
-                            match action {
-                                ClientMessage::Move(idx) => {
-                                    let _ = room_tx.send(GameCommand::Move { user_id, idx }).await;
-                                }
-                            }
-                        } else {
-                            println!("Invalid JSON from user {}", user_id);
-                        }
-                    }
+                let parsed = match msg {
+                    Ok(Message::Text(text)) => parse_client_message(Encoding::Json, text.as_bytes(), version),
+                    Ok(Message::Binary(bytes)) => parse_client_message(out.encoding, &bytes, version),
                     Ok(Message::Ping(bytes)) => {
-                        let _ = session.pong(&bytes).await;
+                        let _ = out.session.pong(&bytes).await;
+                        continue;
                     }
                     Ok(Message::Close(reason)) => {
-                        let _ = session.close(reason).await;
+                        let _ = out.session.close(reason).await;
                         break; 
                     }
-                    _ => {},
+                    _ => continue,
+                };
+                match parsed {
+                    Ok((request_id, ClientMessage::Move(idx))) => {
+                        let _ = room_tx.send(GameCommand::Move { user_id, idx, request_id }).await;
+                    }
+                    Ok((request_id, ClientMessage::Chat(text))) => {
+                        let _ = room_tx.send(GameCommand::Chat { user_id, text, request_id }).await;
+                    }
+                    Err((request_id, e)) => {
+                        println!("Invalid message from user {}: {}", user_id, e.message);
+                        if out.send(&e.into_event(request_id)).await.is_err() {
+                            break;
+                        }
+                    }
                 }
             }
 
-            Some(event) = game_rx.recv() => {
-                let json = match serde_json::to_string(&event) {
-                    Ok(j) => j,
-                    Err(_) => continue,
+            event = game_rx.recv() => {
+                // The room closed or this connection was replaced by a reconnect.
+                let Some(event) = event else {
+                    let _ = out.session.close(None).await;
+                    break;
                 };
-                
-                if let Err(_) = session.text(json).await {
+                if out.send(&event).await.is_err() {
                     break;
                 }
             }
@@ -100,6 +189,28 @@ async fn ws_loop(
         }
     }
 
+    // Close our end first so the room can tell this leave from a stale one.
+    drop(game_rx);
     let _ = room_tx.send(GameCommand::Leave { user_id }).await;
     println!("WebSocket closed for user {}", user_id);
-}
\ No newline at end of file
+}
+
+fn parse_client_message(
+    encoding: Encoding,
+    bytes: &[u8],
+    version: u32,
+) -> Result<(Option<String>, ClientMessage), (Option<String>, GameError)> {
+    let envelope = encoding.decode::<ClientEnvelope>(bytes)
+        .map_err(|e| (None, GameError::new(ErrorCode::InvalidMessage, e.to_string())))?;
+    if envelope.v != version {
+        return Err((envelope.id, GameError::new(
+            ErrorCode::UnsupportedVersion,
+            format!("connection negotiated protocol version {}", version),
+        )));
+    }
+    let message = serde_json::json!({ "type": envelope.kind, "payload": envelope.payload });
+    match serde_json::from_value::<ClientMessage>(message) {
+        Ok(message) => Ok((envelope.id, message)),
+        Err(e) => Err((envelope.id, GameError::new(ErrorCode::InvalidMessage, e.to_string()))),
+    }
+}
@@ -0,0 +1,168 @@
+use std::process::Stdio;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+/// Extra time an engine gets past its `movetime` to answer.
+const MOVE_GRACE: Duration = Duration::from_secs(1);
+
+/// A local engine process spoken to over stdin/stdout, one command per line:
+///
+/// - `newgame <x|o>`: a game starts and the engine plays the given symbol.
+/// - `position <board>`: the board as nine characters, `x`, `o` or `-`,
+///   row by row from the top left. Only sent when it is the engine's turn.
+/// - `go movetime <ms>`: think for at most this long, then answer with
+///   `bestmove <idx>`, the cell from 0 to 8.
+/// - `quit`: the game is over and the engine should exit.
+///
+/// Any other line the engine prints is logged and otherwise ignored.
+pub struct Engine {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+impl Engine {
+    /// Starts `command`, split on whitespace into a program and arguments.
+    pub fn spawn(command: &str) -> Result<Self> {
+        let mut parts = command.split_whitespace();
+        let program = parts.next().ok_or_else(|| anyhow!("engine command is empty"))?;
+        let mut child = Command::new(program)
+            .args(parts)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("failed to start engine {}", program))?;
+        let stdin = child.stdin.take().ok_or_else(|| anyhow!("engine has no stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("engine has no stdout"))?;
+        Ok(Self { child, stdin, stdout: BufReader::new(stdout).lines() })
+    }
+
+    pub async fn send(&mut self, line: &str) -> Result<()> {
+        println!("> {}", line);
+        self.stdin.write_all(format!("{}\n", line).as_bytes()).await?;
+        self.stdin.flush().await?;
+        Ok(())
+    }
+
+    /// Sends the position and waits for the engine's move.
+    pub async fn best_move(&mut self, board: &str, movetime: Duration) -> Result<usize> {
+        self.send(&format!("position {}", board)).await?;
+        self.send(&format!("go movetime {}", movetime.as_millis())).await?;
+        tokio::time::timeout(movetime + MOVE_GRACE, self.read_best_move())
+            .await
+            .map_err(|_| anyhow!("engine did not answer within {:?}", movetime + MOVE_GRACE))?
+    }
+
+    async fn read_best_move(&mut self) -> Result<usize> {
+        while let Some(line) = self.stdout.next_line().await? {
+            println!("< {}", line);
+            if let Some(cell) = line.trim().strip_prefix("bestmove ") {
+                let cell: usize = cell.trim().parse().with_context(|| format!("bad bestmove: {}", line))?;
+                if cell > 8 {
+                    bail!("engine picked cell {}, which is off the board", cell);
+                }
+                return Ok(cell);
+            }
+        }
+        bail!("engine exited without a move")
+    }
+
+    /// Asks the engine to quit and waits briefly for it to do so.
+    pub async fn quit(mut self) {
+        let _ = self.send("quit").await;
+        if tokio::time::timeout(MOVE_GRACE, self.child.wait()).await.is_err() {
+            let _ = self.child.kill().await;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A shell script engine in a temporary file, removed on drop.
+    pub(crate) struct Script(std::path::PathBuf);
+
+    impl Script {
+        pub(crate) fn new(name: &str, script: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("bot-bridge-{}-{}.sh", name, std::process::id()));
+            std::fs::write(&path, script).unwrap();
+            Self(path)
+        }
+
+        pub(crate) fn spawn(&self) -> Engine {
+            Engine::spawn(&format!("sh {}", self.0.display())).unwrap()
+        }
+    }
+
+    impl Drop for Script {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Plays the first empty cell, after some chatter the bridge should skip.
+    const FIRST_EMPTY: &str = r#"
+while read cmd arg rest; do
+    case "$cmd" in
+        position) board="$arg" ;;
+        go) echo "info thinking"; before="${board%%-*}"; echo "bestmove ${#before}" ;;
+        quit) exit 0 ;;
+    esac
+done
+"#;
+
+    #[tokio::test]
+    async fn best_move_reads_the_engine_answer() {
+        let script = Script::new("first-empty", FIRST_EMPTY);
+        let mut engine = script.spawn();
+        engine.send("newgame x").await.unwrap();
+        assert_eq!(engine.best_move("---------", Duration::from_millis(100)).await.unwrap(), 0);
+        assert_eq!(engine.best_move("xo-x-----", Duration::from_millis(100)).await.unwrap(), 2);
+        engine.quit().await;
+    }
+
+    #[tokio::test]
+    async fn best_move_rejects_cells_off_the_board() {
+        let script = "while read cmd rest; do [ \"$cmd\" = go ] && echo 'bestmove 9'; done\n";
+        let script = Script::new("off-board", script);
+        let mut engine = script.spawn();
+        let err = engine.best_move("---------", Duration::from_millis(100)).await.unwrap_err();
+        assert!(err.to_string().contains("off the board"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn best_move_rejects_malformed_answers() {
+        let script = "while read cmd rest; do [ \"$cmd\" = go ] && echo 'bestmove centre'; done\n";
+        let script = Script::new("malformed", script);
+        let mut engine = script.spawn();
+        let err = engine.best_move("---------", Duration::from_millis(100)).await.unwrap_err();
+        assert!(err.to_string().contains("bad bestmove"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn best_move_times_out_on_a_silent_engine() {
+        let script = "while read cmd rest; do :; done\n";
+        let script = Script::new("silent", script);
+        let mut engine = script.spawn();
+        let err = engine.best_move("---------", Duration::from_millis(10)).await.unwrap_err();
+        assert!(err.to_string().contains("did not answer"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn best_move_fails_when_the_engine_exits() {
+        let script = Script::new("exits", "read cmd rest\n");
+        let mut engine = script.spawn();
+        let err = engine.best_move("---------", Duration::from_millis(100)).await.unwrap_err();
+        assert!(err.to_string().contains("exited without a move"), "{}", err);
+    }
+
+    #[test]
+    fn spawn_rejects_an_empty_command() {
+        assert!(Engine::spawn("  ").is_err());
+    }
+}
@@ -0,0 +1,360 @@
+mod engine;
+
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use uuid::Uuid;
+
+use crate::engine::Engine;
+
+/// How long to wait before resending a move the server rate limited, and
+/// how often to try.
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RETRIES: u32 = 10;
+
+/// Connects a local engine to the server as a bot account and plays one
+/// game with it. Configured through the environment (or a `.env` file):
+///
+/// - `BOT_API_KEY`: a `play` key of the bot account.
+/// - `BOT_ENGINE`: the engine command line, e.g. `python3 engine.py`.
+/// - `BOT_SERVER_URL`: defaults to `http://localhost:3000`.
+/// - `BOT_ROOM_ID`: the room to join. Without it a new room is created and
+///   its id printed, bot-only if `BOT_BOTS_ONLY` is `true`.
+/// - `BOT_MOVETIME_MS`: thinking time per move, 1000 by default.
+///
+/// The server has no engine of its own to play against. To pit two engines
+/// against each other, start a second bridge with `BOT_ROOM_ID` set to the
+/// room the first one printed.
+struct Config {
+    server_url: String,
+    api_key: String,
+    engine: String,
+    room_id: Option<Uuid>,
+    bots_only: bool,
+    movetime: Duration,
+}
+
+impl Config {
+    fn from_env() -> Result<Self> {
+        Ok(Self {
+            server_url: env_or("BOT_SERVER_URL", "http://localhost:3000".to_string()).trim_end_matches('/').to_owned(),
+            api_key: std::env::var("BOT_API_KEY").map_err(|_| anyhow!("BOT_API_KEY must be set"))?,
+            engine: std::env::var("BOT_ENGINE").map_err(|_| anyhow!("BOT_ENGINE must be set"))?,
+            room_id: std::env::var("BOT_ROOM_ID").ok().map(|id| id.parse()).transpose().context("BOT_ROOM_ID is not a uuid")?,
+            bots_only: env_or("BOT_BOTS_ONLY", false),
+            movetime: Duration::from_millis(env_or("BOT_MOVETIME_MS", 1000)),
+        })
+    }
+
+    fn ws_url(&self, room_id: Uuid) -> String {
+        let base = match self.server_url.split_once("://") {
+            Some(("https", rest)) => format!("wss://{}", rest),
+            Some((_, rest)) => format!("ws://{}", rest),
+            None => format!("ws://{}", self.server_url),
+        };
+        format!("{}/api/ws/{}", base, room_id)
+    }
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+#[derive(Deserialize)]
+struct Frame {
+    #[serde(rename = "type")]
+    kind: String,
+    id: Option<String>,
+    #[serde(default)]
+    payload: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+enum Symbol {
+    X,
+    O,
+}
+
+impl Symbol {
+    fn letter(self) -> char {
+        match self {
+            Symbol::X => 'x',
+            Symbol::O => 'o',
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Seat {
+    user_id: Uuid,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    board: [Option<Symbol>; 9],
+    current_turn: Symbol,
+    status: String,
+    player_x: Option<Seat>,
+    player_o: Option<Seat>,
+    your_symbol: Option<Symbol>,
+    move_number: i32,
+}
+
+impl Snapshot {
+    fn board_string(&self) -> String {
+        self.board.iter().map(|cell| cell.map_or('-', Symbol::letter)).collect()
+    }
+
+    fn my_id(&self) -> Option<Uuid> {
+        let seat = match self.your_symbol? {
+            Symbol::X => self.player_x.as_ref(),
+            Symbol::O => self.player_o.as_ref(),
+        };
+        seat.map(|s| s.user_id)
+    }
+}
+
+async fn create_room(config: &Config) -> Result<Uuid> {
+    #[derive(Deserialize)]
+    struct Created {
+        room_id: Uuid,
+    }
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/room", config.server_url))
+        .header("X-Api-Key", &config.api_key)
+        .json(&serde_json::json!({ "bots_only": config.bots_only }))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!("failed to create room: {} {}", response.status(), response.text().await.unwrap_or_default());
+    }
+    Ok(response.json::<Created>().await?.room_id)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenvy::dotenv().ok();
+    // Neither HTTP client picks a TLS crypto provider of its own.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let config = Config::from_env()?;
+
+    let room_id = match config.room_id {
+        Some(id) => id,
+        None => {
+            let id = create_room(&config).await?;
+            println!("Created room {}, waiting for an opponent", id);
+            id
+        }
+    };
+
+    let mut request = config.ws_url(room_id).into_client_request()?;
+    request.headers_mut().insert("X-Api-Key", HeaderValue::from_str(&config.api_key)?);
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.context("failed to connect")?;
+    println!("Joined room {}", room_id);
+
+    let mut engine = Engine::spawn(&config.engine)?;
+    let result = play(&config, &mut socket, &mut engine).await;
+    engine.quit().await;
+    let _ = socket.close(None).await;
+    result
+}
+
+async fn play<S>(config: &Config, socket: &mut S, engine: &mut Engine) -> Result<()>
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + SinkExt<Message> + Unpin,
+    <S as futures_util::Sink<Message>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut my_id = None;
+    let mut started = false;
+    let mut last_move_number = None;
+    let mut pending: Option<(String, u32)> = None;
+
+    while let Some(message) = socket.next().await {
+        let text = match message? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let frame: Frame = serde_json::from_str(&text).with_context(|| format!("unexpected frame: {}", text))?;
+        match frame.kind.as_str() {
+            "state_snapshot" => {
+                let snapshot: Snapshot = serde_json::from_value(frame.payload)?;
+                let Some(symbol) = snapshot.your_symbol else { continue };
+                if snapshot.status != "active" {
+                    continue;
+                }
+                if !started {
+                    started = true;
+                    my_id = snapshot.my_id();
+                    println!("Game started, playing {:?}", symbol);
+                    engine.send(&format!("newgame {}", symbol.letter())).await?;
+                }
+                if snapshot.current_turn != symbol || last_move_number == Some(snapshot.move_number) {
+                    continue;
+                }
+                last_move_number = Some(snapshot.move_number);
+                let cell = engine.best_move(&snapshot.board_string(), config.movetime).await?;
+                let envelope = serde_json::json!({
+                    "v": 1,
+                    "id": snapshot.move_number.to_string(),
+                    "type": "move",
+                    "payload": cell
+                });
+                socket.send(Message::text(envelope.to_string())).await?;
+                pending = Some((envelope.to_string(), 0));
+            }
+            "ack" => pending = None,
+            "error" if frame.payload["code"] == "RATE_LIMITED" => {
+                // Without a pending move there is nothing to resend, and
+                // giving up here would forfeit a game still in progress.
+                let Some((envelope, retries)) = pending.as_mut() else {
+                    println!("Ignoring rate limit with no move pending: {}", frame.payload);
+                    continue;
+                };
+                if *retries >= MAX_RETRIES {
+                    bail!("still rate limited after {} retries", MAX_RETRIES);
+                }
+                *retries += 1;
+                tokio::time::sleep(RATE_LIMIT_BACKOFF).await;
+                socket.send(Message::text(envelope.clone())).await?;
+            }
+            "error" => {
+                // Moves are only sent on our turn, so a rejected one is the
+                // engine's fault; anything else is the server turning us away.
+                match frame.id {
+                    Some(id) => bail!("move {} rejected: {}", id, frame.payload),
+                    None => bail!("server error: {}", frame.payload),
+                }
+            }
+            "game_over" => {
+                let winner: Option<Uuid> = serde_json::from_value(frame.payload["winner"].clone()).unwrap_or(None);
+                match winner {
+                    None => println!("Game over: draw"),
+                    Some(winner) if Some(winner) == my_id => println!("Game over: we won"),
+                    Some(winner) => println!("Game over: {} won", winner),
+                }
+                return Ok(());
+            }
+            "room_closed" | "room_expired" => {
+                println!("Room ended: {}", frame.payload);
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+    bail!("connection closed before the game ended")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::tests::Script;
+    use tokio::net::TcpListener;
+
+    fn config() -> Config {
+        Config {
+            server_url: String::new(),
+            api_key: String::new(),
+            engine: String::new(),
+            room_id: None,
+            bots_only: false,
+            movetime: Duration::from_millis(100),
+        }
+    }
+
+    /// Always plays the centre.
+    fn engine() -> Script {
+        Script::new("play", "while read cmd rest; do [ \"$cmd\" = go ] && echo 'bestmove 4'; [ \"$cmd\" = quit ] && exit 0; done\n")
+    }
+
+    /// Runs `play` against a server that answers the bot's first move with
+    /// `replies`, then ends the game.
+    async fn play_against(replies: Vec<serde_json::Value>) -> (Result<()>, Vec<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let me = Uuid::new_v4();
+            let snapshot = serde_json::json!({
+                "v": 1, "type": "state_snapshot", "payload": {
+                    "board": [null, null, null, null, null, null, null, null, null],
+                    "current_turn": "X", "status": "active", "move_number": 0, "your_symbol": "X",
+                    "player_x": { "user_id": me }, "player_o": { "user_id": Uuid::new_v4() }
+                }
+            });
+            ws.send(Message::text(snapshot.to_string())).await.unwrap();
+            let mut received = Vec::new();
+            let Some(Ok(Message::Text(text))) = ws.next().await else { panic!("no move sent") };
+            received.push(serde_json::from_str(&text).unwrap());
+            for reply in replies {
+                ws.send(Message::text(reply.to_string())).await.unwrap();
+                if reply["type"] == "error"
+                    && let Ok(Some(Ok(Message::Text(text)))) = tokio::time::timeout(Duration::from_secs(2), ws.next()).await
+                {
+                    received.push(serde_json::from_str(&text).unwrap());
+                }
+            }
+            let over = serde_json::json!({ "v": 1, "type": "game_over", "payload": { "winner": me } });
+            let _ = ws.send(Message::text(over.to_string())).await;
+            received
+        });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        let script = engine();
+        let mut engine = script.spawn();
+        let result = play(&config(), &mut socket, &mut engine).await;
+        engine.quit().await;
+        (result, server.await.unwrap())
+    }
+
+    fn rate_limited(id: Option<&str>) -> serde_json::Value {
+        serde_json::json!({ "v": 1, "type": "error", "id": id, "payload": { "code": "RATE_LIMITED", "message": "slow down" } })
+    }
+
+    #[tokio::test]
+    async fn resends_a_rate_limited_move() {
+        let ack = serde_json::json!({ "v": 1, "type": "ack", "id": "0" });
+        let (result, received) = play_against(vec![rate_limited(Some("0")), ack]).await;
+        result.unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0], received[1]);
+        assert_eq!(received[0]["payload"], 4);
+    }
+
+    #[tokio::test]
+    async fn ignores_a_rate_limit_with_no_move_pending() {
+        let ack = serde_json::json!({ "v": 1, "type": "ack", "id": "0" });
+        let (result, received) = play_against(vec![ack, rate_limited(None)]).await;
+        result.unwrap();
+        assert_eq!(received.len(), 1);
+    }
+
+    /// The first byte `connect` sends to a local server on the given port.
+    async fn first_byte_sent(connect: impl FnOnce(u16) -> tokio::task::JoinHandle<()>) -> u8 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = connect(listener.local_addr().unwrap().port());
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let byte = tokio::io::AsyncReadExt::read_u8(&mut stream).await.unwrap();
+        client.abort();
+        byte
+    }
+
+    #[tokio::test]
+    async fn speaks_tls_to_https_servers() {
+        const TLS_HANDSHAKE: u8 = 0x16;
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let websocket = first_byte_sent(|port| tokio::spawn(async move {
+            let _ = tokio_tungstenite::connect_async(format!("wss://127.0.0.1:{}/", port)).await;
+        }));
+        assert_eq!(websocket.await, TLS_HANDSHAKE);
+        let http = first_byte_sent(|port| tokio::spawn(async move {
+            let _ = reqwest::Client::new().get(format!("https://127.0.0.1:{}/", port)).send().await;
+        }));
+        assert_eq!(http.await, TLS_HANDSHAKE);
+    }
+}
@@ -0,0 +1,4 @@
+-- Record which player made the first move of each game
+ALTER TABLE games ADD COLUMN IF NOT EXISTS first_player_id UUID REFERENCES users(id);
+
+CREATE INDEX idx_games_first_player_id ON games(first_player_id);
@@ -0,0 +1,13 @@
+-- Create game chat table
+CREATE TABLE IF NOT EXISTS game_chat (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    room_id UUID NOT NULL,
+    game_id UUID REFERENCES games(id), -- NULL for messages sent before both seats were filled
+    user_id UUID NOT NULL REFERENCES users(id),
+    message TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+-- Create indexes
+CREATE INDEX idx_game_chat_room_id ON game_chat(room_id);
+CREATE INDEX idx_game_chat_game_id ON game_chat(game_id);
@@ -0,0 +1,15 @@
+-- Create move audit table for rejected move attempts
+CREATE TABLE IF NOT EXISTS move_audit (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    room_id UUID NOT NULL,
+    game_id UUID REFERENCES games(id),
+    user_id UUID NOT NULL REFERENCES users(id),
+    cell INTEGER NOT NULL,
+    code VARCHAR(50) NOT NULL,
+    message TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+-- Create indexes
+CREATE INDEX idx_move_audit_user_id ON move_audit(user_id);
+CREATE INDEX idx_move_audit_room_id ON move_audit(room_id);
@@ -0,0 +1,11 @@
+-- Correspondence games live only in this table and are played over HTTP
+ALTER TABLE games ADD COLUMN IF NOT EXISTS mode VARCHAR(20) DEFAULT 'live'; -- live, correspondence
+ALTER TABLE games ADD COLUMN IF NOT EXISTS creator_id UUID REFERENCES users(id);
+ALTER TABLE games ADD COLUMN IF NOT EXISTS invited_id UUID REFERENCES users(id); -- NULL for open challenges
+ALTER TABLE games ADD COLUMN IF NOT EXISTS days_per_move INTEGER;
+ALTER TABLE games ADD COLUMN IF NOT EXISTS move_deadline TIMESTAMP WITH TIME ZONE;
+ALTER TABLE games ADD COLUMN IF NOT EXISTS options JSONB;
+
+-- Create indexes
+CREATE INDEX idx_games_mode ON games(mode);
+CREATE INDEX idx_games_move_deadline ON games(move_deadline);
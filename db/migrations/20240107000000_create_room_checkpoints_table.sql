@@ -0,0 +1,6 @@
+-- Create room checkpoints table so live rooms survive a restart
+CREATE TABLE IF NOT EXISTS room_checkpoints (
+    room_id UUID PRIMARY KEY,
+    state JSONB NOT NULL, -- serialized room state: board, seats, turn, clocks, move list
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
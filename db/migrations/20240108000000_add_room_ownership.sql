@@ -0,0 +1,9 @@
+-- Track running backend instances and which one owns each live room
+CREATE TABLE IF NOT EXISTS instances (
+    id UUID PRIMARY KEY,
+    heartbeat_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+ALTER TABLE room_checkpoints ADD COLUMN IF NOT EXISTS instance_id UUID; -- owning instance, NULL for rooms saved before clustering
+
+CREATE INDEX IF NOT EXISTS idx_room_checkpoints_instance ON room_checkpoints(instance_id);
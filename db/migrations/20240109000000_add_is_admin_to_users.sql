@@ -0,0 +1,2 @@
+-- Flag users allowed to use the admin API
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,7 @@
+-- Replace the admin flag with a role: player, moderator or admin
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role VARCHAR(32) NOT NULL DEFAULT 'player'
+    CHECK (role IN ('player', 'moderator', 'admin'));
+
+UPDATE users SET role = 'admin' WHERE is_admin;
+
+ALTER TABLE users DROP COLUMN IF EXISTS is_admin;
@@ -0,0 +1,8 @@
+-- Guest accounts have no password until they are upgraded
+ALTER TABLE users ALTER COLUMN password DROP NOT NULL;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_guest BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Bumped whenever a session is started or refreshed; guests expire on it
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_seen_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW();
+
+CREATE INDEX idx_users_guest_last_seen ON users(last_seen_at) WHERE is_guest;
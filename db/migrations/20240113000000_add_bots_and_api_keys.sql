@@ -0,0 +1,23 @@
+-- Bot accounts play through API keys and belong to a human user
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_bot BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS owner_id UUID REFERENCES users(id);
+
+CREATE INDEX idx_users_owner_id ON users(owner_id) WHERE owner_id IS NOT NULL;
+
+-- Long-lived API keys, stored as SHA-256 hashes
+CREATE TABLE IF NOT EXISTS api_keys (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR(100) NOT NULL,
+    key_prefix VARCHAR(16) NOT NULL, -- shown in listings to tell keys apart
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    scope VARCHAR(20) NOT NULL CHECK (scope IN ('read', 'play')),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_api_keys_user_id ON api_keys(user_id);
+
+-- Games with a bot in them count separately from human statistics
+ALTER TABLE games ADD COLUMN IF NOT EXISTS bot BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,21 @@
+-- Consecutive failed sign-ins per username or client IP, and the lockout
+-- they earned. Keys look like `user:<name>` or `ip:<address>`.
+CREATE TABLE IF NOT EXISTS login_throttles (
+    key VARCHAR(300) PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    locked_until TIMESTAMP WITH TIME ZONE
+);
+
+-- Audit log of failed sign-ins
+CREATE TABLE IF NOT EXISTS failed_logins (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    username VARCHAR(255) NOT NULL,
+    user_id UUID REFERENCES users(id) ON DELETE SET NULL, -- NULL for unknown usernames
+    ip VARCHAR(64),
+    reason VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_failed_logins_username ON failed_logins(username);
+CREATE INDEX idx_failed_logins_created_at ON failed_logins(created_at);
@@ -0,0 +1,15 @@
+-- Folds a username to a skeleton under which lookalike names compare equal:
+-- Cyrillic and Greek homoglyphs become their Latin twins, 0 becomes o, 1 and
+-- capital I become l, then the name is lowercased and rn/vv become m/w.
+CREATE OR REPLACE FUNCTION username_skeleton(name TEXT) RETURNS TEXT
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE AS $$
+    SELECT replace(replace(lower(translate(name,
+        'АВЕКМНОРСТХУІЈЅаеорсухіјѕԁһӏԛԝΑΒΕΖΗΙΚΜΝΟΡΤΥΧαικνορυχ01I',
+        'ABEKMHOPCTXYlJSaeopcyxijsdhlqwABEZHlKMNOPTYXaikvopuxoll')),
+        'rn', 'm'), 'vv', 'w')
+$$;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS username_skeleton TEXT
+    GENERATED ALWAYS AS (username_skeleton(username)) STORED;
+
+CREATE INDEX idx_users_username_skeleton ON users(username_skeleton);
@@ -0,0 +1,9 @@
+-- Public profile fields, all optional
+ALTER TABLE users ADD COLUMN IF NOT EXISTS display_name VARCHAR(64);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS bio VARCHAR(500);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS country CHAR(2); -- ISO 3166-1 alpha-2
+
+-- Names the avatar files on disk; a new one on every upload so URLs can be cached forever
+ALTER TABLE users ADD COLUMN IF NOT EXISTS avatar_id UUID;
+
+CREATE INDEX idx_games_finished_at ON games(finished_at DESC) WHERE status = 'finished';
@@ -0,0 +1,25 @@
+-- Directed edges of the social graph. A friendship is a pair of 'friend' rows,
+-- one each way; a pending friend request is a single row from the sender.
+CREATE TABLE IF NOT EXISTS user_relationships (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    target_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    kind VARCHAR(20) NOT NULL CHECK (kind IN ('friend_request', 'friend', 'follow', 'block')),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, target_id, kind),
+    CHECK (user_id <> target_id)
+);
+
+CREATE INDEX idx_user_relationships_target ON user_relationships(target_id, kind);
+
+-- Who is connected to which room, written by the instance running the room.
+-- Rows of instances that stopped sending heartbeats are ignored.
+CREATE TABLE IF NOT EXISTS user_presence (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    room_id UUID NOT NULL,
+    instance_id UUID NOT NULL,
+    spectatable BOOLEAN NOT NULL DEFAULT FALSE,
+    connected_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, room_id)
+);
+
+CREATE INDEX idx_user_presence_room_id ON user_presence(room_id);
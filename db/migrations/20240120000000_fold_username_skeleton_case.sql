@@ -0,0 +1,19 @@
+-- Capital I was folded to l before lowercasing, so ADMIN and admin had
+-- different skeletons. Fold i to l as well, after lowercasing, so every case
+-- variant of a name shares its skeleton. The stored column has to be
+-- regenerated for existing names to pick this up.
+ALTER TABLE users DROP COLUMN IF EXISTS username_skeleton;
+
+CREATE OR REPLACE FUNCTION username_skeleton(name TEXT) RETURNS TEXT
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE AS $$
+    SELECT replace(replace(translate(lower(translate(name,
+        'АВЕКМНОРСТХУІЈЅаеорсухіјѕԁһӏԛԝΑΒΕΖΗΙΚΜΝΟΡΤΥΧαικνορυχ01I',
+        'ABEKMHOPCTXYlJSaeopcyxijsdhlqwABEZHlKMNOPTYXaikvopuxoll')),
+        'i', 'l'),
+        'rn', 'm'), 'vv', 'w')
+$$;
+
+ALTER TABLE users ADD COLUMN username_skeleton TEXT
+    GENERATED ALWAYS AS (username_skeleton(username)) STORED;
+
+CREATE INDEX idx_users_username_skeleton ON users(username_skeleton);
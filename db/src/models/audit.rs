@@ -0,0 +1,32 @@
+use uuid::Uuid;
+use anyhow::Result;
+
+use crate::Db;
+
+pub struct RecordRejectedMove<'a> {
+    pub room_id: Uuid,
+    pub game_id: Option<Uuid>,
+    pub user_id: Uuid,
+    pub cell: i32,
+    pub code: &'a str,
+    pub message: &'a str,
+}
+
+impl Db {
+    pub async fn record_rejected_move(&self, req: RecordRejectedMove<'_>) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO move_audit (room_id, game_id, user_id, cell, code, message) VALUES ($1, $2, $3, $4, $5, $6)",
+            req.room_id,
+            req.game_id,
+            req.user_id,
+            req.cell,
+            req.code,
+            req.message
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
@@ -0,0 +1,184 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+use anyhow::Result;
+
+use crate::Db;
+
+#[derive(Serialize)]
+pub struct Bot {
+    pub id: Uuid,
+    pub username: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub key_prefix: String,
+    pub scope: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// The account an API key authenticates as.
+pub struct ApiKeyUser {
+    pub key_id: Uuid,
+    pub user_id: Uuid,
+    pub scope: String,
+    pub is_bot: bool,
+}
+
+pub struct BotStats {
+    pub id: Uuid,
+    pub username: String,
+    pub owner_id: Option<Uuid>,
+    pub games_played: i64,
+    pub games_won: i64,
+}
+
+impl Db {
+    /// Creates a bot owned by `owner_id`. Returns `None` if the name is taken.
+    pub async fn create_bot(&self, owner_id: Uuid, username: &str) -> Result<Option<Bot>> {
+        let bot = sqlx::query_as!(
+            Bot,
+            "INSERT INTO users (username, is_bot, owner_id) VALUES ($1, TRUE, $2)
+             ON CONFLICT (username) DO NOTHING
+             RETURNING id, username, created_at",
+            username,
+            owner_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(bot)
+    }
+
+    pub async fn get_bots(&self, owner_id: Uuid) -> Result<Vec<Bot>> {
+        let bots = sqlx::query_as!(
+            Bot,
+            "SELECT id, username, created_at FROM users WHERE is_bot AND owner_id = $1 ORDER BY created_at",
+            owner_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(bots)
+    }
+
+    pub async fn is_bot_owner(&self, bot_id: Uuid, owner_id: Uuid) -> Result<bool> {
+        let row = sqlx::query!(
+            r#"SELECT EXISTS (SELECT 1 FROM users WHERE id = $1 AND is_bot AND owner_id = $2) AS "owned!""#,
+            bot_id,
+            owner_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.owned)
+    }
+
+    pub async fn is_bot(&self, user_id: Uuid) -> Result<bool> {
+        let row = sqlx::query!(
+            r#"SELECT EXISTS (SELECT 1 FROM users WHERE id = $1 AND is_bot) AS "bot!""#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.bot)
+    }
+
+    pub async fn create_api_key(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        key_prefix: &str,
+        key_hash: &str,
+        scope: &str,
+    ) -> Result<ApiKey> {
+        let key = sqlx::query_as!(
+            ApiKey,
+            "INSERT INTO api_keys (user_id, name, key_prefix, key_hash, scope) VALUES ($1, $2, $3, $4, $5)
+             RETURNING id, name, key_prefix, scope, created_at, last_used_at, revoked_at",
+            user_id,
+            name,
+            key_prefix,
+            key_hash,
+            scope
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(key)
+    }
+
+    pub async fn get_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKey>> {
+        let keys = sqlx::query_as!(
+            ApiKey,
+            "SELECT id, name, key_prefix, scope, created_at, last_used_at, revoked_at
+             FROM api_keys WHERE user_id = $1 ORDER BY created_at",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(keys)
+    }
+
+    /// Returns `false` if the key does not exist, is not `user_id`'s or was
+    /// already revoked.
+    pub async fn revoke_api_key(&self, user_id: Uuid, key_id: Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE api_keys SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+            key_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Looks up a live API key by hash and marks it as used.
+    pub async fn use_api_key(&self, key_hash: &str) -> Result<Option<ApiKeyUser>> {
+        let key = sqlx::query_as!(
+            ApiKeyUser,
+            r#"UPDATE api_keys k SET last_used_at = NOW()
+               FROM users u
+               WHERE u.id = k.user_id AND k.key_hash = $1 AND k.revoked_at IS NULL
+               RETURNING k.id AS "key_id", k.user_id, k.scope, u.is_bot"#,
+            key_hash
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(key)
+    }
+
+    /// Finished-game records of every bot, best first. Bot games never touch
+    /// the counters on `users`, so these come from `games`.
+    pub async fn get_all_bot_stats(&self) -> Result<Vec<BotStats>> {
+        let stats = sqlx::query_as!(
+            BotStats,
+            r#"SELECT u.id, u.username, u.owner_id,
+                      COUNT(g.id) AS "games_played!",
+                      COUNT(g.id) FILTER (WHERE g.winner_id = u.id) AS "games_won!"
+               FROM users u
+               LEFT JOIN games g ON g.bot AND g.status = 'finished' AND u.id IN (g.player_x_id, g.player_o_id)
+               WHERE u.is_bot
+               GROUP BY u.id
+               ORDER BY COUNT(g.id) FILTER (WHERE g.winner_id = u.id) DESC, COUNT(g.id)"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(stats)
+    }
+
+    /// Games played and won by the user against or as a bot.
+    pub async fn get_user_bot_stats(&self, user_id: Uuid) -> Result<(i64, i64)> {
+        let stats = sqlx::query!(
+            r#"SELECT COUNT(*) AS "played!", COUNT(*) FILTER (WHERE winner_id = $1) AS "won!"
+               FROM games
+               WHERE bot AND status = 'finished' AND (player_x_id = $1 OR player_o_id = $1)"#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok((stats.played, stats.won))
+    }
+}
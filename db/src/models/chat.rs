@@ -0,0 +1,55 @@
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use crate::Db;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub message: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl Db {
+    pub async fn save_chat_message(&self, room_id: Uuid, game_id: Option<Uuid>, user_id: Uuid, message: &str) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO game_chat (room_id, game_id, user_id, message) VALUES ($1, $2, $3, $4)",
+            room_id,
+            game_id,
+            user_id,
+            message
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the chat of a game, including messages sent in its room before
+    /// the game record was created.
+    pub async fn get_game_chat(&self, game_id: Uuid) -> Result<Vec<ChatRecord>> {
+        let rows = sqlx::query_as!(
+            ChatRecord,
+            "SELECT c.id, c.user_id, c.message, c.created_at FROM game_chat c
+             JOIN games g ON g.room_id = c.room_id
+             WHERE g.id = $1 AND (c.game_id IS NULL OR c.game_id = $1)
+             ORDER BY c.created_at",
+            game_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn get_game_players(&self, game_id: Uuid) -> Result<Option<(Option<Uuid>, Option<Uuid>)>> {
+        let row = sqlx::query!("SELECT player_x_id, player_o_id FROM games WHERE id = $1", game_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| (r.player_x_id, r.player_o_id)))
+    }
+}
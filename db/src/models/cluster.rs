@@ -0,0 +1,117 @@
+use std::time::Duration;
+use uuid::Uuid;
+use anyhow::Result;
+use sqlx::postgres::PgListener;
+
+use crate::Db;
+use crate::models::rooms::RoomCheckpoint;
+
+impl Db {
+    pub async fn heartbeat(&self, instance_id: Uuid) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO instances (id) VALUES ($1)
+             ON CONFLICT (id) DO UPDATE SET heartbeat_at = NOW()",
+            instance_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Deregisters an instance so its rooms can be taken over straight away.
+    pub async fn remove_instance(&self, instance_id: Uuid) -> Result<()> {
+        sqlx::query!("DELETE FROM instances WHERE id = $1", instance_id)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query!("DELETE FROM user_presence WHERE instance_id = $1", instance_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Instances that sent a heartbeat within `stale_after`.
+    pub async fn get_live_instances(&self, stale_after: Duration) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query!(
+            "SELECT id FROM instances WHERE heartbeat_at > NOW() - make_interval(secs => $1)",
+            stale_after.as_secs_f64()
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.id).collect())
+    }
+
+    /// The live instance that owns `room_id`, if any.
+    pub async fn get_room_owner(&self, room_id: Uuid, stale_after: Duration) -> Result<Option<Uuid>> {
+        let row = sqlx::query!(
+            "SELECT i.id FROM room_checkpoints rc
+             JOIN instances i ON i.id = rc.instance_id
+             WHERE rc.room_id = $1 AND i.heartbeat_at > NOW() - make_interval(secs => $2)",
+            room_id,
+            stale_after.as_secs_f64()
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| r.id))
+    }
+
+    /// Every room owned by a live instance, with its owner.
+    pub async fn get_live_rooms(&self, stale_after: Duration) -> Result<Vec<(Uuid, Uuid)>> {
+        let rows = sqlx::query!(
+            "SELECT rc.room_id, i.id AS instance_id FROM room_checkpoints rc
+             JOIN instances i ON i.id = rc.instance_id
+             WHERE i.heartbeat_at > NOW() - make_interval(secs => $1)",
+            stale_after.as_secs_f64()
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| (r.room_id, r.instance_id)).collect())
+    }
+
+    /// Takes over every room whose owner has stopped sending heartbeats and
+    /// returns their checkpoints. With `include_own`, rooms already recorded
+    /// against `instance_id` are returned too, for restarts that keep their id.
+    pub async fn claim_orphaned_rooms(
+        &self,
+        instance_id: Uuid,
+        stale_after: Duration,
+        include_own: bool,
+    ) -> Result<Vec<RoomCheckpoint>> {
+        let rows = sqlx::query_as!(
+            RoomCheckpoint,
+            "UPDATE room_checkpoints rc SET instance_id = $1, updated_at = NOW()
+             WHERE ($3 OR rc.instance_id IS DISTINCT FROM $1)
+               AND NOT EXISTS (
+                   SELECT 1 FROM instances i
+                   WHERE i.id = rc.instance_id AND i.id <> $1
+                     AND i.heartbeat_at > NOW() - make_interval(secs => $2)
+               )
+             RETURNING rc.room_id, rc.state",
+            instance_id,
+            stale_after.as_secs_f64(),
+            include_own
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn notify(&self, channel: &str, payload: &str) -> Result<()> {
+        sqlx::query!("SELECT pg_notify($1, $2)", channel, payload)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn listen(&self, channel: &str) -> Result<PgListener> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(channel).await?;
+        Ok(listener)
+    }
+}
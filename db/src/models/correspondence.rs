@@ -0,0 +1,136 @@
+use uuid::Uuid;
+use anyhow::Result;
+
+use crate::Db;
+use crate::models::games::{Game, GameRow, PlayerSymbol};
+
+pub struct CreateCorrespondenceGame {
+    pub creator_id: Uuid,
+    pub invited_id: Option<Uuid>,
+    pub days_per_move: i32,
+    pub options: serde_json::Value,
+}
+
+pub struct StartCorrespondenceGame {
+    pub game_id: Uuid,
+    pub player_x_id: Uuid,
+    pub player_o_id: Uuid,
+    pub first_player_id: Uuid,
+}
+
+impl Db {
+    pub async fn create_correspondence_game(&self, req: CreateCorrespondenceGame) -> Result<Uuid> {
+        let row = sqlx::query!(
+            "INSERT INTO games (room_id, mode, status, creator_id, invited_id, days_per_move, options)
+             VALUES ($1, 'correspondence', 'waiting', $2, $3, $4, $5)
+             RETURNING id",
+            Uuid::new_v4(),
+            req.creator_id,
+            req.invited_id,
+            req.days_per_move,
+            req.options
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.id)
+    }
+
+    /// Seats both players on a waiting correspondence game and starts the
+    /// first move's clock. Returns `false` if someone else got there first.
+    pub async fn start_correspondence_game(&self, req: StartCorrespondenceGame) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE games
+             SET player_x_id = $2, player_o_id = $3, first_player_id = $4, status = 'active',
+                 board_state = $5, started_at = NOW(),
+                 bot = EXISTS (SELECT 1 FROM users WHERE id IN ($2, $3) AND is_bot),
+                 move_deadline = NOW() + make_interval(days => days_per_move)
+             WHERE id = $1 AND mode = 'correspondence' AND status = 'waiting'",
+            req.game_id,
+            req.player_x_id,
+            req.player_o_id,
+            req.first_player_id,
+            serde_json::to_value(vec![None::<PlayerSymbol>; 9])?
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Stores a move if the game is still at `expected_moves` and the mover's
+    /// clock has not run out. `result` is `Some(winner_id)` when the move
+    /// ends the game, which is then finished and scored in the same
+    /// transaction. Returns `false` when the update lost a race.
+    pub async fn apply_correspondence_move(
+        &self,
+        game_id: Uuid,
+        expected_moves: i32,
+        board_state: &[Option<PlayerSymbol>],
+        result: Option<Option<Uuid>>,
+    ) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let updated = sqlx::query!(
+            "UPDATE games
+             SET board_state = $3, moves_count = moves_count + 1,
+                 move_deadline = CASE WHEN $4 THEN move_deadline ELSE NOW() + make_interval(days => days_per_move) END,
+                 status = CASE WHEN $4 THEN 'finished' ELSE status END,
+                 winner_id = $5,
+                 finished_at = CASE WHEN $4 THEN NOW() END
+             WHERE id = $1 AND mode = 'correspondence' AND status = 'active'
+               AND moves_count = $2 AND move_deadline > NOW()",
+            game_id,
+            expected_moves,
+            serde_json::to_value(board_state)?,
+            result.is_some(),
+            result.flatten()
+        )
+        .execute(&mut *tx)
+        .await?;
+        if updated.rows_affected() == 0 {
+            return Ok(false);
+        }
+        if let Some(winner_id) = result {
+            Self::record_result(&mut tx, game_id, winner_id).await?;
+        }
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    pub async fn get_expired_correspondence_games(&self) -> Result<Vec<Game>> {
+        let rows = sqlx::query_as!(
+            GameRow,
+            "SELECT id, room_id, player_x_id, player_o_id, winner_id, board_state, moves_count, started_at,
+                    finished_at, status, first_player_id, mode, creator_id, invited_id, days_per_move,
+                    move_deadline, options
+             FROM games
+             WHERE mode = 'correspondence' AND status = 'active' AND move_deadline <= NOW()"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Game::from).collect())
+    }
+
+    /// Finishes a correspondence game whose clock ran out, awarding it to
+    /// `winner_id`. Does nothing if a move landed in the meantime.
+    pub async fn finish_expired_game(&self, game_id: Uuid, winner_id: Option<Uuid>) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query!(
+            "UPDATE games SET winner_id = $2, finished_at = NOW(), status = 'finished'
+             WHERE id = $1 AND mode = 'correspondence' AND status = 'active' AND move_deadline <= NOW()",
+            game_id,
+            winner_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        Self::record_result(&mut tx, game_id, winner_id).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+}
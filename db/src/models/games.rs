@@ -2,6 +2,7 @@ use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
+use sqlx::PgConnection;
 use num_traits::cast::ToPrimitive;
 
 use crate::Db;
@@ -24,6 +25,59 @@ pub struct Game {
     pub started_at: Option<DateTime<Utc>>,
     pub finished_at: Option<DateTime<Utc>>,
     pub status: String,
+    pub first_player_id: Option<Uuid>,
+    pub mode: String,
+    pub creator_id: Option<Uuid>,
+    pub invited_id: Option<Uuid>,
+    pub days_per_move: Option<i32>,
+    pub move_deadline: Option<DateTime<Utc>>,
+    pub options: Option<serde_json::Value>,
+}
+
+pub(crate) struct GameRow {
+    pub(crate) id: Uuid,
+    pub(crate) room_id: Uuid,
+    pub(crate) player_x_id: Option<Uuid>,
+    pub(crate) player_o_id: Option<Uuid>,
+    pub(crate) winner_id: Option<Uuid>,
+    pub(crate) board_state: Option<serde_json::Value>,
+    pub(crate) moves_count: Option<i32>,
+    pub(crate) started_at: Option<DateTime<Utc>>,
+    pub(crate) finished_at: Option<DateTime<Utc>>,
+    pub(crate) status: Option<String>,
+    pub(crate) first_player_id: Option<Uuid>,
+    pub(crate) mode: Option<String>,
+    pub(crate) creator_id: Option<Uuid>,
+    pub(crate) invited_id: Option<Uuid>,
+    pub(crate) days_per_move: Option<i32>,
+    pub(crate) move_deadline: Option<DateTime<Utc>>,
+    pub(crate) options: Option<serde_json::Value>,
+}
+
+impl From<GameRow> for Game {
+    fn from(row: GameRow) -> Self {
+        Game {
+            id: row.id,
+            room_id: row.room_id,
+            player_x_id: row.player_x_id,
+            player_o_id: row.player_o_id,
+            winner_id: row.winner_id,
+            board_state: row.board_state
+                .and_then(|b| serde_json::from_value(b).ok())
+                .unwrap_or_else(|| vec![None; 9]),
+            moves_count: row.moves_count.unwrap_or(0),
+            started_at: row.started_at,
+            finished_at: row.finished_at,
+            status: row.status.unwrap_or_default(),
+            first_player_id: row.first_player_id,
+            mode: row.mode.unwrap_or_default(),
+            creator_id: row.creator_id,
+            invited_id: row.invited_id,
+            days_per_move: row.days_per_move,
+            move_deadline: row.move_deadline,
+            options: row.options,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -31,6 +85,7 @@ pub struct CreateGameRequest {
     pub room_id: Uuid,
     pub player_x_id: Option<Uuid>,
     pub player_o_id: Option<Uuid>,
+    pub first_player_id: Option<Uuid>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -42,10 +97,13 @@ impl Db {
     pub async fn create_game(&self, req: CreateGameRequest) -> Result<CreateGameResponse> {
         let game = sqlx::query_as!(
             CreateGameResponse,
-            "INSERT INTO games (room_id, player_x_id, player_o_id) VALUES ($1, $2, $3) RETURNING id",
+            "INSERT INTO games (room_id, player_x_id, player_o_id, first_player_id, bot)
+             VALUES ($1, $2, $3, $4, EXISTS (SELECT 1 FROM users WHERE id IN ($2, $3) AND is_bot))
+             RETURNING id",
             req.room_id,
             req.player_x_id,
-            req.player_o_id
+            req.player_o_id,
+            req.first_player_id
         )
         .fetch_one(&self.pool)
         .await?;
@@ -53,76 +111,147 @@ impl Db {
         Ok(game)
     }
 
+    pub async fn get_game(&self, game_id: Uuid) -> Result<Option<Game>> {
+        let row = sqlx::query_as!(
+            GameRow,
+            "SELECT id, room_id, player_x_id, player_o_id, winner_id, board_state, moves_count, started_at,
+                    finished_at, status, first_player_id, mode, creator_id, invited_id, days_per_move,
+                    move_deadline, options
+             FROM games WHERE id = $1",
+            game_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(Game::from))
+    }
+
+    /// Records the result of a game in progress and updates the players'
+    /// statistics, in one transaction. Returns `false`, changing nothing, if
+    /// the game had already ended.
     pub async fn finish_game(
         &self,
         game_id: Uuid,
         winner_id: Option<Uuid>,
         board_state: &[Option<PlayerSymbol>],
         moves_count: i32,
-    ) -> Result<()> {
+    ) -> Result<bool> {
         let board_json = serde_json::to_value(board_state)?;
 
-        sqlx::query!(
-            "UPDATE games SET winner_id = $1, board_state = $2, moves_count = $3, finished_at = NOW(), status = 'finished' WHERE id = $4",
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query!(
+            "UPDATE games SET winner_id = $1, board_state = $2, moves_count = $3, finished_at = NOW(), status = 'finished'
+             WHERE id = $4 AND status = 'active'",
             winner_id,
             board_json,
             moves_count,
             game_id
         )
-        .execute(&self.pool)
+        .execute(&mut *tx)
         .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        Self::record_result(&mut tx, game_id, winner_id).await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Updates both players' statistics for a game that has just finished.
+    /// Bot games are left out; they are counted from `games` instead, and so
+    /// are deleted accounts, whose statistics were cleared. Runs on `conn` so
+    /// it commits together with the result.
+    pub(crate) async fn record_result(conn: &mut PgConnection, game_id: Uuid, winner_id: Option<Uuid>) -> Result<()> {
+        let Some(game) = sqlx::query!("SELECT player_x_id, player_o_id, bot FROM games WHERE id = $1", game_id)
+            .fetch_optional(&mut *conn)
+            .await?
+        else {
+            return Ok(());
+        };
+        if game.bot {
+            return Ok(());
+        }
 
         if let Some(winner) = winner_id {
-            sqlx::query!("UPDATE users SET games_played = games_played + 1, games_won = games_won + 1 WHERE id = $1", winner)
-                .execute(&self.pool)
+            sqlx::query!("UPDATE users SET games_played = games_played + 1, games_won = games_won + 1 WHERE id = $1 AND deleted_at IS NULL", winner)
+                .execute(&mut *conn)
                 .await?;
 
             sqlx::query!(
-                "UPDATE users SET win_rate = ROUND((games_won::decimal / games_played) * 100, 2) WHERE id = $1",
+                "UPDATE users SET win_rate = ROUND((games_won::decimal / games_played) * 100, 2) WHERE id = $1 AND deleted_at IS NULL",
                 winner
             )
-            .execute(&self.pool)
+            .execute(&mut *conn)
             .await?;
         }
 
-        if let Some(game) = sqlx::query!("SELECT player_x_id, player_o_id FROM games WHERE id = $1", game_id)
-            .fetch_optional(&self.pool)
-            .await?
-        {
-            if let Some(player_x) = game.player_x_id {
-                sqlx::query!("UPDATE users SET games_played = games_played + 1 WHERE id = $1 AND id != $2", player_x, winner_id.unwrap_or(Uuid::nil()))
-                    .execute(&self.pool)
-                    .await?;
-
-                if winner_id.is_none() || winner_id != Some(player_x) {
-                    sqlx::query!(
-                        "UPDATE users SET win_rate = ROUND((games_won::decimal / games_played) * 100, 2) WHERE id = $1",
-                        player_x
-                    )
-                    .execute(&self.pool)
-                    .await?;
-                }
+        if let Some(player_x) = game.player_x_id {
+            sqlx::query!("UPDATE users SET games_played = games_played + 1 WHERE id = $1 AND id != $2 AND deleted_at IS NULL", player_x, winner_id.unwrap_or(Uuid::nil()))
+                .execute(&mut *conn)
+                .await?;
+
+            if winner_id.is_none() || winner_id != Some(player_x) {
+                sqlx::query!(
+                    "UPDATE users SET win_rate = ROUND((games_won::decimal / games_played) * 100, 2) WHERE id = $1 AND deleted_at IS NULL",
+                    player_x
+                )
+                .execute(&mut *conn)
+                .await?;
             }
+        }
+
+        if let Some(player_o) = game.player_o_id {
+            sqlx::query!("UPDATE users SET games_played = games_played + 1 WHERE id = $1 AND id != $2 AND deleted_at IS NULL", player_o, winner_id.unwrap_or(Uuid::nil()))
+                .execute(&mut *conn)
+                .await?;
 
-            if let Some(player_o) = game.player_o_id {
-                sqlx::query!("UPDATE users SET games_played = games_played + 1 WHERE id = $1 AND id != $2", player_o, winner_id.unwrap_or(Uuid::nil()))
-                    .execute(&self.pool)
-                    .await?;
-
-                if winner_id.is_none() || winner_id != Some(player_o) {
-                    sqlx::query!(
-                        "UPDATE users SET win_rate = ROUND((games_won::decimal / games_played) * 100, 2) WHERE id = $1",
-                        player_o
-                    )
-                    .execute(&self.pool)
-                    .await?;
-                }
+            if winner_id.is_none() || winner_id != Some(player_o) {
+                sqlx::query!(
+                    "UPDATE users SET win_rate = ROUND((games_won::decimal / games_played) * 100, 2) WHERE id = $1 AND deleted_at IS NULL",
+                    player_o
+                )
+                .execute(&mut *conn)
+                .await?;
             }
         }
 
         Ok(())
     }
 
+    pub async fn get_last_first_player(&self, player_a: Uuid, player_b: Uuid) -> Result<Option<Uuid>> {
+        let row = sqlx::query!(
+            "SELECT first_player_id FROM games
+             WHERE first_player_id IS NOT NULL
+               AND ((player_x_id = $1 AND player_o_id = $2) OR (player_x_id = $2 AND player_o_id = $1))
+             ORDER BY started_at DESC
+             LIMIT 1",
+            player_a,
+            player_b
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.and_then(|r| r.first_player_id))
+    }
+
+    pub async fn get_user_first_move_stats(&self, user_id: Uuid) -> Result<(i64, i64, i64, i64)> {
+        let stats = sqlx::query!(
+            r#"SELECT
+                COUNT(*) FILTER (WHERE first_player_id = $1) AS "played_first!",
+                COUNT(*) FILTER (WHERE first_player_id = $1 AND winner_id = $1) AS "won_first!",
+                COUNT(*) FILTER (WHERE first_player_id != $1) AS "played_second!",
+                COUNT(*) FILTER (WHERE first_player_id != $1 AND winner_id = $1) AS "won_second!"
+               FROM games
+               WHERE status = 'finished' AND NOT bot AND (player_x_id = $1 OR player_o_id = $1)"#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((stats.played_first, stats.won_first, stats.played_second, stats.won_second))
+    }
+
     pub async fn get_user_stats(&self, user_id: Uuid) -> Result<(i32, i32, f32)> {
         let stats = sqlx::query!(
             "SELECT games_played, games_won, win_rate FROM users WHERE id = $1",
@@ -138,9 +267,10 @@ impl Db {
         ))
     }
 
-    pub async fn get_all_user_stats(&self) -> Result<Vec<(Uuid, i32, i32, f32)>> {
+    pub async fn get_all_user_stats(&self) -> Result<Vec<(Uuid, String, Option<String>, i32, i32, f32)>> {
         let rows = sqlx::query!(
-            "SELECT id, games_played, games_won, win_rate FROM users ORDER BY win_rate DESC"
+            "SELECT id, username, display_name, games_played, games_won, win_rate FROM users
+             WHERE NOT is_guest AND NOT is_bot AND deleted_at IS NULL ORDER BY win_rate DESC"
         )
         .fetch_all(&self.pool)
         .await?;
@@ -149,6 +279,8 @@ impl Db {
         for row in rows {
             stats.push((
                 row.id,
+                row.username,
+                row.display_name,
                 row.games_played.unwrap_or(0),
                 row.games_won.unwrap_or(0),
                 row.win_rate.unwrap_or(sqlx::types::BigDecimal::from(0)).to_f32().unwrap_or(0.0)
@@ -0,0 +1,141 @@
+use uuid::Uuid;
+use anyhow::Result;
+
+use crate::Db;
+
+/// How long a user has to come back from the provider.
+pub const LOGIN_TIMEOUT_MINUTES: i32 = 10;
+
+pub struct OidcLogin {
+    pub code_verifier: String,
+    pub nonce: String,
+    pub link_user_id: Option<Uuid>,
+}
+
+/// The account an external identity signs in to.
+pub struct IdentityUser {
+    pub user_id: Uuid,
+    pub role: String,
+}
+
+pub enum LinkOutcome {
+    Linked,
+    /// The identity already belongs to another account.
+    LinkedElsewhere,
+}
+
+impl Db {
+    pub async fn create_oidc_login(
+        &self,
+        state: &str,
+        code_verifier: &str,
+        nonce: &str,
+        link_user_id: Option<Uuid>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM oidc_logins WHERE created_at < NOW() - make_interval(mins => $1)",
+            LOGIN_TIMEOUT_MINUTES
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO oidc_logins (state, code_verifier, nonce, link_user_id) VALUES ($1, $2, $3, $4)",
+            state,
+            code_verifier,
+            nonce,
+            link_user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes and returns the sign-in started with `state`, unless it has
+    /// timed out. Each state works once.
+    pub async fn take_oidc_login(&self, state: &str) -> Result<Option<OidcLogin>> {
+        let login = sqlx::query_as!(
+            OidcLogin,
+            "DELETE FROM oidc_logins
+             WHERE state = $1 AND created_at > NOW() - make_interval(mins => $2)
+             RETURNING code_verifier, nonce, link_user_id",
+            state,
+            LOGIN_TIMEOUT_MINUTES
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(login)
+    }
+
+    /// Finds the account linked to an identity and records the sign-in.
+    pub async fn get_identity_user(&self, issuer: &str, subject: &str) -> Result<Option<IdentityUser>> {
+        let user = sqlx::query_as!(
+            IdentityUser,
+            "UPDATE user_identities i SET last_login_at = NOW()
+             FROM users u
+             WHERE u.id = i.user_id AND i.issuer = $1 AND i.subject = $2
+             RETURNING i.user_id, u.role",
+            issuer,
+            subject
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn link_identity(&self, user_id: Uuid, issuer: &str, subject: &str, email: Option<&str>) -> Result<LinkOutcome> {
+        sqlx::query!(
+            "INSERT INTO user_identities (user_id, issuer, subject, email) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (issuer, subject) DO NOTHING",
+            user_id,
+            issuer,
+            subject,
+            email
+        )
+        .execute(&self.pool)
+        .await?;
+        let owner = sqlx::query!(
+            "SELECT user_id FROM user_identities WHERE issuer = $1 AND subject = $2",
+            issuer,
+            subject
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(if owner.user_id == user_id { LinkOutcome::Linked } else { LinkOutcome::LinkedElsewhere })
+    }
+
+    /// Creates a passwordless account for an external identity. Returns
+    /// `None` if the username is taken.
+    pub async fn create_identity_user(
+        &self,
+        username: &str,
+        issuer: &str,
+        subject: &str,
+        email: Option<&str>,
+    ) -> Result<Option<Uuid>> {
+        let mut tx = self.pool.begin().await?;
+        let Some(user) = sqlx::query!(
+            "INSERT INTO users (username) VALUES ($1) ON CONFLICT (username) DO NOTHING RETURNING id",
+            username
+        )
+        .fetch_optional(&mut *tx)
+        .await? else {
+            return Ok(None);
+        };
+        sqlx::query!(
+            "INSERT INTO user_identities (user_id, issuer, subject, email, last_login_at) VALUES ($1, $2, $3, $4, NOW())",
+            user.id,
+            issuer,
+            subject,
+            email
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(Some(user.id))
+    }
+}
@@ -0,0 +1,98 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use anyhow::Result;
+
+use crate::Db;
+
+pub struct RecordFailedLogin<'a> {
+    pub username: &'a str,
+    pub user_id: Option<Uuid>,
+    pub ip: Option<&'a str>,
+    /// `unknown_user`, `no_password`, `wrong_password` or `locked`.
+    pub reason: &'a str,
+}
+
+impl Db {
+    /// The latest lockout among `keys` that has not run out yet.
+    pub async fn get_login_lockout(&self, keys: &[String]) -> Result<Option<DateTime<Utc>>> {
+        let row = sqlx::query!(
+            "SELECT MAX(locked_until) AS locked_until FROM login_throttles WHERE key = ANY($1) AND locked_until > NOW()",
+            keys
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.locked_until)
+    }
+
+    /// Counts a sign-in attempt against `key` before its password is
+    /// checked, and returns the consecutive attempts so far. `None` if the
+    /// key is locked out, in which case nothing is counted. Attempts older
+    /// than `window_secs` are forgotten. Past `free_attempts` the key is
+    /// locked right away, for `lockouts[n - 1]` seconds on the `n`th attempt
+    /// over (the last entry beyond that), so parallel attempts cannot get
+    /// past a lockout that has not been written yet.
+    pub async fn reserve_login_attempt(&self, key: &str, window_secs: f64, free_attempts: i32, lockouts: &[f64]) -> Result<Option<i32>> {
+        let row = sqlx::query!(
+            "INSERT INTO login_throttles AS t (key, failures, last_failure_at, locked_until)
+             VALUES ($1, 1, NOW(), CASE WHEN $3 < 1 THEN NOW() + make_interval(secs => ($4::float8[])[1]) END)
+             ON CONFLICT (key) DO UPDATE SET
+                 failures = CASE
+                     WHEN t.last_failure_at < NOW() - make_interval(secs => $2) THEN 1
+                     ELSE t.failures + 1
+                 END,
+                 last_failure_at = NOW(),
+                 locked_until = CASE
+                     WHEN (CASE WHEN t.last_failure_at < NOW() - make_interval(secs => $2) THEN 1 ELSE t.failures + 1 END) > $3
+                     THEN NOW() + make_interval(secs => ($4::float8[])[LEAST(
+                         (CASE WHEN t.last_failure_at < NOW() - make_interval(secs => $2) THEN 1 ELSE t.failures + 1 END) - $3,
+                         cardinality($4::float8[])
+                     )])
+                 END
+             WHERE t.locked_until IS NULL OR t.locked_until <= NOW()
+             RETURNING failures",
+            key,
+            window_secs,
+            free_attempts,
+            lockouts
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| r.failures))
+    }
+
+    /// Takes back an attempt reserved with `reserve_login_attempt` that
+    /// turned out to be a successful sign-in.
+    pub async fn release_login_attempt(&self, key: &str) -> Result<()> {
+        sqlx::query!("UPDATE login_throttles SET failures = GREATEST(failures - 1, 0) WHERE key = $1", key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_login_failures(&self, key: &str) -> Result<()> {
+        sqlx::query!("DELETE FROM login_throttles WHERE key = $1", key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Names and addresses too long for their columns are cut short, as a
+    /// sign-in may send anything as its username.
+    pub async fn record_failed_login(&self, req: RecordFailedLogin<'_>) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO failed_logins (username, user_id, ip, reason) VALUES (LEFT($1, 255), $2, LEFT($3, 64), $4)",
+            req.username,
+            req.user_id,
+            req.ip,
+            req.reason
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
@@ -1,2 +1,14 @@
 pub mod users;
 pub mod games;
+pub mod chat;
+pub mod audit;
+pub mod correspondence;
+pub mod rooms;
+pub mod cluster;
+pub mod tokens;
+pub mod bots;
+pub mod identities;
+pub mod logins;
+pub mod profiles;
+pub mod relationships;
+pub mod presence;
@@ -0,0 +1,47 @@
+use uuid::Uuid;
+use anyhow::Result;
+
+use crate::Db;
+
+impl Db {
+    pub async fn set_presence(&self, user_id: Uuid, room_id: Uuid, instance_id: Uuid, spectatable: bool) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO user_presence (user_id, room_id, instance_id, spectatable) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (user_id, room_id) DO UPDATE SET instance_id = $3, spectatable = $4",
+            user_id,
+            room_id,
+            instance_id,
+            spectatable
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_presence(&self, user_id: Uuid, room_id: Uuid) -> Result<()> {
+        sqlx::query!("DELETE FROM user_presence WHERE user_id = $1 AND room_id = $2", user_id, room_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// The rooms any of `user_ids` is in.
+    pub async fn get_presence_rooms(&self, user_ids: &[Uuid]) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query!("SELECT DISTINCT room_id FROM user_presence WHERE user_id = ANY($1)", user_ids)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|r| r.room_id).collect())
+    }
+
+    /// Forgets everyone in the room, when it closes or changes owner.
+    pub async fn clear_room_presence(&self, room_id: Uuid) -> Result<()> {
+        sqlx::query!("DELETE FROM user_presence WHERE room_id = $1", room_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
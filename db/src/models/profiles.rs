@@ -0,0 +1,117 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+use anyhow::Result;
+
+use crate::Db;
+
+pub struct Profile {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub country: Option<String>,
+    pub avatar_id: Option<Uuid>,
+    pub is_guest: bool,
+    pub is_bot: bool,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+pub struct UpdateProfile<'a> {
+    pub display_name: Option<&'a str>,
+    pub bio: Option<&'a str>,
+    pub country: Option<&'a str>,
+}
+
+/// A finished game as seen by one of its players.
+#[derive(Serialize)]
+pub struct RecentGame {
+    pub id: Uuid,
+    pub mode: String,
+    pub opponent_id: Option<Uuid>,
+    pub opponent_username: Option<String>,
+    pub winner_id: Option<Uuid>,
+    pub moves_count: i32,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl Db {
+    /// Deleted accounts have no profile.
+    pub async fn get_profile(&self, user_id: Uuid) -> Result<Option<Profile>> {
+        let profile = sqlx::query_as!(
+            Profile,
+            r#"SELECT id, username, display_name, bio, country, avatar_id, is_guest, is_bot, created_at
+               FROM users WHERE id = $1 AND deleted_at IS NULL"#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(profile)
+    }
+
+    pub async fn get_profile_by_username(&self, username: &str) -> Result<Option<Profile>> {
+        let profile = sqlx::query_as!(
+            Profile,
+            r#"SELECT id, username, display_name, bio, country, avatar_id, is_guest, is_bot, created_at
+               FROM users WHERE username = $1 AND deleted_at IS NULL"#,
+            username
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(profile)
+    }
+
+    /// Replaces all profile fields; `None` clears one.
+    pub async fn update_profile(&self, user_id: Uuid, req: UpdateProfile<'_>) -> Result<()> {
+        sqlx::query!(
+            "UPDATE users SET display_name = $2, bio = $3, country = $4, updated_at = NOW() WHERE id = $1",
+            user_id,
+            req.display_name,
+            req.bio,
+            req.country
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Sets or clears the avatar and returns the one it replaced, whose files
+    /// can then be removed.
+    pub async fn set_avatar(&self, user_id: Uuid, avatar_id: Option<Uuid>) -> Result<Option<Uuid>> {
+        let row = sqlx::query!(
+            "UPDATE users u SET avatar_id = $2, updated_at = NOW()
+             FROM (SELECT avatar_id FROM users WHERE id = $1 FOR UPDATE) old
+             WHERE u.id = $1
+             RETURNING old.avatar_id",
+            user_id,
+            avatar_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.and_then(|r| r.avatar_id))
+    }
+
+    /// The user's last `limit` finished games, newest first.
+    pub async fn get_recent_games(&self, user_id: Uuid, limit: i64) -> Result<Vec<RecentGame>> {
+        let games = sqlx::query_as!(
+            RecentGame,
+            r#"SELECT g.id, COALESCE(g.mode, 'live') AS "mode!",
+                      o.id AS "opponent_id?", o.username AS "opponent_username?", g.winner_id, COALESCE(g.moves_count, 0) AS "moves_count!", g.finished_at
+               FROM games g
+               LEFT JOIN users o ON o.id = CASE WHEN g.player_x_id = $1 THEN g.player_o_id ELSE g.player_x_id END
+               WHERE g.status = 'finished' AND $1 IN (g.player_x_id, g.player_o_id)
+               ORDER BY g.finished_at DESC
+               LIMIT $2"#,
+            user_id,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(games)
+    }
+}
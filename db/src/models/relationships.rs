@@ -0,0 +1,416 @@
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+use anyhow::Result;
+use sqlx::PgConnection;
+
+use crate::Db;
+
+pub enum FriendRequestOutcome {
+    Sent,
+    /// The other user had already asked; the two are now friends.
+    Accepted,
+    AlreadyFriends,
+    Blocked,
+    NotFound,
+}
+
+/// Result of following or blocking someone.
+pub enum RelationshipOutcome {
+    Done,
+    Blocked,
+    NotFound,
+}
+
+pub struct Friend {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub avatar_id: Option<Uuid>,
+    pub since: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub online: bool,
+    /// A room the friend is in that allows spectators.
+    pub room_id: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct FriendRequest {
+    pub user_id: Uuid,
+    pub username: String,
+    /// `incoming` or `outgoing`.
+    pub direction: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The other end of a follow or block.
+#[derive(Serialize)]
+pub struct RelatedUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub since: DateTime<Utc>,
+}
+
+impl Db {
+    /// Locks both users, always in the same order, so that changes to the
+    /// relationships between them happen one at a time and a block cannot
+    /// slip in between checking for one and adding a friend or follow.
+    /// Returns `false` if `target_id` does not exist.
+    async fn lock_pair(conn: &mut PgConnection, user_id: Uuid, target_id: Uuid) -> Result<bool> {
+        let rows = sqlx::query!(
+            r#"SELECT id, deleted_at IS NULL AS "active!" FROM users WHERE id IN ($1, $2) ORDER BY id FOR NO KEY UPDATE"#,
+            user_id,
+            target_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(rows.iter().any(|r| r.id == target_id && r.active))
+    }
+
+    async fn blocked_between(conn: &mut PgConnection, user_id: Uuid, other: Uuid) -> Result<bool> {
+        let row = sqlx::query!(
+            r#"SELECT EXISTS (
+                 SELECT 1 FROM user_relationships
+                 WHERE kind = 'block' AND ((user_id = $1 AND target_id = $2) OR (user_id = $2 AND target_id = $1))
+             ) AS "blocked!""#,
+            user_id,
+            other
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        Ok(row.blocked)
+    }
+
+    /// The users among `others` that `user_id` has blocked or is blocked by.
+    pub async fn get_blocked_among(&self, user_id: Uuid, others: &[Uuid]) -> Result<Vec<Uuid>> {
+        let rows = sqlx::query!(
+            r#"SELECT DISTINCT CASE WHEN user_id = $1 THEN target_id ELSE user_id END AS "other!"
+               FROM user_relationships
+               WHERE kind = 'block'
+                 AND ((user_id = $1 AND target_id = ANY($2)) OR (target_id = $1 AND user_id = ANY($2)))"#,
+            user_id,
+            others
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.other).collect())
+    }
+
+    pub async fn is_blocked_between(&self, user_id: Uuid, other: Uuid) -> Result<bool> {
+        Ok(!self.get_blocked_among(user_id, &[other]).await?.is_empty())
+    }
+
+    /// Asks `target_id` to be friends, or accepts if they already asked.
+    pub async fn send_friend_request(&self, user_id: Uuid, target_id: Uuid) -> Result<FriendRequestOutcome> {
+        let mut tx = self.pool.begin().await?;
+        if !Self::lock_pair(&mut tx, user_id, target_id).await? {
+            return Ok(FriendRequestOutcome::NotFound);
+        }
+        if Self::blocked_between(&mut tx, user_id, target_id).await? {
+            return Ok(FriendRequestOutcome::Blocked);
+        }
+        let friends = sqlx::query!(
+            r#"SELECT EXISTS (
+                 SELECT 1 FROM user_relationships WHERE user_id = $1 AND target_id = $2 AND kind = 'friend'
+             ) AS "friends!""#,
+            user_id,
+            target_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if friends.friends {
+            return Ok(FriendRequestOutcome::AlreadyFriends);
+        }
+        let theirs = sqlx::query!(
+            "DELETE FROM user_relationships WHERE user_id = $2 AND target_id = $1 AND kind = 'friend_request'",
+            user_id,
+            target_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let outcome = if theirs.rows_affected() == 1 {
+            sqlx::query!(
+                "DELETE FROM user_relationships WHERE user_id = $1 AND target_id = $2 AND kind = 'friend_request'",
+                user_id,
+                target_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "INSERT INTO user_relationships (user_id, target_id, kind) VALUES ($1, $2, 'friend'), ($2, $1, 'friend')
+                 ON CONFLICT DO NOTHING",
+                user_id,
+                target_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            FriendRequestOutcome::Accepted
+        } else {
+            sqlx::query!(
+                "INSERT INTO user_relationships (user_id, target_id, kind) VALUES ($1, $2, 'friend_request')
+                 ON CONFLICT DO NOTHING",
+                user_id,
+                target_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            FriendRequestOutcome::Sent
+        };
+        tx.commit().await?;
+
+        Ok(outcome)
+    }
+
+    /// Accepts `requester_id`'s request. Returns `false` if there is none.
+    pub async fn accept_friend_request(&self, user_id: Uuid, requester_id: Uuid) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let request = sqlx::query!(
+            "DELETE FROM user_relationships WHERE user_id = $2 AND target_id = $1 AND kind = 'friend_request'",
+            user_id,
+            requester_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if request.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query!(
+            "INSERT INTO user_relationships (user_id, target_id, kind) VALUES ($1, $2, 'friend'), ($2, $1, 'friend')
+             ON CONFLICT DO NOTHING",
+            user_id,
+            requester_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Turns down `requester_id`'s request. Returns `false` if there is none.
+    pub async fn decline_friend_request(&self, user_id: Uuid, requester_id: Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM user_relationships WHERE user_id = $2 AND target_id = $1 AND kind = 'friend_request'",
+            user_id,
+            requester_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Ends a friendship, or withdraws a request sent to `other_id`. Returns
+    /// `false` if there was neither.
+    pub async fn remove_friend(&self, user_id: Uuid, other_id: Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM user_relationships
+             WHERE (kind = 'friend' AND ((user_id = $1 AND target_id = $2) OR (user_id = $2 AND target_id = $1)))
+                OR (kind = 'friend_request' AND user_id = $1 AND target_id = $2)",
+            user_id,
+            other_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn get_friend_requests(&self, user_id: Uuid) -> Result<Vec<FriendRequest>> {
+        let requests = sqlx::query_as!(
+            FriendRequest,
+            r#"SELECT u.id AS user_id, u.username,
+                      CASE WHEN r.user_id = $1 THEN 'outgoing' ELSE 'incoming' END AS "direction!",
+                      r.created_at
+               FROM user_relationships r
+               JOIN users u ON u.id = CASE WHEN r.user_id = $1 THEN r.target_id ELSE r.user_id END
+               WHERE r.kind = 'friend_request' AND $1 IN (r.user_id, r.target_id)
+               ORDER BY r.created_at DESC"#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(requests)
+    }
+
+    /// Friends with their presence. Presence rows of instances that have not
+    /// sent a heartbeat within `stale_after` are ignored.
+    pub async fn get_friends(&self, user_id: Uuid, stale_after: Duration) -> Result<Vec<Friend>> {
+        let friends = sqlx::query_as!(
+            Friend,
+            r#"SELECT u.id, u.username, u.display_name, u.avatar_id, r.created_at AS since, u.last_seen_at,
+                      p.room_id IS NOT NULL AS "online!",
+                      (SELECT sp.room_id FROM user_presence sp
+                       JOIN instances i ON i.id = sp.instance_id
+                       WHERE sp.user_id = u.id AND sp.spectatable
+                         AND i.heartbeat_at > NOW() - make_interval(secs => $2)
+                       ORDER BY sp.connected_at DESC
+                       LIMIT 1) AS room_id
+               FROM user_relationships r
+               JOIN users u ON u.id = r.target_id
+               LEFT JOIN LATERAL (
+                   SELECT lp.room_id FROM user_presence lp
+                   JOIN instances i ON i.id = lp.instance_id
+                   WHERE lp.user_id = u.id AND i.heartbeat_at > NOW() - make_interval(secs => $2)
+                   LIMIT 1
+               ) p ON TRUE
+               WHERE r.user_id = $1 AND r.kind = 'friend'
+               ORDER BY u.username"#,
+            user_id,
+            stale_after.as_secs_f64()
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(friends)
+    }
+
+    pub async fn follow(&self, user_id: Uuid, target_id: Uuid) -> Result<RelationshipOutcome> {
+        let mut tx = self.pool.begin().await?;
+        if !Self::lock_pair(&mut tx, user_id, target_id).await? {
+            return Ok(RelationshipOutcome::NotFound);
+        }
+        if Self::blocked_between(&mut tx, user_id, target_id).await? {
+            return Ok(RelationshipOutcome::Blocked);
+        }
+        sqlx::query!(
+            "INSERT INTO user_relationships (user_id, target_id, kind) VALUES ($1, $2, 'follow') ON CONFLICT DO NOTHING",
+            user_id,
+            target_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(RelationshipOutcome::Done)
+    }
+
+    pub async fn unfollow(&self, user_id: Uuid, target_id: Uuid) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM user_relationships WHERE user_id = $1 AND target_id = $2 AND kind = 'follow'",
+            user_id,
+            target_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Users that `user_id` has a `kind` relationship with, or that have one
+    /// with `user_id` when `incoming` is set.
+    pub async fn get_related_users(&self, user_id: Uuid, kind: &str, incoming: bool) -> Result<Vec<RelatedUser>> {
+        let users = sqlx::query_as!(
+            RelatedUser,
+            "SELECT u.id AS user_id, u.username, u.display_name, r.created_at AS since
+             FROM user_relationships r
+             JOIN users u ON u.id = CASE WHEN $3 THEN r.user_id ELSE r.target_id END
+             WHERE r.kind = $2 AND CASE WHEN $3 THEN r.target_id ELSE r.user_id END = $1
+             ORDER BY r.created_at DESC",
+            user_id,
+            kind,
+            incoming
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+
+    /// Blocks `target_id`, ending any friendship, pending request or follow
+    /// between the two in either direction.
+    pub async fn block(&self, user_id: Uuid, target_id: Uuid) -> Result<RelationshipOutcome> {
+        let mut tx = self.pool.begin().await?;
+        if !Self::lock_pair(&mut tx, user_id, target_id).await? {
+            return Ok(RelationshipOutcome::NotFound);
+        }
+        sqlx::query!(
+            "DELETE FROM user_relationships
+             WHERE kind <> 'block' AND ((user_id = $1 AND target_id = $2) OR (user_id = $2 AND target_id = $1))",
+            user_id,
+            target_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO user_relationships (user_id, target_id, kind) VALUES ($1, $2, 'block') ON CONFLICT DO NOTHING",
+            user_id,
+            target_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(RelationshipOutcome::Done)
+    }
+
+    pub async fn unblock(&self, user_id: Uuid, target_id: Uuid) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM user_relationships WHERE user_id = $1 AND target_id = $2 AND kind = 'block'",
+            user_id,
+            target_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "db-tests"))]
+mod tests {
+    use super::*;
+
+    async fn seed_user(db: &Db) -> Uuid {
+        let name = format!("seed{}", &Uuid::new_v4().simple().to_string()[..12]);
+        db.create_user(&name, &String::new()).await.unwrap().unwrap().id
+    }
+
+    async fn kinds_between(db: &Db, a: Uuid, b: Uuid) -> Vec<String> {
+        sqlx::query_scalar!(
+            "SELECT kind FROM user_relationships WHERE (user_id = $1 AND target_id = $2) OR (user_id = $2 AND target_id = $1) ORDER BY kind",
+            a,
+            b
+        )
+        .fetch_all(&db.pool)
+        .await
+        .unwrap()
+    }
+
+    async fn remove_users(db: &Db, ids: &[Uuid]) {
+        sqlx::query!("DELETE FROM users WHERE id = ANY($1)", ids).execute(&db.pool).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mutual_requests_at_once_make_friends() {
+        let db = Db::new().await.unwrap();
+        for _ in 0..10 {
+            let (a, b) = (seed_user(&db).await, seed_user(&db).await);
+            let (ab, ba) = tokio::join!(db.send_friend_request(a, b), db.send_friend_request(b, a));
+            ab.unwrap();
+            ba.unwrap();
+            let kinds = kinds_between(&db, a, b).await;
+            remove_users(&db, &[a, b]).await;
+            assert_eq!(kinds, ["friend", "friend"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn nothing_survives_a_concurrent_block() {
+        let db = Db::new().await.unwrap();
+        for _ in 0..10 {
+            let (a, b) = (seed_user(&db).await, seed_user(&db).await);
+            let (block, follow, request) = tokio::join!(db.block(a, b), db.follow(b, a), db.send_friend_request(b, a));
+            block.unwrap();
+            follow.unwrap();
+            request.unwrap();
+            let kinds = kinds_between(&db, a, b).await;
+            remove_users(&db, &[a, b]).await;
+            assert_eq!(kinds, ["block"]);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use uuid::Uuid;
+use anyhow::Result;
+
+use crate::Db;
+use crate::models::games::PlayerSymbol;
+
+pub struct RoomCheckpoint {
+    pub room_id: Uuid,
+    pub state: serde_json::Value,
+}
+
+impl Db {
+    /// Saves the state of a room owned by `instance_id`. Returns `false` if
+    /// another instance has taken the room over.
+    pub async fn save_room_checkpoint(&self, room_id: Uuid, instance_id: Uuid, state: &serde_json::Value) -> Result<bool> {
+        let result = sqlx::query!(
+            "INSERT INTO room_checkpoints (room_id, instance_id, state) VALUES ($1, $2, $3)
+             ON CONFLICT (room_id) DO UPDATE SET state = EXCLUDED.state, updated_at = NOW()
+             WHERE room_checkpoints.instance_id IS NULL OR room_checkpoints.instance_id = EXCLUDED.instance_id",
+            room_id,
+            instance_id,
+            state
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Deletes the checkpoint if `instance_id` still owns the room. Returns
+    /// `false` if it does not.
+    pub async fn delete_room_checkpoint(&self, room_id: Uuid, instance_id: Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM room_checkpoints WHERE room_id = $1 AND instance_id = $2",
+            room_id,
+            instance_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Marks live games that were active when the server stopped but have no
+    /// checkpoint to resume from as abandoned.
+    pub async fn abandon_orphaned_live_games(&self) -> Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE games SET status = 'abandoned', finished_at = NOW()
+             WHERE mode = 'live' AND status IN ('active', 'suspended')
+               AND room_id NOT IN (SELECT room_id FROM room_checkpoints)"
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn suspend_game(&self, game_id: Uuid) -> Result<()> {
+        sqlx::query!("UPDATE games SET status = 'suspended' WHERE id = $1 AND status = 'active'", game_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn resume_game(&self, game_id: Uuid) -> Result<()> {
+        sqlx::query!("UPDATE games SET status = 'active' WHERE id = $1 AND status = 'suspended'", game_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Ends a game without a result; player statistics are left untouched.
+    pub async fn abort_game(
+        &self,
+        game_id: Uuid,
+        board_state: &[Option<PlayerSymbol>],
+        moves_count: i32,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE games SET status = 'aborted', board_state = $2, moves_count = $3, finished_at = NOW() WHERE id = $1",
+            game_id,
+            serde_json::to_value(board_state)?,
+            moves_count
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Closes a game nobody finished, e.g. one whose room expired; player
+    /// statistics are left untouched.
+    pub async fn abandon_game(
+        &self,
+        game_id: Uuid,
+        board_state: &[Option<PlayerSymbol>],
+        moves_count: i32,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE games SET status = 'abandoned', board_state = $2, moves_count = $3, finished_at = NOW() WHERE id = $1",
+            game_id,
+            serde_json::to_value(board_state)?,
+            moves_count
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use anyhow::Result;
+
+use crate::Db;
+
+/// The user a refresh token was rotated for.
+pub struct RefreshedSession {
+    pub user_id: Uuid,
+    pub role: String,
+}
+
+pub enum RefreshOutcome {
+    Rotated(RefreshedSession),
+    /// Unknown or expired token.
+    Invalid,
+    /// The token had already been rotated or revoked. Its whole family has
+    /// been revoked since it may have been stolen.
+    Reused,
+}
+
+impl Db {
+    pub async fn create_refresh_token(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO refresh_tokens (user_id, family_id, token_hash, expires_at) VALUES ($1, $2, $3, $4)",
+            user_id,
+            Uuid::new_v4(),
+            token_hash,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query!("UPDATE users SET last_seen_at = NOW() WHERE id = $1", user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Swaps the refresh token hashed as `token_hash` for a new one in the
+    /// same family.
+    pub async fn rotate_refresh_token(
+        &self,
+        token_hash: &str,
+        new_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<RefreshOutcome> {
+        let mut tx = self.pool.begin().await?;
+        let Some(current) = sqlx::query!(
+            "SELECT rt.id, rt.user_id, rt.family_id, rt.expires_at, rt.revoked_at, u.role
+             FROM refresh_tokens rt JOIN users u ON u.id = rt.user_id
+             WHERE rt.token_hash = $1
+             FOR UPDATE OF rt",
+            token_hash
+        )
+        .fetch_optional(&mut *tx)
+        .await? else {
+            return Ok(RefreshOutcome::Invalid);
+        };
+
+        if current.revoked_at.is_some() {
+            sqlx::query!(
+                "UPDATE refresh_tokens SET revoked_at = NOW() WHERE family_id = $1 AND revoked_at IS NULL",
+                current.family_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            return Ok(RefreshOutcome::Reused);
+        }
+        if current.expires_at <= Utc::now() {
+            return Ok(RefreshOutcome::Invalid);
+        }
+
+        sqlx::query!("UPDATE refresh_tokens SET revoked_at = NOW() WHERE id = $1", current.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            "INSERT INTO refresh_tokens (user_id, family_id, token_hash, expires_at) VALUES ($1, $2, $3, $4)",
+            current.user_id,
+            current.family_id,
+            new_hash,
+            expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("UPDATE users SET last_seen_at = NOW() WHERE id = $1", current.user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(RefreshOutcome::Rotated(RefreshedSession {
+            user_id: current.user_id,
+            role: current.role,
+        }))
+    }
+
+    /// Revokes the session the refresh token belongs to, if it is `user_id`'s.
+    pub async fn revoke_refresh_token(&self, user_id: Uuid, token_hash: &str) -> Result<()> {
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW()
+             WHERE revoked_at IS NULL AND family_id IN (
+                 SELECT family_id FROM refresh_tokens WHERE token_hash = $1 AND user_id = $2
+             )",
+            token_hash,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revokes one access token. Tokens issued before `jti` existed all share
+    /// the nil id, so for those every access token of the user issued up to
+    /// now is revoked instead; refresh tokens stay valid.
+    pub async fn revoke_access_token(&self, user_id: Uuid, jti: Uuid, expires_at: DateTime<Utc>) -> Result<()> {
+        if jti.is_nil() {
+            sqlx::query!(
+                "UPDATE users SET sessions_revoked_at = date_trunc('second', NOW()) + INTERVAL '1 second' WHERE id = $1",
+                user_id
+            )
+            .execute(&self.pool)
+            .await?;
+            return Ok(());
+        }
+        sqlx::query!(
+            "INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, $2) ON CONFLICT (jti) DO NOTHING",
+            jti,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+        // Expired tokens are rejected anyway, so their entries can go.
+        sqlx::query!("DELETE FROM revoked_tokens WHERE expires_at < NOW()")
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Revokes every refresh token of the user and every access token issued
+    /// up to now. JWT `iat` only has whole seconds, so the cutoff is rounded
+    /// up to the next second.
+    pub async fn revoke_all_sessions(&self, user_id: Uuid) -> Result<()> {
+        sqlx::query!(
+            "UPDATE users SET sessions_revoked_at = date_trunc('second', NOW()) + INTERVAL '1 second' WHERE id = $1",
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn is_access_token_revoked(&self, jti: Uuid, user_id: Uuid, issued_at: DateTime<Utc>) -> Result<bool> {
+        let row = sqlx::query!(
+            r#"SELECT (
+                 EXISTS (SELECT 1 FROM revoked_tokens WHERE jti = $1)
+                 OR EXISTS (SELECT 1 FROM users WHERE id = $2 AND sessions_revoked_at > $3)
+             ) AS "revoked!""#,
+            jti,
+            user_id,
+            issued_at
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.revoked)
+    }
+}
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use anyhow::Result;
@@ -8,7 +9,28 @@ use crate::Db;
 pub struct User {
     pub id: Uuid,
     pub username: String,
-    pub password: String,
+    /// `None` for guests.
+    pub password: Option<String>,
+    pub role: String,
+}
+
+pub enum UsernameClash {
+    Reserved,
+    /// Looks like another user's name.
+    Existing,
+}
+
+pub enum UsernameChange {
+    Changed,
+    UsernameTaken,
+    NotFound,
+}
+
+pub enum GuestUpgrade {
+    Upgraded,
+    /// The account already has a username and password.
+    NotGuest,
+    UsernameTaken,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -17,21 +39,338 @@ pub struct CreateUserResponse {
 }
 
 impl Db {
-        pub async fn create_user(&self, username: &String, password: &String) -> Result<CreateUserResponse> {
-            let user = sqlx::query_as!(CreateUserResponse, "INSERT INTO users (username, password) VALUES ($1, $2) RETURNING id", username, password)
+        /// Returns `None` if the username is taken.
+        pub async fn create_user(&self, username: &String, password: &String) -> Result<Option<CreateUserResponse>> {
+            let result = sqlx::query_as!(CreateUserResponse, "INSERT INTO users (username, password) VALUES ($1, $2) RETURNING id", username, password)
                 .fetch_one(&self.pool)
-                .await?;
+                .await;
+            match result {
+                Ok(user) => Ok(Some(CreateUserResponse { id: user.id })),
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        }
+
+        /// Looks for a reserved name or another user's name that `username`
+        /// could be mistaken for, going by `username_skeleton`. A user
+        /// renaming itself (`user_id`) does not clash with its own name, and
+        /// an exact match with another user is left to the unique constraint.
+        pub async fn find_username_clash(&self, username: &str, reserved: &[String], user_id: Option<Uuid>) -> Result<Option<UsernameClash>> {
+            let row = sqlx::query!(
+                r#"SELECT
+                     EXISTS (SELECT 1 FROM unnest($2::text[]) r WHERE username_skeleton(r) = username_skeleton($1)) AS "reserved!",
+                     EXISTS (
+                         SELECT 1 FROM users
+                         WHERE username_skeleton = username_skeleton($1) AND username <> $1 AND id IS DISTINCT FROM $3
+                     ) AS "existing!""#,
+                username,
+                reserved,
+                user_id
+            )
+            .fetch_one(&self.pool)
+            .await?;
 
-            Ok(CreateUserResponse {
-                id: user.id
+            Ok(if row.reserved {
+                Some(UsernameClash::Reserved)
+            } else if row.existing {
+                Some(UsernameClash::Existing)
+            } else {
+                None
             })
         }
 
-        pub async fn get_user_by_username(&self, username: &String) -> Result<User> {
-            let user = sqlx::query_as!(User, "SELECT id, username, password FROM users WHERE username=$1", username)
+        pub async fn get_username(&self, user_id: Uuid) -> Result<String> {
+            let row = sqlx::query!("SELECT username FROM users WHERE id=$1", user_id)
                 .fetch_one(&self.pool)
                 .await?;
+            Ok(row.username)
+        }
+
+        /// Sets the user's role and revokes the access tokens issued so far,
+        /// which still carry the old role. Refresh tokens stay valid.
+        /// Returns `false` if there is no such user.
+        pub async fn set_user_role(&self, user_id: Uuid, role: &str) -> Result<bool> {
+            let result = sqlx::query!(
+                "UPDATE users SET role=$2, updated_at=NOW(),
+                     sessions_revoked_at = date_trunc('second', NOW()) + INTERVAL '1 second'
+                 WHERE id=$1",
+                user_id,
+                role
+            )
+            .execute(&self.pool)
+            .await?;
+            Ok(result.rows_affected() == 1)
+        }
+
+        pub async fn get_user_by_username(&self, username: &String) -> Result<Option<User>> {
+            let user = sqlx::query_as!(User, "SELECT id, username, password, role FROM users WHERE username=$1", username)
+                .fetch_optional(&self.pool)
+                .await?;
             Ok(user)
         }
 
+        pub async fn is_guest(&self, user_id: Uuid) -> Result<bool> {
+            let row = sqlx::query!(
+                r#"SELECT EXISTS (SELECT 1 FROM users WHERE id=$1 AND is_guest) AS "guest!""#,
+                user_id
+            )
+            .fetch_one(&self.pool)
+            .await?;
+            Ok(row.guest)
+        }
+
+        /// Creates a guest account. Returns `None` if the name is taken.
+        pub async fn create_guest_user(&self, username: &str) -> Result<Option<Uuid>> {
+            let row = sqlx::query!(
+                "INSERT INTO users (username, is_guest) VALUES ($1, TRUE) ON CONFLICT (username) DO NOTHING RETURNING id",
+                username
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+            Ok(row.map(|r| r.id))
+        }
+
+        /// Gives a guest a username and password, turning it into a regular
+        /// account. The id stays the same, so its games stay with it.
+        pub async fn upgrade_guest(&self, user_id: Uuid, username: &str, password: &str) -> Result<GuestUpgrade> {
+            let result = sqlx::query!(
+                "UPDATE users SET username=$2, password=$3, is_guest=FALSE, updated_at=NOW() WHERE id=$1 AND is_guest",
+                user_id,
+                username,
+                password
+            )
+            .execute(&self.pool)
+            .await;
+            match result {
+                Ok(r) if r.rows_affected() == 1 => Ok(GuestUpgrade::Upgraded),
+                Ok(_) => Ok(GuestUpgrade::NotGuest),
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(GuestUpgrade::UsernameTaken),
+                Err(e) => Err(e.into()),
+            }
+        }
+
+        /// Expires guests not seen since `cutoff`. Guests with no games are
+        /// deleted; the rest are kept so their games stay intact, but lose
+        /// every session. Returns how many were deleted and how many kept.
+        pub async fn expire_guests(&self, cutoff: DateTime<Utc>) -> Result<(u64, u64)> {
+            let deleted = sqlx::query!(
+                "DELETE FROM users u
+                 WHERE u.is_guest AND u.last_seen_at < $1
+                   AND NOT EXISTS (
+                       SELECT 1 FROM games g
+                       WHERE u.id IN (g.player_x_id, g.player_o_id, g.creator_id, g.invited_id)
+                   )
+                   AND NOT EXISTS (SELECT 1 FROM game_chat c WHERE c.user_id = u.id)
+                   AND NOT EXISTS (SELECT 1 FROM move_audit a WHERE a.user_id = u.id)",
+                cutoff
+            )
+            .execute(&self.pool)
+            .await?;
+
+            let mut tx = self.pool.begin().await?;
+            let retired = sqlx::query!(
+                "UPDATE users SET sessions_revoked_at = date_trunc('second', NOW()) + INTERVAL '1 second'
+                 WHERE is_guest AND last_seen_at < $1
+                   AND (sessions_revoked_at IS NULL OR sessions_revoked_at < last_seen_at)
+                 RETURNING id",
+                cutoff
+            )
+            .fetch_all(&mut *tx)
+            .await?;
+            let ids: Vec<Uuid> = retired.iter().map(|r| r.id).collect();
+            sqlx::query!(
+                "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = ANY($1) AND revoked_at IS NULL",
+                &ids
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+
+            Ok((deleted.rows_affected(), ids.len() as u64))
+        }
+
+        /// The user's password hash, `None` for accounts without a password.
+        pub async fn get_password_hash(&self, user_id: Uuid) -> Result<Option<String>> {
+            let row = sqlx::query!("SELECT password FROM users WHERE id=$1 AND deleted_at IS NULL", user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+            Ok(row.and_then(|r| r.password))
+        }
+
+        /// Sets a new password and revokes every session, as
+        /// `revoke_all_sessions` does.
+        pub async fn change_password(&self, user_id: Uuid, password: &str) -> Result<()> {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query!(
+                "UPDATE users SET password=$2, updated_at=NOW(),
+                     sessions_revoked_at = date_trunc('second', NOW()) + INTERVAL '1 second'
+                 WHERE id=$1",
+                user_id,
+                password
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+                user_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+
+            Ok(())
+        }
+
+        /// Renames a user, keeping the old name in `username_history`.
+        pub async fn change_username(&self, user_id: Uuid, username: &str) -> Result<UsernameChange> {
+            let mut tx = self.pool.begin().await?;
+            let Some(current) = sqlx::query!("SELECT username FROM users WHERE id=$1 AND deleted_at IS NULL FOR UPDATE", user_id)
+                .fetch_optional(&mut *tx)
+                .await?
+            else {
+                return Ok(UsernameChange::NotFound);
+            };
+            if current.username == username {
+                return Ok(UsernameChange::Changed);
+            }
+            let result = sqlx::query!("UPDATE users SET username=$2, updated_at=NOW() WHERE id=$1", user_id, username)
+                .execute(&mut *tx)
+                .await;
+            match result {
+                Ok(_) => {}
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Ok(UsernameChange::UsernameTaken),
+                Err(e) => return Err(e.into()),
+            }
+            sqlx::query!("INSERT INTO username_history (user_id, username) VALUES ($1, $2)", user_id, current.username)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            Ok(UsernameChange::Changed)
+        }
+
+        /// Deletes an account. The user row stays, under a placeholder name
+        /// and without a password, profile, sessions, keys, linked identities
+        /// or name history, so games keep both players. Running correspondence games
+        /// are forfeited to the opponent and open challenges withdrawn; the
+        /// opponents' statistics are recomputed from their finished games.
+        /// Returns `false` if the account does not exist or is already gone.
+        pub async fn delete_account(&self, user_id: Uuid) -> Result<bool> {
+            let mut tx = self.pool.begin().await?;
+            let deleted = sqlx::query!(
+                "UPDATE users
+                 SET username = 'deleted-' || replace(id::text, '-', ''), password = NULL, deleted_at = NOW(),
+                     updated_at = NOW(), games_played = 0, games_won = 0, win_rate = 0,
+                     display_name = NULL, bio = NULL, country = NULL, avatar_id = NULL,
+                     sessions_revoked_at = date_trunc('second', NOW()) + INTERVAL '1 second'
+                 WHERE id = $1 AND deleted_at IS NULL",
+                user_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            if deleted.rows_affected() == 0 {
+                return Ok(false);
+            }
+
+            sqlx::query!("UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL", user_id)
+                .execute(&mut *tx)
+                .await?;
+            // The user's bots can no longer be managed, so their keys go too.
+            sqlx::query!(
+                "DELETE FROM api_keys WHERE user_id = $1 OR user_id IN (SELECT id FROM users WHERE owner_id = $1)",
+                user_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!("DELETE FROM user_identities WHERE user_id = $1", user_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!("DELETE FROM username_history WHERE user_id = $1", user_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!("DELETE FROM user_relationships WHERE $1 IN (user_id, target_id)", user_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!("DELETE FROM user_presence WHERE user_id = $1", user_id)
+                .execute(&mut *tx)
+                .await?;
+
+            let forfeited = sqlx::query!(
+                r#"UPDATE games
+                 SET winner_id = CASE WHEN player_x_id = $1 THEN player_o_id ELSE player_x_id END,
+                     finished_at = NOW(), status = 'finished'
+                 WHERE mode = 'correspondence' AND status = 'active' AND $1 IN (player_x_id, player_o_id)
+                 RETURNING winner_id AS "opponent!""#,
+                user_id
+            )
+            .fetch_all(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE games SET status = 'aborted', finished_at = NOW()
+                 WHERE mode = 'correspondence' AND status = 'waiting' AND $1 IN (creator_id, invited_id)",
+                user_id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            let opponents: Vec<Uuid> = forfeited.iter().map(|r| r.opponent).collect();
+            sqlx::query!(
+                "UPDATE users u
+                 SET games_played = s.played, games_won = s.won,
+                     win_rate = CASE WHEN s.played = 0 THEN 0 ELSE ROUND((s.won::decimal / s.played) * 100, 2) END
+                 FROM (
+                     SELECT p.id, COUNT(g.id)::int AS played, (COUNT(g.id) FILTER (WHERE g.winner_id = p.id))::int AS won
+                     FROM users p
+                     LEFT JOIN games g ON g.status = 'finished' AND NOT g.bot AND p.id IN (g.player_x_id, g.player_o_id)
+                     WHERE p.id = ANY($1)
+                     GROUP BY p.id
+                 ) s
+                 WHERE u.id = s.id",
+                &opponents
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+
+            Ok(true)
+        }
+
+}
+
+#[cfg(all(test, feature = "db-tests"))]
+mod tests {
+    use super::*;
+
+    fn reserved() -> Vec<String> {
+        ["admin", "root", "wizard", "coop"].map(str::to_owned).to_vec()
+    }
+
+    fn is_reserved(clash: Option<UsernameClash>) -> bool {
+        matches!(clash, Some(UsernameClash::Reserved))
+    }
+
+    #[tokio::test]
+    async fn names_resembling_reserved_ones_clash() {
+        let db = Db::new().await.unwrap();
+        for name in ["admin", "ADMIN", "adrnin", "r00t", "vvizard", "c00p", "соор", "СООР"] {
+            assert!(is_reserved(db.find_username_clash(name, &reserved(), None).await.unwrap()), "{}", name);
+        }
+        assert!(!is_reserved(db.find_username_clash("wizard.", &reserved(), None).await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn names_resembling_another_user_clash() {
+        let db = Db::new().await.unwrap();
+        let name = format!("seed{}", &Uuid::new_v4().simple().to_string()[..12]);
+        let user = db.create_user(&name, &String::new()).await.unwrap().unwrap();
+
+        let lookalike = db.find_username_clash(&name.to_uppercase(), &[], None).await;
+        let own_rename = db.find_username_clash(&name.to_uppercase(), &[], Some(user.id)).await;
+        let same_name = db.find_username_clash(&name, &[], None).await;
+        sqlx::query!("DELETE FROM users WHERE id=$1", user.id).execute(&db.pool).await.unwrap();
+
+        assert!(matches!(lookalike.unwrap(), Some(UsernameClash::Existing)));
+        assert!(own_rename.unwrap().is_none());
+        assert!(same_name.unwrap().is_none());
+    }
 }